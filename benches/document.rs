@@ -0,0 +1,203 @@
+//! Compares `Document` with the one it replaced, which kept its rows in a `Vec` and
+//! rebuilt a row's string grapheme by grapheme on every edit, on large synthetic files.
+//! Run with `cargo bench`.
+use lecturn::{Document, Position, Row};
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::time::{Duration, Instant};
+use unicode_segmentation::UnicodeSegmentation;
+
+const EDITS: usize = 1_000;
+
+/// A row as the old `Document` kept it
+struct OldRow {
+    string: String,
+    len: usize,
+}
+
+impl OldRow {
+    fn from(slice: &str) -> Self {
+        Self { string: String::from(slice), len: slice.graphemes(true).count() }
+    }
+    fn insert(&mut self, at: usize, c: char) {
+        if at >= self.len {
+            self.string.push(c);
+            self.len = self.len.saturating_add(1);
+            return;
+        }
+        let mut result = String::new();
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            if index == at {
+                result.push(c);
+            }
+            result.push_str(grapheme);
+        }
+        self.len = self.len.saturating_add(1);
+        self.string = result;
+    }
+    fn delete(&mut self, at: usize) {
+        if at >= self.len {
+            return;
+        }
+        let mut result = String::new();
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            if index != at {
+                result.push_str(grapheme);
+            }
+        }
+        self.len = self.len.saturating_sub(1);
+        self.string = result;
+    }
+    fn append(&mut self, new: &Self) {
+        self.string = format!("{}{}", self.string, new.string);
+        self.len = self.len.saturating_add(new.len);
+    }
+    fn split(&mut self, at: usize) -> Self {
+        let (mut row, mut split_row) = (String::new(), String::new());
+        let mut length = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            if index < at {
+                length += 1;
+                row.push_str(grapheme);
+            } else {
+                split_row.push_str(grapheme);
+            }
+        }
+        let split_length = self.len.saturating_sub(length);
+        self.string = row;
+        self.len = length;
+        Self { string: split_row, len: split_length }
+    }
+}
+
+/// The editing operations timed on both documents
+trait Edit {
+    fn load(path: &str) -> Self;
+    fn row_len(&self, y: usize) -> usize;
+    fn insert(&mut self, at: &Position, c: char);
+    fn insert_newline(&mut self, at: &Position);
+    fn delete(&mut self, at: &Position);
+}
+
+/// The old `Document`, reduced to its rows
+struct OldDocument {
+    rows: Vec<OldRow>,
+}
+
+impl Edit for OldDocument {
+    fn load(path: &str) -> Self {
+        let text = fs::read_to_string(path).unwrap_or_default();
+        Self { rows: text.lines().map(OldRow::from).collect() }
+    }
+    fn row_len(&self, y: usize) -> usize {
+        self.rows.get(y).map_or(0, |row| row.len)
+    }
+    fn insert(&mut self, at: &Position, c: char) {
+        if let Some(row) = self.rows.get_mut(at.y) {
+            row.insert(at.x, c);
+        }
+    }
+    fn insert_newline(&mut self, at: &Position) {
+        if let Some(row) = self.rows.get_mut(at.y) {
+            let new_row = row.split(at.x);
+            self.rows.insert(at.y + 1, new_row);
+        }
+    }
+    fn delete(&mut self, at: &Position) {
+        if at.x == self.row_len(at.y) && at.y + 1 < self.rows.len() {
+            let next_row = self.rows.remove(at.y + 1);
+            if let Some(row) = self.rows.get_mut(at.y) {
+                row.append(&next_row);
+            }
+        } else if let Some(row) = self.rows.get_mut(at.y) {
+            row.delete(at.x);
+        }
+    }
+}
+
+impl Edit for Document {
+    fn load(path: &str) -> Self {
+        Document::open(path).unwrap_or_else(|_| Document::default())
+    }
+    fn row_len(&self, y: usize) -> usize {
+        self.row(y).map_or(0, Row::len)
+    }
+    fn insert(&mut self, at: &Position, c: char) {
+        Document::insert(self, at, c);
+    }
+    fn insert_newline(&mut self, at: &Position) {
+        self.insert_text(at, "\n");
+    }
+    fn delete(&mut self, at: &Position) {
+        Document::delete(self, at);
+    }
+}
+
+/// `Position` can't be built with a struct expression outside the crate
+fn at(x: usize, y: usize) -> Position {
+    let mut position = Position::default();
+    position.x = x;
+    position.y = y;
+    position
+}
+
+fn synthetic_file(lines: usize) -> String {
+    (0..lines)
+        .map(|i| format!("{:>10} INFO  request served in {}ms from worker {}\n", i, i % 997, i % 16))
+        .collect()
+}
+
+fn per_edit(elapsed: Duration, edits: usize) -> Duration {
+    elapsed / u32::try_from(edits).unwrap_or(u32::MAX)
+}
+
+/// Presses Enter in the middle of rows around the middle of the file, then Backspace to
+/// join them again, returning the average time per edit
+fn split_and_join<D: Edit>(document: &mut D, len: usize) -> Duration {
+    let start = Instant::now();
+    for i in 0..EDITS {
+        let y = len / 2 + i % 64;
+        let x = document.row_len(y) / 2;
+        document.insert_newline(&at(x, y));
+        document.delete(&at(x, y));
+    }
+    per_edit(start.elapsed(), EDITS * 2)
+}
+
+/// Types a character in the middle of rows and deletes it again
+fn type_and_delete<D: Edit>(document: &mut D, len: usize) -> Duration {
+    let start = Instant::now();
+    for i in 0..EDITS {
+        let y = len / 2 + i % 64;
+        let x = document.row_len(y) / 2;
+        document.insert(&at(x, y), 'x');
+        document.delete(&at(x, y));
+    }
+    per_edit(start.elapsed(), EDITS * 2)
+}
+
+fn bench<D: Edit>(name: &str, path: &str, len: usize) {
+    let start = Instant::now();
+    let mut document = D::load(path);
+    let load = start.elapsed();
+    let lines = split_and_join(&mut document, len);
+    let typing = type_and_delete(&mut document, len);
+    println!(
+        "{:>10} rows  {:<4}  load {:>10.2?}  enter/backspace {:>10.2?}  type/delete {:>10.2?}",
+        len, name, load, lines, typing
+    );
+}
+
+fn main() {
+    let path = env::temp_dir().join(format!("lecturn-bench-{}.log", std::process::id()));
+    let path_name = path.to_string_lossy();
+    for &len in &[10_000, 100_000, 1_000_000] {
+        if fs::write(&path, synthetic_file(len)).is_err() {
+            return;
+        }
+        bench::<OldDocument>("old", &path_name, len);
+        bench::<Document>("new", &path_name, len);
+    }
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,98 @@
+//! Compares the old `Vec` row storage with the `Rope` now behind `Document` on large
+//! synthetic files. Run with `cargo bench`.
+#[allow(dead_code)]
+#[path = "../src/rope.rs"]
+mod rope;
+
+use rope::Rope;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+const EDITS: usize = 1_000;
+
+trait Rows {
+    fn build(lines: Vec<String>) -> Self;
+    fn row(&self, index: usize) -> Option<&String>;
+    fn insert_row(&mut self, index: usize, row: String);
+    fn remove_row(&mut self, index: usize) -> Option<String>;
+}
+
+impl Rows for Vec<String> {
+    fn build(lines: Vec<String>) -> Self {
+        lines
+    }
+    fn row(&self, index: usize) -> Option<&String> {
+        self.get(index)
+    }
+    fn insert_row(&mut self, index: usize, row: String) {
+        self.insert(index, row);
+    }
+    fn remove_row(&mut self, index: usize) -> Option<String> {
+        Some(self.remove(index))
+    }
+}
+
+impl Rows for Rope<String> {
+    fn build(lines: Vec<String>) -> Self {
+        lines.into_iter().collect()
+    }
+    fn row(&self, index: usize) -> Option<&String> {
+        self.get(index)
+    }
+    fn insert_row(&mut self, index: usize, row: String) {
+        self.insert(index, row);
+    }
+    fn remove_row(&mut self, index: usize) -> Option<String> {
+        self.remove(index)
+    }
+}
+
+fn synthetic_file(lines: usize) -> Vec<String> {
+    (0..lines)
+        .map(|i| format!("{:>10} INFO  request served in {}ms from worker {}", i, i % 997, i % 16))
+        .collect()
+}
+
+/// Splits and re-joins rows around the middle of the file, the way pressing Enter and
+/// Backspace does, returning the average time per edit
+fn edit_middle<R: Rows>(rows: &mut R, len: usize) -> Duration {
+    let middle = len / 2;
+    let start = Instant::now();
+    for i in 0..EDITS {
+        let y = middle + i % 64;
+        let row = rows.row(y).cloned().unwrap_or_default();
+        rows.insert_row(y + 1, row);
+        rows.remove_row(y + 1);
+    }
+    start.elapsed() / u32::try_from(EDITS * 2).unwrap_or(u32::MAX)
+}
+
+fn scroll<R: Rows>(rows: &R, len: usize) -> Duration {
+    let start = Instant::now();
+    let mut bytes = 0;
+    for y in (0..len).step_by((len / EDITS).max(1)) {
+        bytes += rows.row(y).map_or(0, String::len);
+    }
+    assert!(bytes > 0);
+    start.elapsed() / u32::try_from(EDITS).unwrap_or(u32::MAX)
+}
+
+fn bench<R: Rows>(name: &str, len: usize) {
+    let lines = synthetic_file(len);
+    let start = Instant::now();
+    let mut rows = R::build(lines);
+    let build = start.elapsed();
+    let edit = edit_middle(&mut rows, len);
+    let lookup = scroll(&rows, len);
+    println!(
+        "{:>10} rows  {:<5}  build {:>10.2?}  edit {:>10.2?}  lookup {:>10.2?}",
+        len, name, build, edit, lookup
+    );
+}
+
+fn main() {
+    for &len in &[10_000, 100_000, 1_000_000, 5_000_000] {
+        bench::<Vec<String>>("vec", len);
+        bench::<Rope<String>>("rope", len);
+    }
+}
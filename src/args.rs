@@ -0,0 +1,168 @@
+use crate::window::Direction;
+use std::path::Path;
+
+pub const USAGE: &str = "\
+Usage: lecturn [options] [+N | +/pattern] [file[:line[:column]] | -]...
+
+Options:
+  +N          Start at line N of the first file (+ alone for the last line)
+  +/pattern   Start at the first match of pattern in the first file
+  -           Read the document from stdin
+  -R          Open files read-only, so they are only written with :w!
+  -o          Open the files in windows one above the other
+  -O          Open the files in windows side by side
+  -d          Open two files side by side with a diff of them
+  --hex       Open files in hex mode
+  --stdout    Write the document to stdout on :wq rather than saving it
+  --help      Show this help
+  --version   Show the version";
+
+/// Where to put the cursor in a file when it is opened
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Jump {
+    /// A line and optionally a column, both counting from 1
+    Line(usize, Option<usize>),
+    LastLine,
+    /// The first match of a search pattern
+    Search(String),
+}
+
+/// A file named on the command line
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct File {
+    /// `-` for stdin
+    pub name: String,
+    pub jump: Option<Jump>,
+}
+
+/// What the command line asked the editor to do
+#[derive(Default, Debug)]
+pub struct Args {
+    pub files: Vec<File>,
+    pub hex: bool,
+    pub stdout: bool,
+    pub read_only: bool,
+    /// Open every file in its own window, split this way
+    pub split: Option<Direction>,
+    pub diff: bool,
+}
+
+/// The command line parsed, which either starts the editor or just prints something
+#[derive(Debug)]
+pub enum Parsed {
+    Edit(Args),
+    Help,
+    Version,
+}
+
+/// Parses the arguments after the program name. `+N` and `+/pattern` apply to the first
+/// file, and `file:line:column` to that file, unless a file with that whole name exists.
+/// Everything after `--` is a file name
+/// # Errors
+/// Will return `Err` with a message for an unknown option or a missing file for `-d`
+pub fn parse(arguments: impl Iterator<Item = String>) -> Result<Parsed, String> {
+    let mut args = Args::default();
+    let mut first_jump = None;
+    let mut files_only = false;
+    for argument in arguments {
+        if files_only || argument == "-" || !argument.starts_with(['-', '+']) {
+            args.files.push(parse_file(argument));
+            continue;
+        }
+        match argument.as_str() {
+            "--" => files_only = true,
+            "--help" | "-h" => return Ok(Parsed::Help),
+            "--version" | "-v" => return Ok(Parsed::Version),
+            "--hex" => args.hex = true,
+            "--stdout" => args.stdout = true,
+            "-R" => args.read_only = true,
+            "-o" => args.split = Some(Direction::Horizontal),
+            "-O" => args.split = Some(Direction::Vertical),
+            "-d" => args.diff = true,
+            "+" => first_jump = Some(Jump::LastLine),
+            _ => {
+                first_jump = Some(match argument.strip_prefix("+/") {
+                    Some(pattern) => Jump::Search(pattern.to_owned()),
+                    None => argument
+                        .strip_prefix('+')
+                        .and_then(|line| line.parse().ok())
+                        .map(|line| Jump::Line(line, None))
+                        .ok_or_else(|| format!("unknown option {}", argument))?,
+                });
+            }
+        }
+    }
+    if args.diff {
+        if args.files.len() != 2 {
+            return Err("-d needs two files to compare".to_owned());
+        }
+        args.split = Some(Direction::Vertical);
+    }
+    if let (Some(jump), Some(file)) = (first_jump, args.files.first_mut()) {
+        file.jump = Some(jump);
+    }
+    Ok(Parsed::Edit(args))
+}
+
+/// Splits `name:line` or `name:line:column` into the name and where to jump
+fn parse_file(argument: String) -> File {
+    if Path::new(&argument).exists() {
+        return File { name: argument, jump: None };
+    }
+    let number = |part: &str| part.parse::<usize>().ok();
+    // The parts from the end, e.g. ["3", "7", "main.rs"]
+    let parts: Vec<&str> = argument.rsplitn(3, ':').collect();
+    let (name, jump) = match *parts.as_slice() {
+        [column, line, name] if !name.is_empty() => match (number(line), number(column)) {
+            (Some(line), Some(column)) => (name, Jump::Line(line, Some(column))),
+            (_, Some(line)) => (argument.rsplit_once(':').map_or(name, |(name, _)| name), Jump::Line(line, None)),
+            _ => return File { name: argument, jump: None },
+        },
+        [line, name] if !name.is_empty() => match number(line) {
+            Some(line) => (name, Jump::Line(line, None)),
+            None => return File { name: argument, jump: None },
+        },
+        _ => return File { name: argument, jump: None },
+    };
+    File { name: name.to_owned(), jump: Some(jump) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(arguments: &[&str]) -> Result<Args, String> {
+        match parse(arguments.iter().map(|&argument| argument.to_owned()))? {
+            Parsed::Edit(args) => Ok(args),
+            other => Err(format!("{:?}", other)),
+        }
+    }
+
+    #[test]
+    fn reads_jumps() {
+        let args = parse_args(&["+42", "a.rs", "b.rs:7:3", "c.rs:9"]).unwrap();
+        let jumps: Vec<_> = args.files.iter().map(|file| (file.name.as_str(), file.jump.clone())).collect();
+        assert_eq!(
+            jumps,
+            [
+                ("a.rs", Some(Jump::Line(42, None))),
+                ("b.rs", Some(Jump::Line(7, Some(3)))),
+                ("c.rs", Some(Jump::Line(9, None))),
+            ]
+        );
+        let args = parse_args(&["+/fn main", "a.rs", "notes:draft"]).unwrap();
+        assert_eq!(args.files[0].jump, Some(Jump::Search("fn main".to_owned())));
+        assert_eq!(args.files[1], File { name: "notes:draft".to_owned(), jump: None });
+    }
+
+    #[test]
+    fn reads_options() {
+        let args = parse_args(&["-R", "-O", "--hex", "a", "--", "-b"]).unwrap();
+        assert!(args.read_only && args.hex && !args.stdout);
+        assert_eq!(args.split, Some(Direction::Vertical));
+        assert_eq!(args.files.iter().map(|file| file.name.as_str()).collect::<Vec<_>>(), ["a", "-b"]);
+        assert!(matches!(parse(std::iter::once("--version".to_owned())), Ok(Parsed::Version)));
+        assert_eq!(parse_args(&["-x", "a"]).unwrap_err(), "unknown option -x");
+        assert!(parse_args(&["-d", "a"]).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use crate::syntax::Language;
+use crate::{Document, Position};
+use std::fs;
+use std::path::Path;
+
+/// A file open in the editor. The current buffer's document, cursor and scroll offset
+/// live in the `Editor` itself; every other buffer keeps them here until switched back to
+pub struct Buffer {
+    /// The number `:b` and `:bd` refer to it by, which stays the same as other buffers close
+    pub id: usize,
+    pub document: Document,
+    pub cursor_position: Position,
+    pub offset: Position,
+}
+
+impl Buffer {
+    #[must_use]
+    pub fn new(id: usize, document: Document) -> Self {
+        Self {
+            id,
+            document,
+            cursor_position: Position::default(),
+            offset: Position::default(),
+        }
+    }
+    /// Opens `file_name` with its language detected, or starts a new document under that
+    /// name with an error message if it cannot be read. Binary files are opened
+    /// read-only, with a warning
+    #[must_use]
+    pub fn open(id: usize, file_name: &str, languages: &[Language]) -> (Self, Option<String>) {
+        let (mut document, error) = if let Ok(document) = Document::open(file_name) {
+            let warning = document.is_binary().then(|| format!("{} looks like a binary file, opened read-only", file_name));
+            (document, warning)
+        } else {
+            let mut document = Document::default();
+            document.set_file_name(file_name.to_owned());
+            (document, Some(format!("ERR: Could not open file (opening as new): {}", file_name)))
+        };
+        document.set_language(Language::detect(languages, file_name).cloned());
+        (Self::new(id, document), error)
+    }
+}
+
+/// The name a buffer is shown under in the status bar and `:ls`
+#[must_use]
+pub fn name(document: &Document) -> &str {
+    document.file_name.as_deref().unwrap_or("[No Name]")
+}
+
+/// The marker shown after a buffer's name while it has unsaved changes
+#[must_use]
+pub fn modified_indicator(document: &Document) -> &'static str {
+    if document.is_dirty() {
+        " [+]"
+    } else {
+        ""
+    }
+}
+
+/// Whether two paths name the same file, comparing canonical paths when both exist
+#[must_use]
+pub fn is_same_file(first: &str, second: &str) -> bool {
+    match (fs::canonicalize(first), fs::canonicalize(second)) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => Path::new(first) == Path::new(second),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{open, play, TempFile};
+
+    #[test]
+    fn opens_missing_files_as_new() {
+        let file = TempFile::new("buffer-open", "notes.txt", "one\n");
+        let missing = file.path.with_file_name("missing.rs");
+        let missing = missing.to_string_lossy();
+        let (buffer, error) = Buffer::open(3, &missing, &Language::built_in());
+        assert_eq!(buffer.id, 3);
+        assert_eq!(buffer.document.file_name.as_deref(), Some(&*missing));
+        assert_eq!(error, Some(format!("ERR: Could not open file (opening as new): {}", missing)));
+        let (buffer, error) = Buffer::open(4, &file.path.to_string_lossy(), &[]);
+        assert_eq!((name(&buffer.document), modified_indicator(&buffer.document)), (&*file.path.to_string_lossy(), ""));
+        assert!(error.is_none());
+        assert_eq!(name(&Document::default()), "[No Name]");
+    }
+
+    #[test]
+    fn compares_files_by_canonical_path() {
+        let file = TempFile::new("buffer-same", "notes.txt", "one\n");
+        let path = file.path.to_string_lossy();
+        let dotted = file.path.parent().unwrap().join(".").join("notes.txt");
+        assert!(is_same_file(&path, &dotted.to_string_lossy()));
+        assert!(!is_same_file(&path, &file.sibling("other.txt", "").to_string_lossy()));
+        assert!(is_same_file("missing.txt", "missing.txt"));
+    }
+
+    #[test]
+    fn keeps_each_buffers_state_when_switching() {
+        let lines: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+        let file = TempFile::new("buffer-switch", "first.txt", &lines);
+        let other = file.sibling("second.txt", "other\n");
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, "Gkkx");
+        let first_screen = screen.rows();
+        assert_eq!(first_screen.first().map(String::as_str), Some("15 line 15"));
+
+        play(&mut editor, &screen, &format!(":e {}\n", other.display()));
+        assert_eq!(editor.document.text(), "other");
+        assert_eq!(editor.current_buffer_id(), 2);
+        play(&mut editor, &screen, ":ls\n");
+        assert_eq!(
+            editor.status_message.text,
+            format!("1 + \"{}\" | 2 % \"{}\"", file.path.display(), other.display())
+        );
+
+        play(&mut editor, &screen, ":bp\n");
+        assert_eq!(screen.rows().get(..6), first_screen.get(..6));
+        assert_eq!(screen.cursor(), Some((3, 3)));
+        assert!(editor.document.is_dirty());
+        play(&mut editor, &screen, ":bn\n");
+        assert_eq!(editor.document.text(), "other");
+        assert!(!editor.document.is_dirty());
+        play(&mut editor, &screen, ":b 1\n");
+        assert_eq!(editor.current_buffer_id(), 1);
+        // Opening a file that is already open switches to its buffer
+        play(&mut editor, &screen, &format!(":e {}\n", other.display()));
+        assert_eq!(editor.current_buffer_id(), 2);
+        play(&mut editor, &screen, ":b 7\n");
+        assert_eq!(editor.status_message.text, "ERR: Buffer 7 does not exist");
+    }
+
+    #[test]
+    fn refuses_to_lose_changes_in_other_buffers() {
+        let file = TempFile::new("buffer-dirty", "first.txt", "one\n");
+        let other = file.sibling("second.txt", "two\n");
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, &format!("x:e {}\n", other.display()));
+        play(&mut editor, &screen, ":bd 1\n");
+        assert_eq!(editor.status_message.text, "ERR: No write since last change for buffer 1 (add ! to override)");
+        play(&mut editor, &screen, ":q\n");
+        assert!(!editor.should_quit);
+        assert_eq!(
+            editor.status_message.text,
+            format!("There are unsaved changes in buffer 1 \"{}\". Run :q! to force quit", file.path.display())
+        );
+        play(&mut editor, &screen, ":bd! 1\n:ls\n");
+        assert_eq!(editor.status_message.text, format!("2 % \"{}\"", other.display()));
+        play(&mut editor, &screen, ":q\n");
+        assert!(editor.should_quit);
+    }
+
+    #[test]
+    fn closing_the_last_buffer_empties_every_window_showing_it() {
+        let file = TempFile::new("buffer-last", "notes.txt", "one\n");
+        let (mut editor, screen) = open(&file.path);
+        // A second tab showing the same buffer, split in two
+        play(&mut editor, &screen, ":tabnew\n:b 1\n:bd 2\n:sp\n:bd\n");
+        let empty = "[No Name]                 no ft | utf-8 unix | 1,1";
+        assert_eq!(screen.rows(), [" 1 [No Name]  2 [No Name]", "1", "", empty, "1", "", empty, ""]);
+        play(&mut editor, &screen, ":tabnext\n:ls\n");
+        assert_eq!(screen.rows().get(6).map(String::as_str), Some(empty));
+        assert_eq!(editor.status_message.text, "3 % \"[No Name]\"");
+    }
+}
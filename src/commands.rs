@@ -1,18 +1,33 @@
 use regex::{Regex};
 use crate::editor::{Editor, StatusMessage};
-use crate::{highlighting, Position, Terminal};
+use crate::encoding::Encoding;
+use crate::fileformat::LineEnding;
+use crate::hex;
+use crate::motion;
+use crate::search::{Match, Query};
+use crate::substitute::Substitution;
+use crate::window::Direction;
+use crate::{highlighting, Position};
 use termion::event::Key;
 
+/// Rows `start..=end` that an ex command applies to, e.g. from `:10,20` or `:'<,'>`
+#[derive(Clone, Copy)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+}
+
 pub struct Command {
     pub regex: Regex,
     pub name: String,
     pub description: String,
-    pub function: fn(editor: &mut Editor, params: Vec<&str>, forced: bool),
+    pub function: fn(editor: &mut Editor, params: Vec<&str>, forced: bool, range: Option<LineRange>),
 }
 
 #[non_exhaustive]
 pub struct Commands {
     pub commands: Vec<Command>,
+    range_regex: Regex,
 }
 impl Commands {
     #[must_use]
@@ -24,36 +39,32 @@ impl Commands {
             Command {
                 regex: Regex::new(r#"\b(q)\b"#).unwrap(),
                 name: "q".to_owned(),
-                description: "Quits Editor".to_owned(),
-                function: |mut editor, _params, forced| {
-                    if editor.document.is_dirty() && !forced {
-                        editor.status_message = StatusMessage::from("There are unsaved changes. Run :q! to force quit".to_owned(), Option::from(crate::ERROR_COLOR));
-                        return;
-                    }
-                    editor.should_quit = true;
+                description: "Closes the window, or quits the editor if it is the last".to_owned(),
+                function: |mut editor, _params, forced, _range| {
+                    editor.close_window(forced);
                 },
             },
             Command {
                 regex: Regex::new(r#"\b(w)\b"#).unwrap(),
                 name: "w".to_owned(),
                 description: "Saves current document".to_owned(),
-                function: |editor, _params, _forced| {
-                    editor.save();
+                function: |editor, _params, forced, _range| {
+                    editor.save(forced);
                 },
             },
             Command {
                 regex: Regex::new(r#"\b(wq)\b"#).unwrap(),
                 name: "wq".to_owned(),
-                description: "Saves current document and exits".to_owned(),
-                function: |editor, _params, _forced| {
-                    editor.should_quit = editor.save();
+                description: "Saves current document and closes the window, exiting if it is the last. With --stdout, exits writing it to stdout".to_owned(),
+                function: |editor, _params, forced, _range| {
+                    editor.write_and_close(forced);
                 },
             },
             Command {
                 regex: Regex::new(r#"/"#).unwrap(),
                 name: "/".to_owned(),
                 description: "Searches document (top -> bottom)".to_owned(),
-                function: |editor, params, _forced| {
+                function: |editor, params, _forced, _range| {
                     Commands::search_command(editor, &params.join(" "), false, false);
                 },
             },
@@ -61,24 +72,342 @@ impl Commands {
                 regex: Regex::new(r#"\?"#).unwrap(),
                 name: "?".to_owned(),
                 description: "Searches document (bottom -> top)".to_owned(),
-                function: |editor, params, _forced| {
+                function: |editor, params, _forced, _range| {
                     Commands::search_command(editor, &params.join(" "), true, false);
                 },
             },
+            Command {
+                regex: Regex::new(r#"^(undo|u)\b"#).unwrap(),
+                name: "undo".to_owned(),
+                description: "Undoes the last change, or moves to the state after change N".to_owned(),
+                function: |editor, params, _forced, _range| {
+                    match params.iter().find(|param| !param.is_empty()) {
+                        Some(change) => {
+                            if let Ok(change) = change.parse::<usize>() {
+                                editor.undo_to(change);
+                            } else {
+                                editor.status_message = StatusMessage::from("ERR: Invalid undo number".to_owned(), Option::from(editor.config.theme.error));
+                            }
+                        }
+                        None => editor.undo(1),
+                    }
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(redo|red)\b"#).unwrap(),
+                name: "redo".to_owned(),
+                description: "Redoes the last undone change".to_owned(),
+                function: |editor, _params, _forced, _range| {
+                    editor.redo(1);
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(wundo)\b"#).unwrap(),
+                name: "wundo".to_owned(),
+                description: "Writes the undo history to a file (defaults to .<name>.un~)".to_owned(),
+                function: |editor, params, _forced, _range| {
+                    let path = params.iter().find(|param| !param.is_empty()).copied();
+                    editor.status_message = match editor.document.write_history(path) {
+                        Ok(path) => StatusMessage::from(format!("Undo history written to {}", path), None),
+                        Err(error) => StatusMessage::from(format!("ERR: Could not write undo history: {}", error), Option::from(editor.config.theme.error)),
+                    };
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(rundo)\b"#).unwrap(),
+                name: "rundo".to_owned(),
+                description: "Reads the undo history from a file (defaults to .<name>.un~)".to_owned(),
+                function: |editor, params, _forced, _range| {
+                    let path = params.iter().find(|param| !param.is_empty()).copied();
+                    editor.status_message = match editor.document.read_history(path) {
+                        Ok(path) => StatusMessage::from(format!("Undo history read from {}", path), None),
+                        Err(error) => StatusMessage::from(format!("ERR: Could not read undo history: {}", error), Option::from(editor.config.theme.error)),
+                    };
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(source|so)\b"#).unwrap(),
+                name: "source".to_owned(),
+                description: "Loads a config file (defaults to the user config)".to_owned(),
+                function: |editor, params, _forced, _range| {
+                    let path = params.iter().find(|param| !param.is_empty()).map(std::path::Path::new);
+                    editor.reload_config(path);
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(reload-config)\b"#).unwrap(),
+                name: "reload-config".to_owned(),
+                description: "Re-reads the user config".to_owned(),
+                function: |editor, _params, _forced, _range| {
+                    editor.reload_config(None);
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(d|delete)\b"#).unwrap(),
+                name: "delete".to_owned(),
+                description: "Deletes the lines in the range (defaults to the current line) into a register".to_owned(),
+                function: |editor, params, _forced, range| {
+                    editor.register = register_param(&params);
+                    editor.with_lines(range, |editor, selection| {
+                        let (yank, cursor) = selection.delete(&mut editor.document);
+                        editor.store_yank(yank, true);
+                        editor.cursor_position = cursor;
+                    });
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(y|yank)\b"#).unwrap(),
+                name: "yank".to_owned(),
+                description: "Yanks the lines in the range (defaults to the current line) into a register".to_owned(),
+                function: |editor, params, _forced, range| {
+                    editor.register = register_param(&params);
+                    editor.with_lines(range, |editor, selection| {
+                        let yank = selection.yank(&editor.document);
+                        editor.store_yank(yank, false);
+                    });
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^>"#).unwrap(),
+                name: ">".to_owned(),
+                description: "Indents the lines in the range, once per >".to_owned(),
+                function: |editor, params, _forced, range| {
+                    let times = params.join("").matches('>').count().saturating_add(1);
+                    for _ in 0..times {
+                        editor.with_lines(range, |editor, selection| {
+                            editor.cursor_position = selection.start();
+                            let indent = editor.config.indent();
+                            selection.indent(&mut editor.document, &indent);
+                        });
+                    }
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^<"#).unwrap(),
+                name: "<".to_owned(),
+                description: "Removes one indent from the lines in the range, once per <".to_owned(),
+                function: |editor, params, _forced, range| {
+                    let times = params.join("").matches('<').count().saturating_add(1);
+                    for _ in 0..times {
+                        editor.with_lines(range, |editor, selection| {
+                            editor.cursor_position = selection.start();
+                            selection.outdent(&mut editor.document, editor.config.tab_width);
+                        });
+                    }
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(s|substitute)\b"#).unwrap(),
+                name: "substitute".to_owned(),
+                description: "Replaces regex matches in the range (defaults to the current line): s/pattern/replacement/[gic]".to_owned(),
+                function: |editor, params, _forced, range| {
+                    Commands::substitute_command(editor, &params.join(" "), range);
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(e|edit)\b"#).unwrap(),
+                name: "edit".to_owned(),
+                description: "Opens a file in a new buffer, or switches to it if already open. Without one, reads the current file again".to_owned(),
+                function: |editor, params, forced, _range| {
+                    let path = argument(&params);
+                    if path.is_empty() {
+                        editor.reload(forced);
+                    } else {
+                        editor.edit(&path);
+                    }
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(b|buffer)\b"#).unwrap(),
+                name: "buffer".to_owned(),
+                description: "Switches to buffer N".to_owned(),
+                function: |editor, params, _forced, _range| {
+                    match Commands::buffer_param(editor, &params) {
+                        Ok(index) => editor.switch_buffer(index),
+                        Err(message) => editor.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(editor.config.theme.error)),
+                    }
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(bn|bnext)\b"#).unwrap(),
+                name: "bnext".to_owned(),
+                description: "Switches to the next buffer".to_owned(),
+                function: |editor, _params, _forced, _range| {
+                    editor.cycle_buffer(1);
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(bp|bprevious)\b"#).unwrap(),
+                name: "bprevious".to_owned(),
+                description: "Switches to the previous buffer".to_owned(),
+                function: |editor, _params, _forced, _range| {
+                    editor.cycle_buffer(-1);
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(ls|buffers)\b"#).unwrap(),
+                name: "ls".to_owned(),
+                description: "Lists the open buffers".to_owned(),
+                function: |editor, _params, _forced, _range| {
+                    editor.list_buffers();
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(bd|bdelete)\b"#).unwrap(),
+                name: "bdelete".to_owned(),
+                description: "Closes buffer N (defaults to the current one); bd! discards unsaved changes".to_owned(),
+                function: |editor, params, forced, _range| {
+                    match Commands::buffer_param(editor, &params) {
+                        Ok(index) => editor.delete_buffer(index, forced),
+                        Err(message) => editor.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(editor.config.theme.error)),
+                    }
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(sp|split)\b"#).unwrap(),
+                name: "split".to_owned(),
+                description: "Splits the window in two, one above the other, optionally opening a file in the new one".to_owned(),
+                function: |editor, params, _forced, _range| {
+                    let path = argument(&params);
+                    editor.split_window(Direction::Horizontal, Some(path.as_str()).filter(|path| !path.is_empty()));
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(vs|vsplit)\b"#).unwrap(),
+                name: "vsplit".to_owned(),
+                description: "Splits the window in two side by side, optionally opening a file in the new one".to_owned(),
+                function: |editor, params, _forced, _range| {
+                    let path = argument(&params);
+                    editor.split_window(Direction::Vertical, Some(path.as_str()).filter(|path| !path.is_empty()));
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(tabnew|tabe|tabedit)\b"#).unwrap(),
+                name: "tabnew".to_owned(),
+                description: "Opens a tab with an empty buffer, or the given file".to_owned(),
+                function: |editor, params, _forced, _range| {
+                    let path = argument(&params);
+                    editor.new_tab(Some(path.as_str()).filter(|path| !path.is_empty()));
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(tabn|tabnext)\b"#).unwrap(),
+                name: "tabnext".to_owned(),
+                description: "Switches to the next tab".to_owned(),
+                function: |editor, _params, _forced, _range| {
+                    editor.cycle_tab(1);
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(tabp|tabprev|tabprevious)\b"#).unwrap(),
+                name: "tabprevious".to_owned(),
+                description: "Switches to the previous tab".to_owned(),
+                function: |editor, _params, _forced, _range| {
+                    editor.cycle_tab(-1);
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(tabc|tabclose)\b"#).unwrap(),
+                name: "tabclose".to_owned(),
+                description: "Closes the current tab and its windows".to_owned(),
+                function: |editor, _params, _forced, _range| {
+                    editor.close_tab();
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(hex)\b"#).unwrap(),
+                name: "hex".to_owned(),
+                description: "Switches the buffer between editing its text and its bytes in hex".to_owned(),
+                function: |editor, _params, _forced, _range| {
+                    editor.toggle_hex();
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(go|goto)\b"#).unwrap(),
+                name: "goto".to_owned(),
+                description: "Moves to a byte offset in the file, in hex (0x1f00) or decimal".to_owned(),
+                function: |editor, params, _forced, _range| {
+                    match hex::parse_offset(&argument(&params)) {
+                        Ok(offset) => editor.goto_byte(offset),
+                        Err(message) => editor.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(editor.config.theme.error)),
+                    }
+                },
+            },
+            Command {
+                regex: Regex::new(r#"^(set|se)\b"#).unwrap(),
+                name: "set".to_owned(),
+                description: "Sets or shows options of the current buffer: fileformat=unix|dos, fileencoding=, [no]bomb, [no]endofline, [no]readonly".to_owned(),
+                function: |editor, params, _forced, _range| {
+                    let mut shown = vec![];
+                    for option in params.iter().filter(|param| !param.is_empty()) {
+                        match Commands::set_option(editor, option) {
+                            Ok(Some(value)) => shown.push(value),
+                            Ok(None) => (),
+                            Err(message) => {
+                                editor.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(editor.config.theme.error));
+                                return;
+                            }
+                        }
+                    }
+                    editor.status_message = StatusMessage::from(shown.join("  "), None);
+                },
+            },
         ];
         Self {
             commands: stock_commands,
+            range_regex: Regex::new(r#"^\s*(%|(\d+|\.|\$|'[<>])(\s*,\s*(\d+|\.|\$|'[<>]))?)"#).unwrap(),
         }
     }
+    /// Splits a leading line range (`%`, `N`, `.`, `$`, `'<`, `'>` or `a,b` of those) off
+    /// a command line, resolving it to rows of the current document
+    /// # Errors
+    /// Will return `Err` with a message if the range uses a mark that is not set
+    pub fn parse_range<'a>(&self, editor: &Editor, command_line: &'a str) -> Result<(Option<LineRange>, &'a str), String> {
+        let captures = match self.range_regex.captures(command_line) {
+            Some(captures) => captures,
+            None => return Ok((None, command_line)),
+        };
+        let rest = command_line.get(captures.get(0).map_or(0, |m| m.end())..).unwrap_or("");
+        let last_row = editor.document.len().saturating_sub(1);
+        if captures.get(1).map(|m| m.as_str()) == Some("%") {
+            return Ok((Some(LineRange { start: 0, end: last_row }), rest));
+        }
+        let resolve = |address: &str| -> Result<usize, String> {
+            let row = match address {
+                "." => editor.cursor_position.y,
+                "$" => last_row,
+                "'<" => editor.visual_marks.ok_or("Mark not set")?.0.y,
+                "'>" => editor.visual_marks.ok_or("Mark not set")?.1.y,
+                number => number.parse::<usize>().map_err(|_| "Invalid range")?.saturating_sub(1),
+            };
+            Ok(row.min(last_row))
+        };
+        let start = resolve(captures.get(2).map_or("", |m| m.as_str()))?;
+        let end = match captures.get(4) {
+            Some(end) => resolve(end.as_str())?,
+            None => start,
+        };
+        Ok((Some(LineRange { start: start.min(end), end: start.max(end) }), rest))
+    }
     pub fn search_command(editor: &mut Editor, query: &str, reverse: bool, live_update: bool) {
-        let positions: Vec<Position> = editor.document.find(query);
+        let positions: Vec<Match> = match Query::parse(query) {
+            Ok(parsed) => editor.document.find(&parsed),
+            Err(message) => {
+                editor.status_message = if live_update {
+                    StatusMessage::from(format!("/{} - {}", query, message), Option::from(editor.config.theme.error))
+                } else {
+                    StatusMessage::from(format!("ERR: {}", message), Option::from(editor.config.theme.error))
+                };
+                return;
+            }
+        };
         let mut i: usize = if reverse { positions.len().saturating_sub(1) } else { 0 };
         let mut direction_just_jumped: isize = 1;
         if positions.is_empty() {
             if live_update {
-                editor.status_message = StatusMessage::from( format!("/{} - No results found", query), Option::from(crate::ERROR_COLOR));
+                editor.status_message = StatusMessage::from( format!("/{} - No results found", query), Option::from(editor.config.theme.error));
             } else {
-                editor.status_message = StatusMessage::from("No results found".to_owned(), Option::from(crate::ERROR_COLOR));
+                editor.status_message = StatusMessage::from("No results found".to_owned(), Option::from(editor.config.theme.error));
             }
 
             return;
@@ -92,24 +421,23 @@ impl Commands {
                 let mut y;
                 #[allow(clippy::integer_division)]
                 if direction_just_jumped == 1 {
-                    y = position.y.saturating_add(usize::from(editor.terminal.size().height / 2));
+                    y = position.y.saturating_add(usize::from(editor.viewport().height / 2));
                 } else {
-                    y = position.y.saturating_sub(usize::from(editor.terminal.size().height / 2));
+                    y = position.y.saturating_sub(usize::from(editor.viewport().height / 2));
                 }
                 y = y.clamp(0, editor.document.len());
-                editor.cursor_position = Position{ x: position.x, y };
+                editor.cursor_position = Position{ x: position.start, y };
                 editor.scroll();
-                editor.cursor_position = Position{ x: position.x, y: position.y };
+                editor.cursor_position = Position{ x: position.start, y: position.y };
 
                 for p in &positions {
-                    if let Some(row) = editor.document.row_mut(p.y) {
-                        for c in (p.x)..(p.x.saturating_add(query.len())) {
-                            if p.y == position.y {
-                                row.add_highlighting(highlighting::Type::SearchSelected, c);
-                            } else {
-                                row.add_highlighting(highlighting::Type::Search, c);
-                            }
-                        }
+                    for x in p.start..p.end {
+                        let highlight_type = if p == position {
+                            highlighting::Type::SearchSelected
+                        } else {
+                            highlighting::Type::Search
+                        };
+                        editor.document.highlight(&Position { x, y: p.y }, highlight_type);
                     }
                 }
             }
@@ -119,7 +447,7 @@ impl Commands {
             }
 
             editor.document.reset_highlighting();
-            if let Ok(key) = Terminal::read_key() {
+            if let Ok(key) = editor.read_key() {
                 match key {
                     Key::Char('n') => {
                         if i > 0 {
@@ -135,10 +463,154 @@ impl Commands {
                     _ => (),
                 }
                 i = i.clamp(0, positions.len().saturating_sub(1));
-        };
+            } else {
+                break;
+            }
         }
         editor.status_message = StatusMessage::from("".to_owned(), None);
     }
+    /// Runs `:s` over the rows in `range` (or the cursor row), asking before each
+    /// replacement when given the `c` flag
+    pub fn substitute_command(editor: &mut Editor, command: &str, range: Option<LineRange>) {
+        let substitution = match Substitution::parse(command) {
+            Ok(substitution) => substitution,
+            Err(message) => {
+                editor.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(editor.config.theme.error));
+                return;
+            }
+        };
+        let range = range.unwrap_or(LineRange { start: editor.cursor_position.y, end: editor.cursor_position.y });
+        let mut replace_all = !substitution.confirm;
+        let mut finished = false;
+        let (mut substitutions, mut lines, mut last_row) = (0_usize, 0_usize, None);
+        let (mut y, mut end) = (range.start, range.end);
+        while y <= end && !finished {
+            let matches: Vec<(usize, usize, String)> = match editor.document.row(y) {
+                Some(row) => substitution
+                    .matches(row.as_str())
+                    .into_iter()
+                    .map(|(bytes, replacement)| (row.grapheme_index(bytes.start), row.grapheme_index(bytes.end), replacement))
+                    .collect(),
+                None => break,
+            };
+            let mut accepted = vec![];
+            for (start, match_end, replacement) in matches {
+                if !replace_all {
+                    match Commands::confirm_substitution(editor, Position { x: start, y }, match_end, &replacement) {
+                        Some('y') => (),
+                        Some('n') => continue,
+                        Some('a') => replace_all = true,
+                        Some('l') => finished = true,
+                        _ => {
+                            finished = true;
+                            break;
+                        }
+                    }
+                }
+                accepted.push((start, match_end, replacement));
+                if finished {
+                    break;
+                }
+            }
+            // Replace from the right so the columns of earlier matches stay valid
+            for (start, match_end, replacement) in accepted.iter().rev() {
+                editor.document.delete_range(&Position { x: *start, y }, &Position { x: *match_end, y });
+                editor.document.insert_text(&Position { x: *start, y }, replacement);
+            }
+            let added_rows = accepted.iter().map(|(_, _, replacement)| replacement.matches('\n').count()).sum::<usize>();
+            if !accepted.is_empty() {
+                substitutions = substitutions.saturating_add(accepted.len());
+                lines = lines.saturating_add(1);
+                last_row = Some(y.saturating_add(added_rows));
+            }
+            end = end.saturating_add(added_rows);
+            y = y.saturating_add(added_rows).saturating_add(1);
+        }
+        if let Some(row) = last_row {
+            editor.cursor_position = Position { x: editor.document.row(row).map_or(0, motion::first_non_blank), y: row };
+        }
+        editor.status_message = if substitutions > 0 {
+            let plural = |count: usize, word: &str| format!("{} {}{}", count, word, if count == 1 { "" } else { "s" });
+            StatusMessage::from(format!("{} on {}", plural(substitutions, "substitution"), plural(lines, "line")), None)
+        } else if finished {
+            StatusMessage::from(String::new(), None)
+        } else {
+            StatusMessage::from(format!("ERR: Pattern not found: {}", substitution.pattern()), Option::from(editor.config.theme.error))
+        };
+    }
+    /// Highlights the match from `at` to `end` and asks whether to replace it, returning the
+    /// answer (`y`, `n`, `a`ll, `q`uit or `l`ast) or `None` if aborted
+    fn confirm_substitution(editor: &mut Editor, at: Position, end: usize, replacement: &str) -> Option<char> {
+        editor.cursor_position = at;
+        editor.scroll();
+        editor.status_message = StatusMessage::from(format!("replace with {} (y/n/a/q/l)?", replacement.replace('\n', "\\r")), None);
+        loop {
+            for x in at.x..end {
+                editor.document.highlight(&Position { x, y: at.y }, highlighting::Type::SearchSelected);
+            }
+            if editor.refresh_screen(true).is_err() {
+                return None;
+            }
+            match editor.read_key() {
+                Ok(Key::Char(c)) if "ynaql".contains(c) => return Some(c),
+                Ok(Key::Esc) | Err(_) => return None,
+                Ok(_) => (),
+            }
+        }
+    }
+    /// Applies one `:set` argument: `name=value`, `name` or `noname` for a flag, or
+    /// `name?` to show the value, which is returned
+    fn set_option(editor: &mut Editor, option: &str) -> Result<Option<String>, String> {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option.trim_end_matches('?'), None),
+        };
+        let query = option.ends_with('?');
+        let mut format = editor.document.format();
+        let (flag, enabled) = match name.strip_prefix("no") {
+            Some(flag) if value.is_none() && !query => (flag, false),
+            _ => (name, true),
+        };
+        match (flag, value) {
+            ("fileformat" | "ff", None) => return Ok(Some(format!("fileformat={}", format.line_ending.name()))),
+            ("fileformat" | "ff", Some(value)) => {
+                format.line_ending = LineEnding::from_name(value).ok_or_else(|| format!("Invalid fileformat {}", value))?;
+            }
+            ("fileencoding" | "fenc", None) => return Ok(Some(format!("fileencoding={}", format.encoding))),
+            ("fileencoding" | "fenc", Some(value)) => {
+                format.encoding = Encoding::from_name(value).ok_or_else(|| format!("Invalid fileencoding {}", value))?;
+                // UTF-16 files are only recognised by their BOM, and other encodings have none
+                format.bom = matches!(format.encoding, Encoding::Utf16Le | Encoding::Utf16Be) || format.bom && format.encoding.is_unicode();
+            }
+            ("bomb" | "endofline" | "eol", None) if query => {
+                let set = if flag == "bomb" { format.bom } else { format.final_newline };
+                return Ok(Some(format!("{}{}", if set { "" } else { "no" }, flag)));
+            }
+            ("bomb", None) => format.bom = enabled,
+            ("readonly" | "ro", None) if query => {
+                return Ok(Some(format!("{}readonly", if editor.document.is_read_only() { "" } else { "no" })));
+            }
+            ("readonly" | "ro", None) => {
+                editor.document.set_read_only(enabled);
+                return Ok(None);
+            }
+            ("endofline" | "eol", None) => format.final_newline = enabled,
+            _ => return Err(format!("Unknown option: {}", option)),
+        }
+        editor.document.set_format(format);
+        Ok(None)
+    }
+    /// The index of the buffer numbered by the argument of `:b` or `:bd`, or the current
+    /// buffer if there is none
+    fn buffer_param(editor: &Editor, params: &[&str]) -> Result<usize, String> {
+        let argument = argument(params);
+        let id = if argument.is_empty() {
+            editor.current_buffer_id()
+        } else {
+            argument.parse::<usize>().map_err(|_| format!("Invalid buffer number {}", argument))?
+        };
+        editor.buffer_index(id).ok_or_else(|| format!("Buffer {} does not exist", id))
+    }
     #[must_use]
     pub fn get_command(&self, command_name: &str) -> Option<&Command> {
         let mut command: Option<&Command> = None;
@@ -149,4 +621,71 @@ impl Commands {
         }
         command
     }
-}
\ No newline at end of file
+}
+
+/// The register named by the first parameter of `:d` or `:y`, e.g. `:d a`
+fn register_param(params: &[&str]) -> Option<char> {
+    params.iter().find(|param| !param.is_empty()).and_then(|param| param.chars().next())
+}
+
+/// Everything after the command name and any `!`, e.g. the path in `:e! notes.txt`
+fn argument(params: &[&str]) -> String {
+    params.join(" ").trim_start_matches('!').trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{open, play, TempFile};
+
+    const TEXT: &str = "one\ntwo\nthree\nfour\nfive\n";
+
+    fn range(editor: &Editor, command_line: &str) -> Result<Option<(usize, usize)>, String> {
+        let (range, _) = Commands::default().parse_range(editor, command_line)?;
+        Ok(range.map(|range| (range.start, range.end)))
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let file = TempFile::new("range", "notes.txt", TEXT);
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, "j");
+        let (whole, rest) = Commands::default().parse_range(&editor, "%s/o/0/").unwrap();
+        assert_eq!((whole.map(|range| (range.start, range.end)), rest), (Some((0, 4)), "s/o/0/"));
+        assert_eq!(range(&editor, ".,$d"), Ok(Some((1, 4))));
+        assert_eq!(range(&editor, " 4 , 2 d"), Ok(Some((1, 3))));
+        assert_eq!(range(&editor, "9"), Ok(Some((4, 4))));
+        assert_eq!(range(&editor, "w"), Ok(None));
+        assert_eq!(range(&editor, "'<,'>d"), Err("Mark not set".to_owned()));
+
+        play(&mut editor, &screen, "jVj\x1bgg");
+        assert_eq!(range(&editor, "'<,'>d"), Ok(Some((2, 3))));
+        assert_eq!(range(&editor, "'>,1d"), Ok(Some((0, 3))));
+    }
+
+    #[test]
+    fn runs_commands_on_the_visual_selection() {
+        let file = TempFile::new("visual-range", "notes.txt", TEXT);
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, "jvj:d\n");
+        assert_eq!(editor.document.text(), "one\nfour\nfive");
+        play(&mut editor, &screen, "Vj:s/f/F/\n");
+        assert_eq!(editor.document.text(), "one\nFour\nFive");
+    }
+
+    #[test]
+    fn operates_on_each_kind_of_selection() {
+        let file = TempFile::new("visual", "notes.txt", TEXT);
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, "lvjy$p");
+        assert_eq!(editor.document.text(), "onene\ntw\ntwo\nthree\nfour\nfive");
+        play(&mut editor, &screen, "uggVj>");
+        assert_eq!(editor.document.text(), "    one\n    two\nthree\nfour\nfive");
+        play(&mut editor, &screen, "V<jjV~");
+        assert_eq!(editor.document.text(), "one\n    two\nTHREE\nfour\nfive");
+        play(&mut editor, &screen, "gg");
+        screen.press(vec![Key::Ctrl('v')]);
+        play(&mut editor, &screen, "jjld");
+        assert_eq!(editor.document.text(), "e\n  two\nREE\nfour\nfive");
+    }
+}
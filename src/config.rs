@@ -0,0 +1,440 @@
+use crate::registers::Clipboard;
+use crate::save::Backup;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use termion::color::Rgb;
+use termion::event::Key;
+use toml::Value;
+
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum LineNumbers {
+    Absolute,
+    Relative,
+    Off,
+}
+
+impl LineNumbers {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "absolute" => Some(LineNumbers::Absolute),
+            "relative" => Some(LineNumbers::Relative),
+            "off" => Some(LineNumbers::Off),
+            _ => None,
+        }
+    }
+}
+
+pub struct Theme {
+    pub error: Rgb,
+    pub status_fg: Rgb,
+    pub status_bg: Rgb,
+    pub message_fg: Rgb,
+    pub line_number: Rgb,
+    pub search_fg: Rgb,
+    pub search_bg: Rgb,
+    pub search_selected_fg: Rgb,
+    pub search_selected_bg: Rgb,
+    pub selection_fg: Rgb,
+    pub selection_bg: Rgb,
+    pub keyword: Rgb,
+    pub data_type: Rgb,
+    pub string: Rgb,
+    pub comment: Rgb,
+    pub number: Rgb,
+    /// Plain text, drawn in the terminal's own colours unless set
+    pub text_fg: Option<Rgb>,
+    pub text_bg: Option<Rgb>,
+}
+
+impl Theme {
+    #[must_use]
+    pub fn default() -> Self {
+        Self {
+            error: Rgb(197, 15, 31),
+            status_fg: Rgb(0, 0, 0),
+            status_bg: Rgb(239, 239, 239),
+            message_fg: Rgb(255, 255, 255),
+            line_number: Rgb(249, 241, 165),
+            search_fg: Rgb(0, 0, 0),
+            search_bg: Rgb(249, 241, 165),
+            search_selected_fg: Rgb(0, 0, 0),
+            search_selected_bg: Rgb(255, 255, 255),
+            selection_fg: Rgb(0, 0, 0),
+            selection_bg: Rgb(160, 180, 220),
+            keyword: Rgb(198, 120, 221),
+            data_type: Rgb(229, 192, 123),
+            string: Rgb(152, 195, 121),
+            comment: Rgb(127, 132, 142),
+            number: Rgb(209, 154, 102),
+            text_fg: None,
+            text_bg: None,
+        }
+    }
+    fn set_color(&mut self, name: &str, color: Rgb) -> Result<(), String> {
+        match name {
+            "text_fg" => self.text_fg = Some(color),
+            "text_bg" => self.text_bg = Some(color),
+            _ => *self.color_mut(name).ok_or_else(|| "unknown color".to_owned())? = color,
+        }
+        Ok(())
+    }
+    fn color_mut(&mut self, name: &str) -> Option<&mut Rgb> {
+        match name {
+            "error" => Some(&mut self.error),
+            "status_fg" => Some(&mut self.status_fg),
+            "status_bg" => Some(&mut self.status_bg),
+            "message_fg" => Some(&mut self.message_fg),
+            "line_number" => Some(&mut self.line_number),
+            "search_fg" => Some(&mut self.search_fg),
+            "search_bg" => Some(&mut self.search_bg),
+            "search_selected_fg" => Some(&mut self.search_selected_fg),
+            "search_selected_bg" => Some(&mut self.search_selected_bg),
+            "selection_fg" => Some(&mut self.selection_fg),
+            "selection_bg" => Some(&mut self.selection_bg),
+            "keyword" => Some(&mut self.keyword),
+            "type" => Some(&mut self.data_type),
+            "string" => Some(&mut self.string),
+            "comment" => Some(&mut self.comment),
+            "number" => Some(&mut self.number),
+            _ => None,
+        }
+    }
+}
+
+/// User settings, read from `<config dir>/config.toml`:
+///
+/// ```toml
+/// tab_width = 4
+/// expand_tab = true # indent with spaces rather than tabs
+/// line_numbers = "relative" # "absolute", "relative" or "off"
+/// backup = true # keep the previous version as file~, or in a directory: "~/.cache/lecturn"
+///
+/// [theme]
+/// status_bg = "#efefef"
+///
+/// [keymap] # Command mode only
+/// "<C-s>" = ":w<CR>"
+///
+/// [clipboard] # shell commands behind the "+ register
+/// copy = "xclip -selection clipboard"
+/// paste = "xclip -selection clipboard -o"
+/// ```
+pub struct Config {
+    pub theme: Theme,
+    pub tab_width: usize,
+    pub expand_tab: bool,
+    pub line_numbers: LineNumbers,
+    pub keymap: HashMap<Key, Vec<Key>>,
+    pub clipboard: Clipboard,
+    pub backup: Backup,
+}
+
+impl Config {
+    #[must_use]
+    pub fn default() -> Self {
+        Self {
+            theme: Theme::default(),
+            tab_width: 4,
+            expand_tab: true,
+            line_numbers: LineNumbers::Absolute,
+            keymap: HashMap::new(),
+            clipboard: Clipboard::default(),
+            backup: Backup::Off,
+        }
+    }
+    /// One level of indentation
+    #[must_use]
+    pub fn indent(&self) -> String {
+        if self.expand_tab {
+            " ".repeat(self.tab_width)
+        } else {
+            "\t".to_owned()
+        }
+    }
+    #[must_use]
+    pub fn default_path() -> Option<PathBuf> {
+        crate::config_dir().map(|dir| dir.join(CONFIG_FILE_NAME))
+    }
+    /// Reads the config at `path` on top of the defaults. A missing file is not an error;
+    /// every invalid entry is skipped and described in the returned messages
+    #[must_use]
+    pub fn load(path: &Path) -> (Self, Vec<String>) {
+        let mut config = Self::default();
+        if !path.exists() {
+            return (config, vec![]);
+        }
+        let errors = match fs::read_to_string(path) {
+            Ok(contents) => config.apply(&contents),
+            Err(error) => vec![format!("{}: {}", path.display(), error)],
+        };
+        (config, errors)
+    }
+    fn apply(&mut self, contents: &str) -> Vec<String> {
+        let table = match contents.parse::<Value>() {
+            Ok(Value::Table(table)) => table,
+            Ok(_) => return vec!["expected a table".to_owned()],
+            Err(error) => return vec![error.to_string()],
+        };
+        let mut errors = vec![];
+        for (name, value) in &table {
+            let result = match name.as_str() {
+                "tab_width" => match value.as_integer().map(usize::try_from) {
+                    Some(Ok(width)) if width > 0 => {
+                        self.tab_width = width;
+                        Ok(())
+                    }
+                    _ => Err("expected a positive integer".to_owned()),
+                },
+                "expand_tab" => match value.as_bool() {
+                    Some(expand_tab) => {
+                        self.expand_tab = expand_tab;
+                        Ok(())
+                    }
+                    None => Err("expected true or false".to_owned()),
+                },
+                "line_numbers" => match value.as_str().and_then(LineNumbers::from_name) {
+                    Some(line_numbers) => {
+                        self.line_numbers = line_numbers;
+                        Ok(())
+                    }
+                    None => Err("expected \"absolute\", \"relative\" or \"off\"".to_owned()),
+                },
+                "theme" => match value.as_table() {
+                    Some(theme) => {
+                        for (color_name, color) in theme {
+                            let result = color
+                                .as_str()
+                                .ok_or_else(|| "expected a string".to_owned())
+                                .and_then(parse_color)
+                                .and_then(|color| self.theme.set_color(color_name, color));
+                            if let Err(error) = result {
+                                errors.push(format!("theme.{}: {}", color_name, error));
+                            }
+                        }
+                        Ok(())
+                    }
+                    None => Err("expected a table".to_owned()),
+                },
+                "keymap" => match value.as_table() {
+                    Some(keymap) => {
+                        for (from, to) in keymap {
+                            let parsed = parse_keys(from).and_then(|from| match from.as_slice() {
+                                [key] => Ok(*key),
+                                _ => Err("a mapping must start from a single key".to_owned()),
+                            });
+                            let to = to.as_str().ok_or_else(|| "expected a string".to_owned()).and_then(parse_keys);
+                            match (parsed, to) {
+                                (Ok(from), Ok(to)) => {
+                                    self.keymap.insert(from, to);
+                                }
+                                (Err(error), _) | (_, Err(error)) => errors.push(format!("keymap.\"{}\": {}", from, error)),
+                            }
+                        }
+                        Ok(())
+                    }
+                    None => Err("expected a table".to_owned()),
+                },
+                "clipboard" => match value.as_table() {
+                    Some(clipboard) => {
+                        for (command_name, command) in clipboard {
+                            let slot = match command_name.as_str() {
+                                "copy" => &mut self.clipboard.copy,
+                                "paste" => &mut self.clipboard.paste,
+                                _ => {
+                                    errors.push(format!("clipboard.{}: unknown command", command_name));
+                                    continue;
+                                }
+                            };
+                            match command.as_str() {
+                                Some(command) => command.clone_into(slot),
+                                None => errors.push(format!("clipboard.{}: expected a string", command_name)),
+                            }
+                        }
+                        Ok(())
+                    }
+                    None => Err("expected a table".to_owned()),
+                },
+                "backup" => Backup::from_value(value).map(|backup| self.backup = backup),
+                _ => Err("unknown option".to_owned()),
+            };
+            if let Err(error) = result {
+                errors.push(format!("{}: {}", name, error));
+            }
+        }
+        errors
+    }
+}
+
+/// Parses `#rrggbb`
+fn parse_color(color: &str) -> Result<Rgb, String> {
+    let invalid = || format!("invalid color \"{}\" (expected #rrggbb)", color);
+    let hex = color.strip_prefix('#').filter(|hex| hex.len() == 6).ok_or_else(invalid)?;
+    let channel = |range: std::ops::Range<usize>| {
+        hex.get(range).and_then(|channel| u8::from_str_radix(channel, 16).ok()).ok_or_else(invalid)
+    };
+    Ok(Rgb(channel(0..2)?, channel(2..4)?, channel(4..6)?))
+}
+
+/// Parses vim-style key notation, e.g. `:w<CR>` or `<C-s>`
+fn parse_keys(notation: &str) -> Result<Vec<Key>, String> {
+    let mut keys = vec![];
+    let mut rest = notation;
+    while let Some(c) = rest.chars().next() {
+        let special = if c == '<' { rest.find('>').and_then(|end| rest.get(1..end)) } else { None };
+        if let Some(name) = special {
+            keys.push(parse_special_key(name).ok_or_else(|| format!("unknown key <{}>", name))?);
+            rest = rest.get(name.len().saturating_add(2)..).unwrap_or("");
+        } else {
+            keys.push(Key::Char(c));
+            rest = rest.get(c.len_utf8()..).unwrap_or("");
+        }
+    }
+    if keys.is_empty() {
+        return Err("empty key sequence".to_owned());
+    }
+    Ok(keys)
+}
+
+fn parse_special_key(name: &str) -> Option<Key> {
+    let mut chars = name.chars();
+    if let (Some(modifier), Some('-'), Some(c), None) = (chars.next(), chars.next(), chars.next(), chars.next()) {
+        return match modifier.to_ascii_uppercase() {
+            'C' => Some(Key::Ctrl(c.to_ascii_lowercase())),
+            'A' | 'M' => Some(Key::Alt(c)),
+            _ => None,
+        };
+    }
+    let key = match name.to_ascii_lowercase().as_str() {
+        "cr" | "enter" | "return" => Key::Char('\n'),
+        "tab" => Key::Char('\t'),
+        "space" => Key::Char(' '),
+        "lt" => Key::Char('<'),
+        "esc" => Key::Esc,
+        "bs" => Key::Backspace,
+        "del" => Key::Delete,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_settings() {
+        let mut config = Config::default();
+        let errors = config.apply(
+            r##"
+            tab_width = 2
+            expand_tab = false
+            line_numbers = "relative"
+            backup = true
+
+            [theme]
+            status_bg = "#102030"
+            text_fg = "#ffffff"
+
+            [keymap]
+            "<C-s>" = ":w<CR>"
+
+            [clipboard]
+            copy = "pbcopy"
+            "##,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.tab_width, 2);
+        assert_eq!(config.indent(), "\t");
+        assert!(config.line_numbers == LineNumbers::Relative);
+        assert_eq!(config.backup, Backup::Beside);
+        assert_eq!(config.theme.status_bg, Rgb(0x10, 0x20, 0x30));
+        assert_eq!(config.theme.text_fg, Some(Rgb(255, 255, 255)));
+        assert_eq!(config.theme.text_bg, None);
+        assert_eq!(
+            config.keymap.get(&Key::Ctrl('s')),
+            Some(&vec![Key::Char(':'), Key::Char('w'), Key::Char('\n')])
+        );
+        assert_eq!(config.clipboard.copy, "pbcopy");
+    }
+
+    #[test]
+    fn reports_and_skips_invalid_entries() {
+        let mut config = Config::default();
+        let errors = config.apply(
+            r##"
+            tab_width = 0
+            expand_tab = "yes"
+            line_numbers = "hybrid"
+            colour = true
+
+            [theme]
+            keyword = "#12345"
+            sky = "#000000"
+            string = "#98c379"
+
+            [keymap]
+            "gg" = "G"
+            "<C-q>" = "<Nope>"
+
+            [clipboard]
+            cut = "x"
+            "##,
+        );
+        assert_eq!(
+            errors,
+            [
+                "clipboard.cut: unknown command",
+                "colour: unknown option",
+                "expand_tab: expected true or false",
+                "keymap.\"<C-q>\": unknown key <Nope>",
+                "keymap.\"gg\": a mapping must start from a single key",
+                "line_numbers: expected \"absolute\", \"relative\" or \"off\"",
+                "tab_width: expected a positive integer",
+                "theme.keyword: invalid color \"#12345\" (expected #rrggbb)",
+                "theme.sky: unknown color",
+            ]
+        );
+        // Valid entries next to the invalid ones still apply, and the rest keep their defaults
+        assert_eq!(config.theme.string, Rgb(0x98, 0xc3, 0x79));
+        assert_eq!(config.theme.keyword, Theme::default().keyword);
+        assert_eq!(config.tab_width, 4);
+        assert!(config.keymap.is_empty());
+        assert_eq!(config.apply("tab_width = "), ["unexpected eof encountered at line 1 column 13"]);
+    }
+
+    #[test]
+    fn loads_files() {
+        let path = std::env::temp_dir().join(format!("lecturn-config-{}.toml", std::process::id()));
+        let (config, errors) = Config::load(&path);
+        assert!(errors.is_empty());
+        assert_eq!(config.tab_width, 4);
+        fs::write(&path, "tab_width = 8\nwrap = true\n").unwrap();
+        let (config, errors) = Config::load(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(config.tab_width, 8);
+        assert_eq!(errors, ["wrap: unknown option"]);
+    }
+
+    #[test]
+    fn parses_key_notation() {
+        assert_eq!(
+            parse_keys("<lt><Space>x<A-j><esc>"),
+            Ok(vec![Key::Char('<'), Key::Char(' '), Key::Char('x'), Key::Alt('j'), Key::Esc])
+        );
+        assert_eq!(parse_keys("<C-W>"), Ok(vec![Key::Ctrl('w')]));
+        assert_eq!(parse_keys("a<b"), Ok(vec![Key::Char('a'), Key::Char('<'), Key::Char('b')]));
+        assert!(parse_keys("").is_err());
+    }
+}
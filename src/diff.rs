@@ -0,0 +1,146 @@
+/// Lines of unchanged text shown around each change
+const CONTEXT: usize = 3;
+/// Beyond this many cells the changed middle is shown as one block rather than compared
+/// line by line, which would take too long
+const MAX_CELLS: usize = 4_000_000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Keep,
+    Remove,
+    Add,
+}
+
+/// A unified diff from `old` to `new`, e.g. the file on disk and the buffer, without the
+/// `---`/`+++` header. Empty if they are the same
+#[must_use]
+pub fn unified(old: &[&str], new: &[&str]) -> Vec<String> {
+    let edits = edits(old, new);
+    let mut lines = vec![];
+    // Where in `edits`, `old` and `new` each hunk starts
+    let (mut index, mut old_line, mut new_line) = (0, 0_usize, 0_usize);
+    while index < edits.len() {
+        let change = match edits.iter().skip(index).position(|&edit| edit != Edit::Keep) {
+            Some(change) => index.saturating_add(change),
+            None => break,
+        };
+        let skipped = change.saturating_sub(index).saturating_sub(CONTEXT);
+        let start = index.saturating_add(skipped);
+        old_line = old_line.saturating_add(skipped);
+        new_line = new_line.saturating_add(skipped);
+        // The hunk runs until the changes are more than two contexts apart
+        let mut end = change;
+        let mut keeps = 0;
+        for (position, &edit) in edits.iter().enumerate().skip(change) {
+            if edit == Edit::Keep {
+                keeps += 1;
+                if keeps > CONTEXT.saturating_mul(2) {
+                    break;
+                }
+            } else {
+                keeps = 0;
+                end = position;
+            }
+        }
+        let end = end.saturating_add(CONTEXT).saturating_add(1).min(edits.len());
+        let hunk = edits.get(start..end).unwrap_or_default();
+        let old_len = hunk.iter().filter(|&&edit| edit != Edit::Add).count();
+        let new_len = hunk.iter().filter(|&&edit| edit != Edit::Remove).count();
+        lines.push(format!("@@ -{},{} +{},{} @@", old_line.saturating_add(1), old_len, new_line.saturating_add(1), new_len));
+        for &edit in hunk {
+            match edit {
+                Edit::Keep => {
+                    lines.push(format!(" {}", old.get(old_line).unwrap_or(&"")));
+                    old_line = old_line.saturating_add(1);
+                    new_line = new_line.saturating_add(1);
+                }
+                Edit::Remove => {
+                    lines.push(format!("-{}", old.get(old_line).unwrap_or(&"")));
+                    old_line = old_line.saturating_add(1);
+                }
+                Edit::Add => {
+                    lines.push(format!("+{}", new.get(new_line).unwrap_or(&"")));
+                    new_line = new_line.saturating_add(1);
+                }
+            }
+        }
+        index = end;
+    }
+    lines
+}
+
+/// The edits turning `old` into `new`: common lines at either end are kept, and the
+/// middle is compared by longest common subsequence
+#[allow(clippy::indexing_slicing, clippy::integer_arithmetic)]
+fn edits(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+    let mut edits = vec![Edit::Keep; prefix];
+    if old_middle.len().saturating_mul(new_middle.len()) > MAX_CELLS {
+        edits.extend(old_middle.iter().map(|_| Edit::Remove));
+        edits.extend(new_middle.iter().map(|_| Edit::Add));
+    } else {
+        // lengths[i][j]: the longest common subsequence of old_middle[i..] and new_middle[j..]
+        let (rows, columns) = (old_middle.len(), new_middle.len());
+        let mut lengths = vec![vec![0_usize; columns + 1]; rows + 1];
+        for i in (0..rows).rev() {
+            for j in (0..columns).rev() {
+                lengths[i][j] = if old_middle[i] == new_middle[j] {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < rows || j < columns {
+            if i < rows && j < columns && old_middle[i] == new_middle[j] {
+                edits.push(Edit::Keep);
+                i += 1;
+                j += 1;
+            } else if i < rows && (j == columns || lengths[i + 1][j] >= lengths[i][j + 1]) {
+                edits.push(Edit::Remove);
+                i += 1;
+            } else {
+                edits.push(Edit::Add);
+                j += 1;
+            }
+        }
+    }
+    edits.extend(std::iter::repeat_n(Edit::Keep, suffix));
+    edits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_changes_with_context() {
+        let old = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+        let new = ["a", "b", "c", "d", "e", "F", "g", "h", "i", "j", "k"];
+        assert_eq!(
+            unified(&old, &new),
+            ["@@ -3,8 +3,9 @@", " c", " d", " e", "-f", "+F", " g", " h", " i", " j", "+k"]
+        );
+        assert!(unified(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn splits_distant_changes_into_hunks() {
+        let old: Vec<String> = (0..20).map(|n| n.to_string()).collect();
+        let old: Vec<&str> = old.iter().map(String::as_str).collect();
+        let mut new = old.clone();
+        new.remove(1);
+        new.insert(17, "x");
+        assert_eq!(
+            unified(&old, &new),
+            [
+                "@@ -1,5 +1,4 @@", " 0", "-1", " 2", " 3", " 4",
+                "@@ -16,5 +15,6 @@", " 15", " 16", " 17", "+x", " 18", " 19",
+            ]
+        );
+    }
+}
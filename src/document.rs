@@ -1,37 +1,130 @@
-use crate::{Position, Row};
+use crate::encoding;
+use crate::fileformat::FileFormat;
+use crate::hex::Hex;
+use crate::history::{Change, History};
+use crate::rope::Rope;
+use crate::save::{self, Backup, SaveError, Stamp};
+use crate::search::{Match, Query};
+use crate::swap;
+use crate::syntax::Language;
+use crate::{highlighting, Position, Row};
 use std::fs;
-use std::io::{Write, Error};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use unicode_segmentation::UnicodeSegmentation;
 
 pub struct Document {
-    rows: Vec<Row>,
+    rows: Rope<Row>,
     pub file_name: Option<String>,
     dirty: bool,
+    history: History,
+    highlighted_rows: Vec<usize>,
+    language: Option<Language>,
+    /// Rows before this one have up to date syntax highlighting
+    syntax_valid_until: usize,
+    swap: swap::State,
+    format: FileFormat,
+    /// Looks like a binary file rather than text
+    binary: bool,
+    /// The bytes being edited in hex mode, which replace the rows until it is left
+    hex: Option<Hex>,
+    /// Opened read-only, so it can't be changed, is only written with `:w!` and gets no
+    /// swap file
+    read_only: bool,
+    /// Read-only because it is binary, which hex mode lifts, kept apart from `read_only`
+    /// so leaving hex mode doesn't lose the user's choice
+    binary_guard: bool,
+    /// The file could be written when it was last opened or saved
+    writable: bool,
+    /// Why the last edit was refused, or that it changed a file that can't be written
+    notice: Option<EditNotice>,
+    /// `EditNotice::Unwritable` has been given, which only happens once
+    warned_unwritable: bool,
+    /// The file as it was last read or written, to notice other programs changing it
+    disk: Option<Stamp>,
+}
+
+/// Something to tell the user about an edit
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EditNotice {
+    /// The document is read-only, so the edit was not made
+    ReadOnly,
+    /// The edit was made, but the file can't be written
+    Unwritable,
+}
+
+/// What has happened to a document's file since it was last read or written
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiskChange {
+    Unchanged,
+    /// Another program has written something else to it
+    Changed,
+    /// It no longer exists, which is only reported once
+    Deleted,
 }
 
 impl Document {
     #[must_use]
     pub fn default() -> Self {
-        let rows = vec![Row::default()];
+        let mut rows = Rope::new();
+        rows.push(Row::default());
         Self {
             rows,
             file_name: None,
             dirty: false,
+            history: History::default(),
+            highlighted_rows: vec![],
+            language: None,
+            syntax_valid_until: 0,
+            swap: swap::State::default(),
+            format: FileFormat::default(),
+            binary: false,
+            hex: None,
+            read_only: false,
+            binary_guard: false,
+            writable: true,
+            notice: None,
+            warned_unwritable: false,
+            disk: None,
         }
     }
 
+    /// A document with no file name holding `bytes`, e.g. read from stdin, in whatever
+    /// format they turn out to be
+    #[must_use]
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let decoded = encoding::decode(bytes);
+        let (format, rows) = FileFormat::detect(&decoded.text, decoded.encoding);
+        let mut document = Self::default();
+        document.rows = rows.into_iter().collect();
+        document.format = format;
+        document.binary = decoded.binary;
+        document.binary_guard = decoded.binary;
+        document
+    }
+    /// A document of `text` that has no file, e.g. a diff
+    #[must_use]
+    pub fn scratch(text: &str) -> Self {
+        let mut document = Self::default();
+        document.rows = text.split('\n').map(Row::from).collect();
+        document
+    }
+
     /// # Errors
     /// Will return `Err` if fs fails to open the file (invalid permissions / not found)
     pub fn open(filename: &str) -> Result<Self, Error> {
-        let contents = fs::read_to_string(filename)?;
-        let mut rows = Vec::new();
-        for value in contents.lines() {
-            rows.push(Row::from(value));
+        let bytes = fs::read(filename)?;
+        let mut document = Self::from_bytes(&bytes);
+        document.file_name = Some(filename.to_owned());
+        document.disk = Stamp::new(Path::new(filename), &bytes).ok();
+        document.writable = save::is_writable(Path::new(filename));
+        let undo_file = undo_file_path(filename);
+        if Path::new(&undo_file).exists() {
+            if let Ok(history) = History::read_from(&undo_file, document.content_hash()) {
+                document.history = history;
+            }
         }
-        Ok(Self{
-            rows,
-            file_name: Some(filename.to_owned()),
-            dirty: false,
-        })
+        Ok(document)
     }
     #[must_use] pub fn row(&self, index: usize) -> Option<&Row> {
         self.rows.get(index)
@@ -50,6 +143,9 @@ impl Document {
     #[must_use] pub fn len(&self) -> usize {
         self.rows.len()
     }
+    fn invalidate_syntax(&mut self, from: usize) {
+        self.syntax_valid_until = self.syntax_valid_until.min(from);
+    }
     fn insert_newline(&mut self, at: &Position) {
         if at.y > self.rows.len() {
             return;
@@ -58,83 +154,599 @@ impl Document {
             self.rows.push(Row::default());
             return;
         }
-        #[allow(clippy::indexing_slicing)]
-        let new_row = self.rows[at.y].split(at.x);
-        #[allow(clippy::integer_arithmetic)]
-        self.rows.insert(at.y + 1, new_row);
+        if let Some(row) = self.rows.get_mut(at.y) {
+            let new_row = row.split(at.x);
+            self.rows.insert(at.y.saturating_add(1), new_row);
+        }
     }
     pub fn insert (&mut self, at: &Position, c: char) {
-        if at.y > self.rows.len() {
+        self.insert_text(at, c.encode_utf8(&mut [0; 4]));
+    }
+    /// Inserts `text` (which may span several rows) as a single recorded change
+    pub fn insert_text(&mut self, at: &Position, text: &str) {
+        if at.y > self.rows.len() || text.is_empty() || !self.allow_edit() {
             return;
         }
         self.dirty = true;
-        if c == '\n' {
-            self.insert_newline(at);
-            return;
-        }
-        if at.y == self.rows.len() {
-            let mut row = Row::default();
-            row.insert(0, c);
-            self.rows.push(row);
-        } else {
-            #[allow(clippy::indexing_slicing)]
-            let row = &mut self.rows[at.y];
-            row.insert(at.x, c);
+        self.swap.stale = true;
+        let mut at = *at;
+        let mut text = text.to_owned();
+        // Typing past the last row starts a new line after it, recorded as such so it can be undone
+        if at.y == self.rows.len() && at.y > 0 {
+            at.y = at.y.saturating_sub(1);
+            at.x = self.rows.get(at.y).map_or(0, Row::len);
+            if !text.starts_with('\n') {
+                text.insert(0, '\n');
+            }
         }
+        self.apply_insert(&at, &text);
+        self.history.record(Change::Insert { at, text });
     }
-    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
     pub fn delete(&mut self, at: &Position) {
         let len = self.rows.len();
-        if at.y >= len {
+        let text = match self.rows.get(at.y) {
+            Some(row) if at.x == row.len() && at.y.saturating_add(1) < len => "\n".to_owned(),
+            Some(row) => match row.grapheme(at.x) {
+                Some(grapheme) => grapheme.to_owned(),
+                None => return,
+            },
+            None => return,
+        };
+        if !self.allow_edit() {
             return;
         }
         self.dirty = true;
-        if at.x == self.rows[at.y].len() && at.y + 1 < len {
-            let next_row = self.rows.remove(at.y + 1);
-            let row = &mut self.rows[at.y];
-            row.append(&next_row);
-        } else {
-            let row = &mut self.rows[at.y];
-            row.delete(at.x);
+        self.swap.stale = true;
+        self.apply_delete(at, &text);
+        self.history.record(Change::Delete { at: *at, text });
+    }
+    /// The text from `start` up to (not including) `end`, with rows separated by `\n`
+    #[must_use] pub fn text_range(&self, start: &Position, end: &Position) -> String {
+        let mut text = String::new();
+        for y in start.y..=end.y {
+            let row = match self.rows.get(y) {
+                Some(row) => row,
+                None => break,
+            };
+            let from = if y == start.y { start.x } else { 0 };
+            let to = if y == end.y { end.x } else { row.len() };
+            text.push_str(row.slice(from, to));
+            if y != end.y && y.saturating_add(1) < self.rows.len() {
+                text.push('\n');
+            }
+        }
+        text
+    }
+    /// The whole text, with rows separated by `\n`
+    #[must_use] pub fn text(&self) -> String {
+        self.text_range(&Position::default(), &self.end())
+    }
+    /// The position after the last grapheme
+    fn end(&self) -> Position {
+        let last = self.rows.len().saturating_sub(1);
+        Position { x: self.rows.get(last).map_or(0, Row::len), y: last }
+    }
+    /// Deletes the text from `start` up to (not including) `end` as a single
+    /// recorded change, returning it
+    pub fn delete_range(&mut self, start: &Position, end: &Position) -> String {
+        let text = self.text_range(start, end);
+        if !text.is_empty() && self.allow_edit() {
+            self.dirty = true;
+            self.swap.stale = true;
+            self.apply_delete(start, &text);
+            self.history.record(Change::Delete { at: *start, text: text.clone() });
+        }
+        text
+    }
+    fn apply_insert(&mut self, at: &Position, text: &str) {
+        self.invalidate_syntax(at.y);
+        let mut at = *at;
+        for grapheme in text.graphemes(true) {
+            if grapheme == "\n" {
+                self.insert_newline(&at);
+                at = Position { x: 0, y: at.y.saturating_add(1) };
+                continue;
+            }
+            if at.y == self.rows.len() {
+                self.rows.push(Row::default());
+            }
+            if let Some(row) = self.rows.get_mut(at.y) {
+                row.insert_str(at.x, grapheme);
+            }
+            at.x = at.x.saturating_add(1);
+        }
+    }
+    fn apply_delete(&mut self, at: &Position, text: &str) {
+        self.invalidate_syntax(at.y);
+        for _ in text.graphemes(true) {
+            let len = self.rows.len();
+            let row_len = match self.rows.get(at.y) {
+                Some(row) => row.len(),
+                None => return,
+            };
+            if at.x == row_len && at.y.saturating_add(1) < len {
+                if let Some(next_row) = self.rows.remove(at.y.saturating_add(1)) {
+                    if let Some(row) = self.rows.get_mut(at.y) {
+                        row.append(&next_row);
+                    }
+                }
+            } else if let Some(row) = self.rows.get_mut(at.y) {
+                row.delete(at.x);
+            }
+        }
+    }
+    fn apply_changes(&mut self, changes: &[Change]) -> Option<Position> {
+        for change in changes {
+            match change {
+                Change::Insert { at, text } => self.apply_insert(at, text),
+                Change::Delete { at, text } => self.apply_delete(at, text),
+            }
+        }
+        self.dirty = !self.history.is_saved();
+        self.swap.stale = true;
+        changes.last().map(Change::position)
+    }
+    /// Whether an edit may be made, noting for `take_notice` if it may not, or if it is the
+    /// first change to a file that can't be written
+    fn allow_edit(&mut self) -> bool {
+        if self.is_read_only() {
+            self.notice = Some(EditNotice::ReadOnly);
+            return false;
+        }
+        if !self.writable && !self.warned_unwritable {
+            self.warned_unwritable = true;
+            self.notice = Some(EditNotice::Unwritable);
+        }
+        true
+    }
+    /// What the user should be told about the last edit, if anything
+    pub fn take_notice(&mut self) -> Option<EditNotice> {
+        self.notice.take()
+    }
+    /// Whether the user made the document read-only, or it is binary and not in hex mode
+    #[must_use] pub fn is_read_only(&self) -> bool {
+        self.read_only || self.binary_guard
+    }
+    /// Makes the document read-only, so edits are refused and it is only written with
+    /// `:w!`, or lets it be changed again, binary or not
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+        if !read_only {
+            self.binary_guard = false;
+        }
+    }
+    /// Whether the file could be written when it was last opened or saved
+    #[must_use] pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+    /// Ends the current undo step, so the next edit starts a new one
+    pub fn commit_history(&mut self) {
+        self.history.commit();
+    }
+    /// Reverts the last undo step, returning where the cursor should go
+    pub fn undo(&mut self) -> Option<Position> {
+        if !self.allow_edit() {
+            return None;
+        }
+        let changes = self.history.undo()?;
+        self.apply_changes(&changes)
+    }
+    /// Reapplies the last undone step, returning where the cursor should go
+    pub fn redo(&mut self) -> Option<Position> {
+        if !self.allow_edit() {
+            return None;
+        }
+        let changes = self.history.redo()?;
+        self.apply_changes(&changes)
+    }
+    /// Moves to the state after change number `change` (0 being the original document)
+    pub fn undo_to(&mut self, change: usize) -> Option<Position> {
+        if !self.allow_edit() {
+            return None;
+        }
+        let changes = self.history.jump(change)?;
+        self.apply_changes(&changes)
+    }
+    #[must_use] pub fn history(&self) -> &History {
+        &self.history
+    }
+    /// Writes the undo history to `path`, defaulting to the sidecar next to the file
+    /// # Errors
+    /// Will return `Err` if there is no path to write to or the file cannot be written
+    pub fn write_history(&mut self, path: Option<&str>) -> Result<String, Error> {
+        let path = self.history_path(path)?;
+        let hash = self.content_hash();
+        self.history.write_to(&path, hash)?;
+        Ok(path)
+    }
+    /// Replaces the undo history with the one stored at `path`
+    /// # Errors
+    /// Will return `Err` if there is no path to read from, the file cannot be read
+    /// or it was written for different contents
+    pub fn read_history(&mut self, path: Option<&str>) -> Result<String, Error> {
+        let path = self.history_path(path)?;
+        self.history = History::read_from(&path, self.content_hash())?;
+        Ok(path)
+    }
+    fn history_path(&self, path: Option<&str>) -> Result<String, Error> {
+        match (path, &self.file_name) {
+            (Some(path), _) => Ok(path.to_owned()),
+            (None, Some(file_name)) => Ok(undo_file_path(file_name)),
+            (None, None) => Err(Error::new(std::io::ErrorKind::NotFound, "no file name")),
+        }
+    }
+    /// FNV-1a over the rows, stable between runs so undo files can be matched to their contents
+    fn content_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for row in self.rows.iter() {
+            for byte in row.as_bytes().iter().chain(b"\n") {
+                hash ^= u64::from(*byte);
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        hash
+    }
+    /// The line endings, BOM and final newline the document is saved with
+    #[must_use] pub fn format(&self) -> FileFormat {
+        self.format
+    }
+    #[must_use] pub fn is_binary(&self) -> bool {
+        self.binary
+    }
+    #[must_use] pub fn hex(&self) -> Option<&Hex> {
+        self.hex.as_ref()
+    }
+    pub fn hex_mut(&mut self) -> Option<&mut Hex> {
+        self.hex.as_mut()
+    }
+    /// Switches between editing the text and editing its bytes in hex mode. Entering it
+    /// encodes the text as it would be saved; leaving it decodes any edited bytes back
+    /// into text. Hex edits are written back exactly, so a binary file can be saved from
+    /// hex mode without `:w!`
+    /// # Errors
+    /// Will return `Err` with a message if the text has characters its encoding cannot
+    /// represent
+    pub fn toggle_hex(&mut self) -> Result<(), String> {
+        let hex = match self.hex.take() {
+            Some(hex) => hex,
+            None => {
+                let bytes = self
+                    .format
+                    .join(self.rows.iter())
+                    .map_err(|line| format!("Line {} cannot be written as {}", line.saturating_add(1), self.format.encoding))?;
+                self.hex = Some(Hex::new(bytes));
+                self.binary_guard = false;
+                return Ok(());
+            }
+        };
+        self.binary_guard = self.binary;
+        if hex.is_changed() {
+            let (format, text, _) = decode_text(hex.bytes());
+            self.replace_text(&text);
+            self.format = format;
+            if !hex.is_modified() {
+                // Saved from hex mode, so the text now matches the file
+                self.dirty = false;
+                self.history.mark_saved();
+            }
         }
+        Ok(())
     }
+    /// Changes how the document is saved, which counts as an unsaved change
+    pub fn set_format(&mut self, format: FileFormat) {
+        if format != self.format {
+            self.format = format;
+            self.dirty = true;
+        }
+    }
+    /// Writes the document to its file without ever leaving it half written, copying the
+    /// previous version to `backup` first. The file is saved even if its undo file can't
+    /// be updated, so that error is returned alongside for a warning
     /// # Errors
-    /// Will return `Err` if fs cannot write to the document (Missing
-    /// permissions / document not found)
-    pub fn save(&mut self) -> Result<(), Error> {
+    /// Will return `Err` with the reason if the file (or its backup) cannot be written
+    pub fn save(&mut self, backup: &Backup) -> Result<Option<Error>, SaveError> {
         #[allow(clippy::pattern_type_mismatch)]
         if let Some(file_name) = &self.file_name {
-            let mut file = fs::File::create(file_name)?;
-            for row in &self.rows {
-                file.write_all(row.as_bytes())?;
-                file.write_all(b"\n")?;
-            }
+            let contents = self.contents()?;
+            save::write_atomic(Path::new(file_name), &contents, backup)?;
+            self.disk = Stamp::new(Path::new(file_name), &contents).ok();
+            self.writable = save::is_writable(Path::new(file_name));
             self.dirty = false;
+            self.history.mark_saved();
+            if let Some(hex) = &mut self.hex {
+                hex.mark_saved();
+            }
+            if self.swap.written {
+                let _ = fs::remove_file(swap::path_for(file_name));
+                self.swap.written = false;
+            }
+            let undo_file = undo_file_path(file_name);
+            if Path::new(&undo_file).exists() {
+                let hash = self.content_hash();
+                return Ok(self.history.write_to(&undo_file, hash).err());
+            }
         }
+        Ok(None)
+    }
+    /// The bytes that saving writes
+    /// # Errors
+    /// Will return `Err` if the text has characters its encoding cannot represent
+    pub fn contents(&self) -> Result<Vec<u8>, SaveError> {
+        match &self.hex {
+            Some(hex) => Ok(hex.bytes().to_vec()),
+            None => self
+                .format
+                .join(self.rows.iter())
+                .map_err(|line| SaveError::Unencodable(self.format.encoding.name(), line.saturating_add(1))),
+        }
+    }
+    /// Replaces the whole text with `text` (e.g. recovered from a swap file) as a change
+    /// that can be undone. Returns `false` if they are the same
+    pub fn replace_text(&mut self, text: &str) -> bool {
+        if self.text() == text {
+            return false;
+        }
+        // Not an edit the user typed, so made even if the document is read-only
+        let old = self.text();
+        self.commit_history();
+        self.apply_delete(&Position::default(), &old);
+        self.history.record(Change::Delete { at: Position::default(), text: old });
+        self.apply_insert(&Position::default(), text);
+        self.history.record(Change::Insert { at: Position::default(), text: text.to_owned() });
+        self.commit_history();
+        self.dirty = true;
+        self.swap.stale = true;
+        true
+    }
+    /// Whether another program has changed or deleted the file since it was last read or
+    /// written. A change is reported until it is dealt with by `reload` or `keep_text`
+    pub fn check_disk(&mut self) -> DiskChange {
+        #[allow(clippy::pattern_type_mismatch)]
+        let (file_name, stamp) = match (&self.file_name, &mut self.disk) {
+            (Some(file_name), Some(stamp)) => (file_name, stamp),
+            _ => return DiskChange::Unchanged,
+        };
+        match stamp.check(Path::new(file_name)) {
+            Ok(Some(_)) => DiskChange::Changed,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                self.disk = None;
+                DiskChange::Deleted
+            }
+            // If it can't be read, try again later
+            Ok(None) | Err(_) => DiskChange::Unchanged,
+        }
+    }
+    /// The file's text as it is on disk now, with rows separated by `\n`
+    /// # Errors
+    /// Will return `Err` if the file cannot be read
+    pub fn disk_text(&self) -> Result<String, Error> {
+        let file_name = self.file_name.as_deref().ok_or_else(|| Error::new(ErrorKind::NotFound, "no file name"))?;
+        Ok(decode_text(&fs::read(file_name)?).1)
+    }
+    /// Reads the file again, throwing away any unsaved changes. The new text replaces the
+    /// old as a change, so the old text can still be had back with undo
+    /// # Errors
+    /// Will return `Err` if the file cannot be read
+    pub fn reload(&mut self) -> Result<(), Error> {
+        let file_name = self.file_name.clone().ok_or_else(|| Error::new(ErrorKind::NotFound, "no file name"))?;
+        let bytes = fs::read(&file_name)?;
+        let (format, text, binary) = decode_text(&bytes);
+        if self.hex.is_some() {
+            self.hex = Some(Hex::new(bytes.clone()));
+        }
+        self.replace_text(&text);
+        self.format = format;
+        self.binary_guard |= binary && self.hex.is_none();
+        self.binary = binary;
+        self.dirty = false;
+        self.history.mark_saved();
+        self.swap.stale = true;
+        self.disk = Stamp::new(Path::new(&file_name), &bytes).ok();
         Ok(())
     }
-    #[must_use] pub fn find(&self, query: &str) -> Vec<Position> {
-        let mut positions: Vec<Position> = vec!();
-        for i in 0..self.len() {
-            if let Some(row) = self.row(i) {
-                if let Some(position) = row.find(query) {
-                    positions.push(Position{x: position, y: i});
-                }
+    /// Keeps the text as it is despite the file having changed, so it is no longer
+    /// reported and saving overwrites the file. The text now differs from the file, so
+    /// counts as unsaved
+    pub fn keep_text(&mut self) {
+        #[allow(clippy::pattern_type_mismatch)]
+        if let Some(file_name) = &self.file_name {
+            let path = Path::new(file_name);
+            self.disk = fs::read(path).and_then(|bytes| Stamp::new(path, &bytes)).ok();
+        }
+        self.dirty = true;
+        self.swap.stale = true;
+    }
+    #[must_use] pub fn swap_path(&self) -> Option<PathBuf> {
+        self.file_name.as_deref().map(swap::path_for)
+    }
+    /// Writes the unsaved text to the swap file if it has changed since it was last
+    /// written, or removes the swap file once there are no unsaved changes left. Stops
+    /// trying after the first failure
+    /// # Errors
+    /// Will return `Err` if the swap file cannot be written or another running process
+    /// is using it
+    pub fn write_swap(&mut self) -> Result<(), Error> {
+        if !self.swap.stale || self.swap.failed || self.is_read_only() {
+            return Ok(());
+        }
+        self.swap.stale = false;
+        if !self.dirty {
+            self.remove_swap();
+            return Ok(());
+        }
+        #[allow(clippy::pattern_type_mismatch)]
+        let (file_name, path) = match (&self.file_name, self.swap_path()) {
+            (Some(file_name), Some(path)) => (file_name, path),
+            _ => return Ok(()),
+        };
+        match swap::write(&path, file_name, &self.text()) {
+            Ok(()) => {
+                self.swap.written = true;
+                Ok(())
+            }
+            Err(error) => {
+                self.swap.failed = true;
+                Err(error)
+            }
+        }
+    }
+    /// Takes over a swap file left by a process that is no longer running, so it is
+    /// replaced and removed as if this one had written it
+    pub fn claim_swap(&mut self) {
+        self.swap.written = true;
+    }
+    /// Removes the swap file if this process wrote it
+    pub fn remove_swap(&mut self) {
+        if self.swap.written {
+            if let Some(path) = self.swap_path() {
+                let _ = fs::remove_file(path);
+            }
+            self.swap.written = false;
+        }
+    }
+    /// Every match of `query`, top to bottom
+    #[must_use] pub fn find(&self, query: &Query) -> Vec<Match> {
+        let mut matches: Vec<Match> = vec!();
+        for y in 0..self.len() {
+            if let Some(row) = self.row(y) {
+                matches.extend(row.find(query).into_iter().map(|range| Match { y, start: range.start, end: range.end }));
             }
         }
-        positions
+        matches
     }
     #[must_use] pub fn is_dirty(&self) -> bool {
-        self.dirty
+        self.dirty || self.hex.as_ref().is_some_and(Hex::is_modified)
+    }
+    /// Highlights the grapheme at `at` until the next `reset_highlighting`
+    pub fn highlight(&mut self, at: &Position, highlight_type: highlighting::Type) {
+        if let Some(row) = self.rows.get_mut(at.y) {
+            row.add_highlighting(highlight_type, at.x);
+            self.highlighted_rows.push(at.y);
+        }
     }
     pub fn reset_highlighting(&mut self) {
-        for i in 0..self.rows.len() {
-            if let Some(row) = self.row_mut(i) {
+        for i in std::mem::take(&mut self.highlighted_rows) {
+            if let Some(row) = self.rows.get_mut(i) {
                 row.reset_highlighting();
             }
         }
     }
+    #[must_use] pub fn language(&self) -> Option<&Language> {
+        self.language.as_ref()
+    }
+    pub fn set_language(&mut self, language: Option<Language>) {
+        self.language = language;
+        self.invalidate_syntax(0);
+    }
+    /// Brings the syntax highlighting of every row before `until` up to date,
+    /// carrying multi-line state (e.g. open block comments) from row to row
+    pub fn highlight_syntax(&mut self, until: usize) {
+        let until = until.min(self.rows.len());
+        let mut state = match self.syntax_valid_until.checked_sub(1) {
+            Some(previous) => self.rows.get(previous).and_then(Row::syntax_end),
+            None => None,
+        };
+        for y in self.syntax_valid_until..until {
+            if let Some(row) = self.rows.get_mut(y) {
+                state = row.highlight_syntax(self.language.as_ref(), state);
+            }
+        }
+        self.syntax_valid_until = self.syntax_valid_until.max(until);
+    }
     pub fn set_file_name(&mut self, file_name: String) {
+        self.writable = save::is_writable(Path::new(&file_name));
         self.file_name = Option::from(file_name);
     }
-}
\ No newline at end of file
+}
+
+/// Decodes a file's bytes into its format, its text with rows separated by `\n`, and
+/// whether it looks binary
+fn decode_text(bytes: &[u8]) -> (FileFormat, String, bool) {
+    let decoded = encoding::decode(bytes);
+    let (format, rows) = FileFormat::detect(&decoded.text, decoded.encoding);
+    let text: Vec<&str> = rows.iter().map(Row::as_str).collect();
+    (format, text.join("\n"), decoded.binary)
+}
+
+/// The sidecar undo file for `file_name`, e.g. `src/.main.rs.un~`
+fn undo_file_path(file_name: &str) -> String {
+    let path = Path::new(file_name);
+    let name = path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    path.with_file_name(format!(".{}.un~", name)).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Encoding;
+    use crate::fileformat::LineEnding;
+
+    #[test]
+    fn reads_bytes_in_their_format() {
+        let document = Document::from_bytes(b"\xef\xbb\xbfone\r\ntwo\r\n");
+        assert_eq!(document.text(), "one\ntwo");
+        assert!(document.file_name.is_none() && !document.is_dirty());
+        let format = document.format();
+        assert_eq!((format.encoding, format.line_ending, format.bom, format.final_newline), (Encoding::Utf8, LineEnding::Dos, true, true));
+        assert_eq!(document.contents().unwrap(), b"\xef\xbb\xbfone\r\ntwo\r\n");
+        assert!(!document.is_binary() && !document.is_read_only());
+
+        let document = Document::from_bytes(b"caf\xe9");
+        assert_eq!((document.text().as_str(), document.format().encoding), ("caf\u{e9}", Encoding::Latin1));
+        assert!(!document.format().final_newline);
+        assert_eq!(document.contents().unwrap(), b"caf\xe9");
+
+        let document = Document::from_bytes(b"ELF\x00\x01");
+        assert!(document.is_binary() && document.is_read_only());
+    }
+
+    #[test]
+    fn refuses_edits_while_read_only() {
+        let mut document = Document::scratch("one two");
+        document.insert_text(&Position { x: 3, y: 0 }, " and");
+        document.commit_history();
+        assert_eq!(document.take_notice(), None);
+        document.set_read_only(true);
+        document.insert_text(&Position::default(), "zero ");
+        assert_eq!(document.take_notice(), Some(EditNotice::ReadOnly));
+        document.delete_range(&Position::default(), &Position { x: 4, y: 0 });
+        assert_eq!(document.take_notice(), Some(EditNotice::ReadOnly));
+        assert!(document.undo().is_none());
+        assert_eq!(document.take_notice(), Some(EditNotice::ReadOnly));
+        assert_eq!(document.text(), "one and two");
+        document.set_read_only(false);
+        assert!(document.undo().is_some());
+        assert_eq!(document.text(), "one two");
+    }
+
+    #[test]
+    fn warns_once_about_changing_an_unwritable_file() {
+        let mut document = Document::scratch("one");
+        document.writable = false;
+        document.insert_text(&Position::default(), "a");
+        assert_eq!(document.take_notice(), Some(EditNotice::Unwritable));
+        document.insert_text(&Position::default(), "b");
+        document.delete_range(&Position::default(), &Position { x: 1, y: 0 });
+        assert_eq!(document.take_notice(), None);
+        assert_eq!(document.text(), "aone");
+    }
+
+    #[test]
+    fn hex_mode_lifts_only_the_binary_guard() {
+        let mut document = Document::from_bytes(b"\x00\x01\x02");
+        assert!(document.is_binary() && document.is_read_only());
+        document.toggle_hex().unwrap();
+        assert!(!document.is_read_only());
+        document.toggle_hex().unwrap();
+        assert!(document.is_read_only());
+
+        // The user's own choice outlasts hex mode
+        document.set_read_only(true);
+        document.toggle_hex().unwrap();
+        assert!(document.is_read_only());
+        document.toggle_hex().unwrap();
+        assert!(document.is_read_only());
+        document.set_read_only(false);
+        assert!(!document.is_read_only());
+    }
+}
@@ -1,26 +1,50 @@
 use crate::{Commands, Document, row};
-use crate::Row;
+use crate::args::{Args, Jump};
+use crate::buffer::{self, Buffer};
+use crate::terminal::{Input, Size};
+use crate::window::{Direction, Layout, Rect, Tab, Window};
+use crate::commands::LineRange;
+use crate::config::{Config, LineNumbers};
+use crate::diff;
+use crate::document::{DiskChange, EditNotice};
+use crate::fileformat::LineEnding;
+use crate::hex;
+use crate::registers::Registers;
+use crate::search::Query;
+use crate::motion::Motion;
+use crate::normal::{self, Action, Operator, Parser, Step};
+use crate::selection::{Case, Selection, SelectionKind, Yank};
+use crate::swap;
+use crate::syntax::Language;
+use crate::{highlighting, Row};
 use crate::Terminal;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
 use std::time::Duration;
 use std::time::Instant;
-use termion::color;
 use termion::color::Rgb;
 use termion::event::Key;
 
-const STATUS_FG_COLOR: color::Rgb = color::Rgb(0, 0, 0);
-const STATUS_BG_COLOR: color::Rgb = color::Rgb(239, 239, 239);
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const QUIT_TIMES: u8 = 3;
+/// How often unsaved changes are written to swap files while typing
+const SWAP_INTERVAL: Duration = Duration::from_secs(2);
+/// How often the current file is checked for changes made by other programs
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(PartialEq, Eq)]
 pub enum InteractionMode {
     Command,
     Search,
     Insert,
+    Visual(SelectionKind),
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
 #[non_exhaustive]
 pub struct Position {
     pub x: usize,
@@ -28,7 +52,7 @@ pub struct Position {
 }
 
 pub struct StatusMessage {
-    text: String,
+    pub(crate) text: String,
     time: Instant,
     color: Option<Rgb>,
 }
@@ -53,11 +77,46 @@ pub struct Editor {
     pub interaction_mode: InteractionMode,
     command_handler: Commands,
     just_entered: bool,
+    languages: Vec<Language>,
+    pub config: Config,
+    /// Keys queued by a keymap, read before any new terminal input
+    pending_keys: VecDeque<Key>,
+    /// Where the selection started when in Visual mode
+    visual_anchor: Position,
+    /// The start and end of the last Visual selection (the `'<` and `'>` marks)
+    pub visual_marks: Option<(Position, Position)>,
+    pub registers: Registers,
+    /// The register picked with `"` for the next yank, delete or put
+    pub register: Option<char>,
+    /// Every open buffer, in the order `:bn` cycles through them
+    buffers: Vec<Buffer>,
+    /// The index in `buffers` of the buffer being edited
+    current_buffer: usize,
+    next_buffer_id: usize,
+    /// Every window on screen, laid out by `layout`
+    windows: Vec<Window>,
+    /// The index in `windows` of the focused window
+    current_window: usize,
+    next_window_id: usize,
+    layout: Layout,
+    /// Every tab page. The current one's windows and layout live in the fields above
+    tabs: Vec<Tab>,
+    current_tab: usize,
+    /// When swap files were last brought up to date
+    swap_written: Instant,
+    /// When the current file was last checked for changes on disk
+    disk_checked: Instant,
+    /// The buffer focused then, so another one is checked as soon as it is focused
+    disk_checked_buffer: usize,
+    /// The original stdout in `--stdout` mode, which gets the document written by `:wq`
+    stdout: Option<File>,
+    /// What `:wq` left to write to `stdout` on exit
+    output: Option<Vec<u8>>,
 }
 
 impl Editor {
     pub fn run(&mut self) {
-        Terminal::cursor_block();
+        self.start();
         loop {
             if let Err(error) = self.refresh_screen(true) {
                 die(&error);
@@ -65,33 +124,124 @@ impl Editor {
             if self.should_quit {
                 break;
             }
-            if let Err(error) = self.process_keypress() {
+            if let Err(error) = self.step() {
+                // The terminal has gone, so keep what was typed since the last swap
+                self.write_swap_files();
                 die(&error);
             }
         }
+        self.finish();
     }
-    pub fn default() -> Self {
-        let args: Vec<String> = env::args().collect();
+    /// Offers to recover each file from a swap file left behind, before editing starts
+    pub(crate) fn start(&mut self) {
+        self.terminal.cursor_block();
+        let current = self.current_buffer;
+        for index in 0..self.buffers.len() {
+            self.switch_buffer(index);
+            if !self.check_swap() {
+                self.should_quit = true;
+                break;
+            }
+        }
+        self.switch_buffer(current);
+        self.disk_checked_buffer = self.current_buffer_id();
+    }
+    /// Handles the next key press, if one comes before swap files are due, then anything
+    /// that has come due: swap files to write and files to check for changes on disk
+    /// # Errors
+    /// Will return `Err` if no key could be read
+    pub(crate) fn step(&mut self) -> Result<(), std::io::Error> {
+        self.process_keypress()?;
+        if let Some(notice) = self.document.take_notice() {
+            let message = match notice {
+                EditNotice::ReadOnly => "ERR: Document is read-only (:set noreadonly to allow changes)".to_owned(),
+                EditNotice::Unwritable => format!("ERR: Changing {}, which can't be written", buffer::name(&self.document)),
+            };
+            self.status_message = StatusMessage::from(message, Option::from(self.config.theme.error));
+        }
+        if self.swap_written.elapsed() >= SWAP_INTERVAL {
+            self.write_swap_files();
+        }
+        // Also check as soon as another buffer is focused
+        if self.disk_checked.elapsed() >= DISK_CHECK_INTERVAL || self.current_buffer_id() != self.disk_checked_buffer {
+            self.disk_checked_buffer = self.current_buffer_id();
+            self.check_disk();
+        }
+        Ok(())
+    }
+    /// Removes swap files and writes out what `:wq` left for stdout, once the editor quits
+    pub(crate) fn finish(&mut self) {
+        self.document.remove_swap();
+        for buffer in &mut self.buffers {
+            buffer.document.remove_swap();
+        }
+        #[allow(clippy::pattern_type_mismatch)]
+        if let (Some(stdout), Some(output)) = (&mut self.stdout, &self.output) {
+            // stdout may be the terminal too, which should be back to normal by now
+            let written = self.terminal.restore().and_then(|()| stdout.write_all(output)).and_then(|()| stdout.flush());
+            if let Err(error) = written {
+                die(&error);
+            }
+        }
+    }
+    /// Opens the files named on the command line, with the cursor and windows it asks for
+    pub fn new(args: &Args) -> Self {
+        let (config, config_errors) = Config::default_path().map_or_else(|| (Config::default(), vec![]), |path| Config::load(&path));
+        let (languages, language_errors) = Language::load_all();
+        let error = language_errors
+            .first()
+            .map(|error| format!("ERR: Invalid language definition {}", error))
+            .or_else(|| config_error_message(&config_errors));
+        let mut editor = Self::with_terminal(args, config, languages, None);
+        // Problems opening the files are shown first
+        if let (Some(error), true) = (error, editor.status_message.text.is_empty()) {
+            editor.status_message = StatusMessage::from(error, Option::from(editor.config.theme.error));
+        }
+        editor
+    }
+    /// Like `new`, but with the given config and languages rather than the user's, and
+    /// drawn on `terminal` if given. Otherwise the real terminal is set up once stdin and
+    /// stdout have been taken over where the command line asks for that
+    pub fn with_terminal(args: &Args, config: Config, languages: Vec<Language>, terminal: Option<Terminal>) -> Self {
         let mut initial_status = StatusMessage::from("".to_owned(), None);
-        let document = if let Some(file_name) = args.get(1) {
-            let doc = Document::open(file_name);
-            if let Ok(doc) = doc {
-                doc
+        #[allow(clippy::expect_used)]
+        let stdout = args.stdout.then(|| Terminal::take_stdout().expect("Failed to open the terminal"));
+        let mut buffers: Vec<Buffer> = vec![];
+        let mut read_stdin = false;
+        for (id, file) in args.files.iter().enumerate() {
+            let id = id.saturating_add(1);
+            let (mut buffer, error) = if file.name == "-" && !read_stdin {
+                read_stdin = true;
+                match Terminal::take_stdin() {
+                    Ok(bytes) => (Buffer::new(id, Document::from_bytes(&bytes)), None),
+                    Err(error) => (Buffer::new(id, Document::default()), Some(format!("ERR: Could not read stdin: {}", error))),
+                }
             } else {
-                initial_status.text = format!("ERR: Could not open file (opening as new): {}", file_name);
-                initial_status.color = Option::from(crate::ERROR_COLOR);
-                let mut doc = Document::default();
-                doc.set_file_name(file_name.to_owned());
-                doc
+                Buffer::open(id, &file.name, &languages)
+            };
+            if let Some(error) = error {
+                initial_status = StatusMessage::from(error, Option::from(config.theme.error));
             }
-        } else {
-            Document::default()
-        };
+            if args.hex {
+                if let Err(error) = buffer.document.toggle_hex() {
+                    initial_status = StatusMessage::from(format!("ERR: {}", error), Option::from(config.theme.error));
+                }
+            }
+            if args.read_only {
+                buffer.document.set_read_only(true);
+            }
+            buffers.push(buffer);
+        }
+        if buffers.is_empty() {
+            buffers.push(Buffer::new(1, Document::default()));
+        }
+        let next_buffer_id = buffers.len().saturating_add(1);
+        let document = buffers.first_mut().map_or_else(Document::default, |first| std::mem::replace(&mut first.document, Document::default()));
 
         #[allow(clippy::expect_used)]
-        Self {
+        let mut editor = Self {
             should_quit: false,
-            terminal: Terminal::default().expect("Failed to initialize terminal"),
+            terminal: terminal.unwrap_or_else(|| Terminal::default().expect("Failed to initialize terminal")),
             document,
             cursor_position: Position::default(),
             offset: Position::default(),
@@ -100,114 +250,930 @@ impl Editor {
             interaction_mode: InteractionMode::Command,
             command_handler: Commands::default(),
             just_entered: true,
+            languages,
+            config,
+            pending_keys: VecDeque::new(),
+            visual_anchor: Position::default(),
+            visual_marks: None,
+            registers: Registers::default(),
+            register: None,
+            buffers,
+            current_buffer: 0,
+            next_buffer_id,
+            windows: vec![Window {
+                id: 1,
+                buffer: 1,
+                cursor_position: Position::default(),
+                offset: Position::default(),
+            }],
+            current_window: 0,
+            next_window_id: 2,
+            layout: Layout::Window(1),
+            tabs: vec![Tab::default()],
+            current_tab: 0,
+            swap_written: Instant::now(),
+            disk_checked: Instant::now(),
+            disk_checked_buffer: 1,
+            stdout,
+            output: None,
+        };
+        editor.open_layout(args);
+        editor
+    }
+    /// Moves the cursor in each file to where the command line said, then lays the files
+    /// out in windows if asked to
+    fn open_layout(&mut self, args: &Args) {
+        for (index, file) in args.files.iter().enumerate() {
+            if let Some(jump) = &file.jump {
+                self.switch_buffer(index);
+                self.jump(jump);
+            }
+        }
+        self.switch_buffer(0);
+        let direction = match args.split {
+            Some(direction) if self.buffers.len() > 1 => direction,
+            _ => return,
+        };
+        // Each split goes before the focused window, so work back from the last file
+        let last = self.buffers.len().saturating_sub(1);
+        self.switch_buffer(last);
+        for index in (0..last).rev() {
+            self.split_window(direction, None);
+            self.switch_buffer(index);
+        }
+        if args.diff {
+            let texts: Vec<(String, String)> = (0..2)
+                .filter_map(|index| self.buffer_document(index))
+                .map(|document| (buffer::name(document).to_owned(), document.text()))
+                .collect();
+            if let [(old_name, old), (new_name, new)] = texts.as_slice() {
+                self.split_diff(old_name, old, new_name, new);
+            }
         }
     }
+    /// Puts the cursor where `jump` says in the current document
+    fn jump(&mut self, jump: &Jump) {
+        match jump {
+            Jump::Line(line, column) => {
+                self.cursor_position = Position {
+                    x: column.unwrap_or(1).saturating_sub(1),
+                    y: line.saturating_sub(1),
+                };
+            }
+            Jump::LastLine => self.cursor_position = Position { x: 0, y: self.document.len().saturating_sub(1) },
+            Jump::Search(pattern) => match Query::parse(pattern) {
+                Ok(query) => match self.document.find(&query).first() {
+                    Some(found) => self.cursor_position = Position { x: found.start, y: found.y },
+                    None => self.status_message = StatusMessage::from(format!("ERR: Pattern not found: {}", pattern), Option::from(self.config.theme.error)),
+                },
+                Err(message) => self.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(self.config.theme.error)),
+            },
+        }
+        self.clamp_cursor();
+        self.scroll();
+    }
 
     pub(crate) fn refresh_screen(&mut self, show_cursor: bool) -> Result<(), std::io::Error> {
-        Terminal::cursor_hide();
-        Terminal::cursor_position(&Position::default());
+        self.terminal.cursor_hide();
+        self.terminal.cursor_position(&Position::default());
         if self.should_quit {
-            Terminal::clear_screen();
-            println!("Goodbye.\r");
+            self.terminal.clear_screen();
+            self.terminal.print("Goodbye.\r\n");
         } else {
-            self.draw_rows();
-            self.draw_status_bar();
+            let rects = self.layout.rects(self.window_area());
+            self.highlight_windows(&rects);
+            self.highlight_selection();
+            if self.has_tab_line() {
+                self.draw_tab_line();
+            }
+            for &(id, area) in &rects {
+                self.draw_window(id, area);
+            }
             self.draw_message_bar();
             self.document.reset_highlighting();
 
-            Terminal::cursor_position(&Position {
-                x: self.cursor_position.x.saturating_sub(self.offset.x).saturating_add(self.document.len().to_string().len()).saturating_add(1),
-                y: self.cursor_position.y.saturating_sub(self.offset.y).clamp(0, self.document.len().saturating_sub(1)),
-            });
+            let area = self.current_rect();
+            let cursor = match self.document.hex() {
+                Some(hex) => Position {
+                    x: hex.cursor_column().saturating_add(usize::from(area.x)),
+                    y: hex.cursor_line().saturating_sub(hex.scroll).saturating_add(usize::from(area.y)),
+                },
+                None => Position {
+                    x: self.cursor_column().saturating_sub(self.offset.x).saturating_add(self.gutter_width()).saturating_add(usize::from(area.x)),
+                    y: self
+                        .cursor_position
+                        .y
+                        .saturating_sub(self.offset.y)
+                        .clamp(0, self.document.len().saturating_sub(1))
+                        .saturating_add(usize::from(area.y)),
+                },
+            };
+            self.terminal.cursor_position(&cursor);
         }
         if show_cursor {
-            Terminal::cursor_show();
+            self.terminal.cursor_show();
         }
 
-        Terminal::flush()
+        self.terminal.flush()
     }
-    pub fn save(&mut self) -> bool {
+    /// Saves the current document, refusing if it was opened read-only unless `forced`
+    pub fn save(&mut self, forced: bool) -> bool {
+        if self.document.is_read_only() && !forced {
+            self.status_message = StatusMessage::from("ERR: File is read-only (add ! to override)".to_owned(), Option::from(self.config.theme.error));
+            return false;
+        }
+        if !self.document.is_writable() && !forced {
+            let message = format!("ERR: No permission to write {} (add ! to try anyway)", buffer::name(&self.document));
+            self.status_message = StatusMessage::from(message, Option::from(self.config.theme.error));
+            return false;
+        }
+        if !forced && self.document.check_disk() == DiskChange::Changed {
+            self.status_message = StatusMessage::from("ERR: File changed on disk since it was read (add ! to overwrite)".to_owned(), Option::from(self.config.theme.error));
+            return false;
+        }
         if self.document.file_name.is_none() {
             let new_name = self.prompt("Save as: ", |_, _|{}).unwrap_or(None);
             if new_name.is_none() {
-                self.status_message = StatusMessage::from("Save aborted.".to_owned(), Option::from(crate::ERROR_COLOR));
+                self.status_message = StatusMessage::from("Save aborted.".to_owned(), Option::from(self.config.theme.error));
                 return false;
             }
             self.document.file_name = new_name;
+            self.detect_language();
         }
 
-        if self.document.save().is_ok() {
-            self.status_message = StatusMessage::from("File saved successfully".to_owned(), None);
-            true
+        match self.document.save(&self.config.backup) {
+            Ok(None) => {
+                self.status_message = StatusMessage::from("File saved successfully".to_owned(), None);
+                true
+            }
+            Ok(Some(error)) => {
+                let message = format!("File saved, but could not write undo history: {}", error);
+                self.status_message = StatusMessage::from(message, Option::from(self.config.theme.error));
+                true
+            }
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: Could not save: {}", error), Option::from(self.config.theme.error));
+                false
+            }
+        }
+    }
+    /// Saves the current document and closes its window, or in `--stdout` mode ends the
+    /// session with the document to be written to stdout instead
+    pub fn write_and_close(&mut self, forced: bool) {
+        if self.stdout.is_none() {
+            if self.save(forced) {
+                self.close_window(forced);
+            }
+            return;
+        }
+        // Only the current document is written, so the others would be lost
+        if let (Some(buffer), false) = (self.unsaved_buffer(), forced) {
+            let message = format!("There are unsaved changes in buffer {} \"{}\". Run :wq! to write anyway", buffer.id, buffer::name(&buffer.document));
+            self.status_message = StatusMessage::from(message, Option::from(self.config.theme.error));
+            return;
+        }
+        match self.document.contents() {
+            Ok(contents) => {
+                self.output = Some(contents);
+                self.should_quit = true;
+            }
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: Could not write to stdout: {}", error), Option::from(self.config.theme.error));
+            }
+        }
+    }
+    /// Re-reads the config file at `path` (or the default one), reporting any invalid entries
+    pub fn reload_config(&mut self, path: Option<&Path>) {
+        let path = match path.map(Path::to_path_buf).or_else(Config::default_path) {
+            Some(path) => path,
+            None => {
+                self.status_message = StatusMessage::from("ERR: No config directory found".to_owned(), Option::from(self.config.theme.error));
+                return;
+            }
+        };
+        let (config, errors) = Config::load(&path);
+        self.config = config;
+        self.status_message = match config_error_message(&errors) {
+            Some(message) => StatusMessage::from(message, Option::from(self.config.theme.error)),
+            None => StatusMessage::from(format!("Loaded {}", path.display()), None),
+        };
+        self.scroll();
+    }
+    pub fn detect_language(&mut self) {
+        let language = self
+            .document
+            .file_name
+            .as_ref()
+            .and_then(|file_name| Language::detect(&self.languages, file_name))
+            .cloned();
+        self.document.set_language(language);
+    }
+    /// Switches to the buffer for `file_name`, opening it in a new one if it is not open yet
+    pub fn edit(&mut self, file_name: &str) {
+        let open = (0..self.buffers.len())
+            .find(|&index| self.buffer_document(index).and_then(|document| document.file_name.as_deref()).is_some_and(|name| buffer::is_same_file(name, file_name)));
+        if let Some(index) = open {
+            self.switch_buffer(index);
+            return;
+        }
+        let (buffer, error) = Buffer::open(self.next_buffer_id, file_name, &self.languages);
+        self.next_buffer_id = self.next_buffer_id.saturating_add(1);
+        self.buffers.push(buffer);
+        let previous = self.current_buffer;
+        self.switch_buffer(self.buffers.len().saturating_sub(1));
+        self.status_message = match error {
+            Some(error) => StatusMessage::from(error, Option::from(self.config.theme.error)),
+            None => StatusMessage::from(format!("\"{}\" {}L", file_name, self.document.len()), None),
+        };
+        if !self.check_swap() {
+            self.delete_buffer(self.current_buffer, true);
+            self.switch_buffer(previous);
+            self.status_message = StatusMessage::from(format!("Aborted opening {}", file_name), None);
+        }
+    }
+    /// Offers to recover the current document from a swap file left behind by a crash,
+    /// or found because another instance is editing the same file. Returns `false` if
+    /// the user chose to abort opening it
+    fn check_swap(&mut self) -> bool {
+        let path = match self.document.swap_path() {
+            Some(path) => path,
+            None => return true,
+        };
+        let swap = match swap::read(&path) {
+            Ok(swap) => swap,
+            Err(_) => return true,
+        };
+        let running = swap.pid != std::process::id() && swap::is_running(swap.pid);
+        let owner = if running {
+            format!("pid {} running", swap.pid)
+        } else {
+            format!("pid {} gone", swap.pid)
+        };
+        let name = path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        let question = format!("{} found ({}) r:recover o:read-only d:delete a:abort", name, owner);
+        match self.ask(&question, "roda") {
+            Some('r') => {
+                self.status_message = if self.document.replace_text(&swap.text) {
+                    StatusMessage::from(format!("Recovered from {}, :w to keep the changes", name), None)
+                } else {
+                    StatusMessage::from(format!("{} matches the file", name), None)
+                };
+                if !running {
+                    self.document.claim_swap();
+                }
+                self.clamp_cursor();
+            }
+            Some('o') => {
+                self.document.set_read_only(true);
+                self.status_message = StatusMessage::from(format!("Opened read-only, {} left alone", name), None);
+            }
+            Some('d') => {
+                self.status_message = match fs::remove_file(&path) {
+                    Ok(()) => StatusMessage::from(format!("Deleted {}", name), None),
+                    Err(error) => StatusMessage::from(format!("ERR: Could not delete {}: {}", name, error), Option::from(self.config.theme.error)),
+                };
+            }
+            _ => return false,
+        }
+        true
+    }
+    /// Asks what to do if another program has changed the current document's file since
+    /// it was read or written: reload it, keep the text being edited, or split off a
+    /// diff of the two to decide later
+    fn check_disk(&mut self) {
+        self.disk_checked = Instant::now();
+        let name = self.document.file_name.clone().unwrap_or_default();
+        match self.document.check_disk() {
+            DiskChange::Unchanged => (),
+            DiskChange::Deleted => {
+                self.status_message = StatusMessage::from(format!("ERR: {} was deleted, :w to write it again", name), Option::from(self.config.theme.error));
+            }
+            DiskChange::Changed => match self.ask(&format!("{} changed on disk r:reload k:keep d:diff", name), "rkd") {
+                Some('r') => self.reload(true),
+                Some('k') => {
+                    self.document.keep_text();
+                    self.status_message = StatusMessage::from(format!("Kept the text, :w to overwrite {}", name), None);
+                }
+                Some('d') => self.show_disk_diff(),
+                // Asked again at the next check
+                _ => self.status_message = StatusMessage::from("".to_owned(), None),
+            },
+        }
+    }
+    /// Reads the current document's file again, refusing if that would lose unsaved
+    /// changes unless `forced`
+    pub fn reload(&mut self, forced: bool) {
+        let name = match self.document.file_name.clone() {
+            Some(name) => name,
+            None => {
+                self.status_message = StatusMessage::from("ERR: No file name".to_owned(), Option::from(self.config.theme.error));
+                return;
+            }
+        };
+        if self.document.is_dirty() && !forced {
+            self.status_message = StatusMessage::from("ERR: No write since last change (add ! to override)".to_owned(), Option::from(self.config.theme.error));
+            return;
+        }
+        self.status_message = match self.document.reload() {
+            Ok(()) => StatusMessage::from(format!("\"{}\" {}L reloaded, u to undo", name, self.document.len()), None),
+            Err(error) => StatusMessage::from(format!("ERR: Could not reload: {}", error), Option::from(self.config.theme.error)),
+        };
+        self.clamp_cursor();
+        self.scroll();
+    }
+    /// Splits off a window showing how the current document's file on disk differs from
+    /// its text. The change is asked about again once its window is focused
+    fn show_disk_diff(&mut self) {
+        let name = self.document.file_name.clone().unwrap_or_default();
+        let disk = match self.document.disk_text() {
+            Ok(disk) => disk,
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: Could not read {}: {}", name, error), Option::from(self.config.theme.error));
+                return;
+            }
+        };
+        let text = self.document.text();
+        self.split_diff(&format!("{} (on disk)", name), &disk, &format!("{} (buffer)", name), &text);
+        self.status_message = StatusMessage::from(format!("{} differs from the file on disk", name), None);
+    }
+    /// Splits off a window with a new buffer showing how `new` differs from `old`
+    fn split_diff(&mut self, old_name: &str, old: &str, new_name: &str, new: &str) {
+        let old: Vec<&str> = old.split('\n').collect();
+        let new: Vec<&str> = new.split('\n').collect();
+        let mut lines = vec![format!("--- {}", old_name), format!("+++ {}", new_name)];
+        lines.extend(diff::unified(&old, &new));
+        self.split_window(Direction::Horizontal, None);
+        self.buffers.push(Buffer::new(self.next_buffer_id, Document::scratch(&lines.join("\n"))));
+        self.next_buffer_id = self.next_buffer_id.saturating_add(1);
+        self.switch_buffer(self.buffers.len().saturating_sub(1));
+    }
+    /// Writes the unsaved changes of every buffer to its swap file, reporting a failure
+    /// (such as another instance editing the same file) once per buffer
+    fn write_swap_files(&mut self) {
+        let documents = std::iter::once(&mut self.document).chain(self.buffers.iter_mut().map(|buffer| &mut buffer.document));
+        let mut failures = vec![];
+        for document in documents {
+            if let Err(error) = document.write_swap() {
+                failures.push(format!("ERR: Could not write swap file: {}", error));
+            }
+        }
+        if let Some(failure) = failures.pop() {
+            self.status_message = StatusMessage::from(failure, Option::from(self.config.theme.error));
+        }
+        self.swap_written = Instant::now();
+    }
+    /// Makes the buffer at `index` the current one, keeping the cursor and scroll offset
+    /// of the one being left
+    pub fn switch_buffer(&mut self, index: usize) {
+        if index == self.current_buffer || index >= self.buffers.len() {
+            return;
+        }
+        if let Some(buffer) = self.buffers.get_mut(self.current_buffer) {
+            buffer.document = std::mem::replace(&mut self.document, Document::default());
+            buffer.cursor_position = self.cursor_position;
+            buffer.offset = self.offset;
+        }
+        self.load_buffer(index);
+    }
+    /// Takes the document, cursor and offset of the buffer at `index` into the editor
+    fn load_buffer(&mut self, index: usize) {
+        if let Some(buffer) = self.buffers.get_mut(index) {
+            self.document = std::mem::replace(&mut buffer.document, Document::default());
+            self.cursor_position = buffer.cursor_position;
+            self.offset = buffer.offset;
+            self.current_buffer = index;
+            self.visual_marks = None;
+            let id = buffer.id;
+            if let Some(window) = self.windows.get_mut(self.current_window) {
+                window.buffer = id;
+            }
+            self.clamp_cursor();
+        }
+    }
+    /// Moves `steps` buffers forward (or back, if negative) in the list, wrapping around
+    pub fn cycle_buffer(&mut self, steps: isize) {
+        self.switch_buffer(wrapping_step(self.current_buffer, steps, self.buffers.len()));
+    }
+    /// The index in the buffer list of the buffer numbered `id`
+    #[must_use] pub fn buffer_index(&self, id: usize) -> Option<usize> {
+        self.buffers.iter().position(|buffer| buffer.id == id)
+    }
+    #[must_use] pub fn current_buffer_id(&self) -> usize {
+        self.buffers.get(self.current_buffer).map_or(0, |buffer| buffer.id)
+    }
+    fn buffer_document(&self, index: usize) -> Option<&Document> {
+        if index == self.current_buffer {
+            Some(&self.document)
+        } else {
+            self.buffers.get(index).map(|buffer| &buffer.document)
+        }
+    }
+    /// Closes the buffer at `index`, refusing if it has unsaved changes unless `forced`.
+    /// Closing the last buffer leaves an empty one in its place
+    pub fn delete_buffer(&mut self, index: usize, forced: bool) {
+        let document = match self.buffer_document(index) {
+            Some(document) => document,
+            None => return,
+        };
+        if document.is_dirty() && !forced {
+            let message = format!("ERR: No write since last change for buffer {} (add ! to override)", self.buffers.get(index).map_or(0, |buffer| buffer.id));
+            self.status_message = StatusMessage::from(message, Option::from(self.config.theme.error));
+            return;
+        }
+        if index == self.current_buffer {
+            self.document.remove_swap();
+        } else if let Some(buffer) = self.buffers.get_mut(index) {
+            buffer.document.remove_swap();
+        }
+        let removed = self.buffers.get(index).map_or(0, |buffer| buffer.id);
+        if self.buffers.len() == 1 {
+            self.buffers = vec![Buffer::new(self.next_buffer_id, Document::default())];
+            self.next_buffer_id = self.next_buffer_id.saturating_add(1);
+            self.load_buffer(0);
+        } else {
+            self.buffers.remove(index);
+            if index == self.current_buffer {
+                self.load_buffer(index.min(self.buffers.len().saturating_sub(1)));
+            } else if index < self.current_buffer {
+                self.current_buffer = self.current_buffer.saturating_sub(1);
+            }
+        }
+        // Other windows showing the closed buffer, in any tab, show the current one instead
+        let current = self.current_buffer_id();
+        let windows = self.windows.iter_mut().chain(self.tabs.iter_mut().flat_map(|tab| tab.windows.iter_mut()));
+        for window in windows.filter(|window| window.buffer == removed) {
+            window.buffer = current;
+            window.cursor_position = Position::default();
+            window.offset = Position::default();
+        }
+    }
+    /// Shows the open buffers in the message bar, marking the current one with `%`
+    /// and those with unsaved changes with `+`
+    pub fn list_buffers(&mut self) {
+        let list: Vec<String> = (0..self.buffers.len())
+            .filter_map(|index| {
+                let id = self.buffers.get(index)?.id;
+                let document = self.buffer_document(index)?;
+                Some(format!(
+                    "{}{}{} \"{}\"",
+                    id,
+                    if index == self.current_buffer { " %" } else { "" },
+                    if document.is_dirty() { " +" } else { "" },
+                    buffer::name(document),
+                ))
+            })
+            .collect();
+        self.status_message = StatusMessage::from(list.join(" | "), None);
+    }
+    /// The screen area windows are laid out in: everything above the message bar
+    fn window_area(&self) -> Rect {
+        let tab_line = u16::from(self.has_tab_line());
+        Rect {
+            x: 0,
+            y: tab_line,
+            width: self.terminal.size().width,
+            height: self.terminal.size().height.saturating_add(1).saturating_sub(tab_line),
+        }
+    }
+    /// The tab line is only shown once there is more than one tab
+    fn has_tab_line(&self) -> bool {
+        self.tabs.len() > 1
+    }
+    /// The area of the focused window, including its status line
+    fn current_rect(&self) -> Rect {
+        let id = self.windows.get(self.current_window).map_or(0, |window| window.id);
+        self.layout
+            .rects(self.window_area())
+            .into_iter()
+            .find(|&(window, _)| window == id)
+            .map_or_else(|| self.window_area(), |(_, rect)| rect)
+    }
+    /// The text area of the focused window, without its status line
+    pub(crate) fn viewport(&self) -> Size {
+        let area = self.current_rect();
+        Size {
+            width: area.width,
+            height: area.height.saturating_sub(1),
+        }
+    }
+    /// Splits the focused window in two, both showing the current buffer, and focuses
+    /// the new one (above or to the left). With a `file_name`, the new window opens it
+    pub fn split_window(&mut self, direction: Direction, file_name: Option<&str>) {
+        let size = self.viewport();
+        let too_small = match direction {
+            Direction::Horizontal => size.height < 3,
+            Direction::Vertical => size.width < 3,
+        };
+        if too_small {
+            self.status_message = StatusMessage::from("ERR: Not enough room".to_owned(), Option::from(self.config.theme.error));
+            return;
+        }
+        let current = match self.windows.get_mut(self.current_window) {
+            Some(window) => window,
+            None => return,
+        };
+        current.cursor_position = self.cursor_position;
+        current.offset = self.offset;
+        let (current_id, buffer) = (current.id, current.buffer);
+        let id = self.next_window_id;
+        self.next_window_id = self.next_window_id.saturating_add(1);
+        self.windows.push(Window {
+            id,
+            buffer,
+            cursor_position: self.cursor_position,
+            offset: self.offset,
+        });
+        self.current_window = self.windows.len().saturating_sub(1);
+        self.layout.split(current_id, id, direction);
+        self.layout.equalize();
+        if let Some(file_name) = file_name {
+            self.edit(file_name);
+        }
+        self.scroll();
+    }
+    /// Closes the focused window, or quits if it is the last one
+    pub fn close_window(&mut self, forced: bool) {
+        if self.windows.len() <= 1 {
+            if self.tabs.len() > 1 {
+                self.close_tab();
+            } else {
+                self.quit(forced);
+            }
+            return;
+        }
+        let closed = self.windows.remove(self.current_window);
+        self.layout.remove(closed.id);
+        self.load_window(self.current_window.min(self.windows.len().saturating_sub(1)));
+    }
+    /// Opens a tab with a single window, showing `file_name` or a new empty buffer
+    pub fn new_tab(&mut self, file_name: Option<&str>) {
+        self.stash_tab();
+        let id = self.next_window_id;
+        self.next_window_id = self.next_window_id.saturating_add(1);
+        self.windows = vec![Window {
+            id,
+            buffer: self.current_buffer_id(),
+            cursor_position: self.cursor_position,
+            offset: self.offset,
+        }];
+        self.current_window = 0;
+        self.layout = Layout::Window(id);
+        self.tabs.push(Tab::default());
+        self.current_tab = self.tabs.len().saturating_sub(1);
+        if let Some(file_name) = file_name {
+            self.edit(file_name);
         } else {
-            self.status_message = StatusMessage::from("ERR: could not write to file".to_owned(), Option::from(crate::ERROR_COLOR));
-            false
+            self.buffers.push(Buffer::new(self.next_buffer_id, Document::default()));
+            self.next_buffer_id = self.next_buffer_id.saturating_add(1);
+            self.switch_buffer(self.buffers.len().saturating_sub(1));
+        }
+    }
+    /// Moves `steps` tabs forward (or back, if negative), wrapping around
+    pub fn cycle_tab(&mut self, steps: isize) {
+        let index = wrapping_step(self.current_tab, steps, self.tabs.len());
+        if index != self.current_tab {
+            self.stash_tab();
+            self.load_tab(index);
+        }
+    }
+    /// Closes the current tab and its windows, leaving their buffers open
+    pub fn close_tab(&mut self) {
+        if self.tabs.len() <= 1 {
+            self.status_message = StatusMessage::from("ERR: Cannot close last tab page".to_owned(), Option::from(self.config.theme.error));
+            return;
+        }
+        self.tabs.remove(self.current_tab);
+        self.load_tab(self.current_tab.min(self.tabs.len().saturating_sub(1)));
+    }
+    /// Moves the current tab's windows and layout from the editor into its entry in `tabs`
+    fn stash_tab(&mut self) {
+        if let Some(window) = self.windows.get_mut(self.current_window) {
+            window.cursor_position = self.cursor_position;
+            window.offset = self.offset;
+        }
+        if let Some(tab) = self.tabs.get_mut(self.current_tab) {
+            tab.windows = std::mem::take(&mut self.windows);
+            tab.current_window = self.current_window;
+            tab.layout = std::mem::replace(&mut self.layout, Layout::Window(0));
+        }
+    }
+    /// Takes the windows and layout of the tab at `index` into the editor
+    fn load_tab(&mut self, index: usize) {
+        let current_window = match self.tabs.get_mut(index) {
+            Some(tab) => {
+                self.windows = std::mem::take(&mut tab.windows);
+                self.layout = std::mem::replace(&mut tab.layout, Layout::Window(0));
+                tab.current_window
+            }
+            None => return,
+        };
+        self.current_tab = index;
+        self.load_window(current_window);
+    }
+    /// Moves focus to the window at `index`, keeping the cursor of the one being left
+    fn focus_window(&mut self, index: usize) {
+        if index == self.current_window || index >= self.windows.len() {
+            return;
+        }
+        if let Some(window) = self.windows.get_mut(self.current_window) {
+            window.cursor_position = self.cursor_position;
+            window.offset = self.offset;
+        }
+        self.load_window(index);
+    }
+    /// Takes the buffer, cursor and offset of the window at `index` into the editor
+    fn load_window(&mut self, index: usize) {
+        let (buffer, cursor_position, offset) = match self.windows.get(index) {
+            Some(window) => (window.buffer, window.cursor_position, window.offset),
+            None => return,
+        };
+        self.current_window = index;
+        if let Some(buffer) = self.buffer_index(buffer) {
+            self.switch_buffer(buffer);
+        }
+        self.cursor_position = cursor_position;
+        self.offset = offset;
+        self.clamp_cursor();
+        self.scroll();
+    }
+    /// Shows `question` in the message bar until one of the keys in `choices` is
+    /// pressed, returning it, or `None` on `Esc`
+    fn ask(&mut self, question: &str, choices: &str) -> Option<char> {
+        loop {
+            self.status_message = StatusMessage::from(question.to_owned(), None);
+            if self.refresh_screen(true).is_err() {
+                return None;
+            }
+            match self.read_key() {
+                Ok(Key::Char(c)) if choices.contains(c) => return Some(c),
+                Ok(Key::Esc) | Err(_) => return None,
+                Ok(_) => (),
+            }
+        }
+    }
+    /// Reads the key following `Ctrl-w` and runs the window command it names
+    fn window_command(&mut self) -> Result<(), std::io::Error> {
+        let area = self.window_area();
+        let id = self.windows.get(self.current_window).map_or(0, |window| window.id);
+        match self.read_key()? {
+            Key::Char(side @ ('h' | 'j' | 'k' | 'l')) => {
+                let rect = self.current_rect();
+                let at = Position {
+                    x: self.cursor_column().saturating_sub(self.offset.x).saturating_add(self.gutter_width()).saturating_add(usize::from(rect.x)),
+                    y: self.cursor_position.y.saturating_sub(self.offset.y).saturating_add(usize::from(rect.y)),
+                };
+                let neighbour = self.layout.neighbour(id, side, at, area);
+                if let Some(index) = neighbour.and_then(|neighbour| self.windows.iter().position(|window| window.id == neighbour)) {
+                    self.focus_window(index);
+                }
+            }
+            Key::Char('=') => self.layout.equalize(),
+            Key::Char(c @ ('+' | '-' | '>' | '<')) => {
+                let direction = if c == '+' || c == '-' { Direction::Horizontal } else { Direction::Vertical };
+                let delta = if c == '+' || c == '>' { 1 } else { -1 };
+                self.layout.resize(id, direction, delta, area);
+            }
+            Key::Char('s') => self.split_window(Direction::Horizontal, None),
+            Key::Char('v') => self.split_window(Direction::Vertical, None),
+            Key::Char('c' | 'q') => self.close_window(false),
+            _ => (),
+        }
+        self.scroll();
+        Ok(())
+    }
+    /// The document, cursor and scroll offset shown in the window numbered `id`
+    fn window_view(&self, id: usize) -> Option<(&Document, Position, Position)> {
+        let index = self.windows.iter().position(|window| window.id == id)?;
+        if index == self.current_window {
+            return Some((&self.document, self.cursor_position, self.offset));
+        }
+        let window = self.windows.get(index)?;
+        let document = self.buffer_document(self.buffer_index(window.buffer)?)?;
+        Some((document, window.cursor_position, window.offset))
+    }
+    /// Brings syntax highlighting up to date for the rows every window shows
+    fn highlight_windows(&mut self, rects: &[(usize, Rect)]) {
+        for &(id, area) in rects {
+            let (buffer, offset) = match self.windows.iter().position(|window| window.id == id) {
+                Some(index) if index == self.current_window => (self.current_buffer, self.offset),
+                Some(index) => match self.windows.get(index) {
+                    Some(window) => (self.buffer_index(window.buffer).unwrap_or(self.current_buffer), window.offset),
+                    None => continue,
+                },
+                None => continue,
+            };
+            let until = offset.y.saturating_add(usize::from(area.height));
+            if buffer == self.current_buffer {
+                self.document.highlight_syntax(until);
+            } else if let Some(buffer) = self.buffers.get_mut(buffer) {
+                buffer.document.highlight_syntax(until);
+            }
+        }
+    }
+    /// Quits unless a buffer has unsaved changes and the quit is not `forced`
+    pub fn quit(&mut self, forced: bool) {
+        if !forced {
+            if self.document.is_dirty() {
+                self.status_message = StatusMessage::from("There are unsaved changes. Run :q! to force quit".to_owned(), Option::from(self.config.theme.error));
+                return;
+            }
+            if let Some(buffer) = self.unsaved_buffer() {
+                let message = format!("There are unsaved changes in buffer {} \"{}\". Run :q! to force quit", buffer.id, buffer::name(&buffer.document));
+                self.status_message = StatusMessage::from(message, Option::from(self.config.theme.error));
+                return;
+            }
+        }
+        self.should_quit = true;
+    }
+    /// The first buffer other than the current one with unsaved changes
+    fn unsaved_buffer(&self) -> Option<&Buffer> {
+        self.buffers.iter().find(|buffer| buffer.document.is_dirty())
+    }
+    pub fn undo(&mut self, times: usize) {
+        self.step_history(times, Document::undo, "Already at oldest change");
+    }
+    pub fn redo(&mut self, times: usize) {
+        self.step_history(times, Document::redo, "Already at newest change");
+    }
+    pub fn undo_to(&mut self, change: usize) {
+        if let Some(position) = self.document.undo_to(change) {
+            self.cursor_position = position;
+            self.clamp_cursor();
+        }
+        if change >= self.document.history().len() {
+            self.status_message = StatusMessage::from(format!("ERR: Undo number {} not found", change), Option::from(self.config.theme.error));
+        } else {
+            self.status_message = StatusMessage::from(format!("At change {}", self.document.history().current()), None);
+        }
+    }
+    fn step_history(&mut self, times: usize, step: fn(&mut Document) -> Option<Position>, limit_message: &str) {
+        for _ in 0..times {
+            if let Some(position) = step(&mut self.document) {
+                self.cursor_position = position;
+            } else {
+                self.status_message = StatusMessage::from(limit_message.to_owned(), Option::from(self.config.theme.error));
+                break;
+            }
+            self.status_message = StatusMessage::from(format!("At change {}", self.document.history().current()), None);
+        }
+        self.clamp_cursor();
+    }
+    fn clamp_cursor(&mut self) {
+        self.cursor_position.y = self.cursor_position.y.min(self.document.len().saturating_sub(1));
+        let width = self.document.row(self.cursor_position.y).map_or(0, row::Row::len);
+        self.cursor_position.x = self.cursor_position.x.min(width);
+    }
+    /// Reads the next key, taking keys queued by a keymap first
+    pub(crate) fn read_key(&mut self) -> Result<Key, std::io::Error> {
+        match self.pending_keys.pop_front() {
+            Some(key) => Ok(key),
+            None => self.next_key(),
+        }
+    }
+    /// Waits for a key press, redrawing as soon as the terminal is resized meanwhile.
+    /// Swap files are still written while waiting in the middle of a command, e.g. at a
+    /// prompt
+    fn next_key(&mut self) -> Result<Key, std::io::Error> {
+        loop {
+            if let Some(key) = self.wait_for_key()? {
+                return Ok(key);
+            }
+            if self.swap_written.elapsed() >= SWAP_INTERVAL {
+                self.write_swap_files();
+            }
+        }
+    }
+    /// Like `next_key`, but gives up with `None` once swap files could be due
+    fn wait_for_key(&mut self) -> Result<Option<Key>, std::io::Error> {
+        loop {
+            match self.terminal.read_input(SWAP_INTERVAL)? {
+                Input::Key(key) => return Ok(Some(key)),
+                Input::Resize => {
+                    self.resize();
+                    self.refresh_screen(self.terminal.cursor_shown())?;
+                }
+                Input::Idle => return Ok(None),
+            }
+        }
+    }
+    /// Scrolls every window of the tab so its cursor is still visible at the terminal's
+    /// new size. The other windows are scrolled where they are kept, as focusing them
+    /// would lose state such as the Visual selection
+    fn resize(&mut self) {
+        self.scroll();
+        let rects = self.layout.rects(self.window_area());
+        let (current_window, current_buffer) = (self.current_window, self.current_buffer_id());
+        let tab_width = self.config.tab_width;
+        for (index, window) in self.windows.iter_mut().enumerate() {
+            let rect = rects.iter().find(|&&(id, _)| id == window.id).map(|&(_, rect)| rect);
+            let document = if window.buffer == current_buffer {
+                Some(&self.document)
+            } else {
+                self.buffers.iter().find(|buffer| buffer.id == window.buffer).map(|buffer| &buffer.document)
+            };
+            let (rect, document) = match (rect, document) {
+                (Some(rect), Some(document)) if index != current_window && document.hex().is_none() => (rect, document),
+                _ => continue,
+            };
+            let Position { x, y } = window.cursor_position;
+            let x = document.row(y).map_or(0, |row| row.display_column(x, tab_width));
+            let width = usize::from(rect.width).saturating_sub(gutter_width(&self.config, document));
+            let height = usize::from(rect.height.saturating_sub(1));
+            scroll_into_view(&mut window.offset, x, y, width, height);
         }
     }
     fn process_keypress(&mut self) -> Result<(), std::io::Error> {
-        let pressed_key = Terminal::read_key()?;
+        let pressed_key = match self.pending_keys.pop_front() {
+            Some(key) => key,
+            None => {
+                let key = match self.wait_for_key()? {
+                    Some(key) => key,
+                    None => return Ok(()),
+                };
+                // Mapped keys are queued without being remapped themselves
+                match self.config.keymap.get(&key) {
+                    Some(keys) if self.interaction_mode == InteractionMode::Command => {
+                        self.pending_keys.extend(keys.iter().copied());
+                        return Ok(());
+                    }
+                    _ => key,
+                }
+            }
+        };
+        if self.interaction_mode == InteractionMode::Command && self.document.hex().is_some() && self.process_hex_key(pressed_key)? {
+            self.scroll();
+            return Ok(());
+        }
         match pressed_key {
             Key::Esc => {
+                self.register = None;
+                if let InteractionMode::Visual(_) = self.interaction_mode {
+                    self.exit_visual();
+                }
                 self.interaction_mode = InteractionMode::Command;
-                Terminal::cursor_block();
+                self.terminal.cursor_block();
             }
+            Key::Ctrl('r') if self.interaction_mode == InteractionMode::Command => self.redo(1),
+            Key::Ctrl('w') if self.interaction_mode == InteractionMode::Command => self.window_command()?,
+            Key::Ctrl('v') if self.interaction_mode != InteractionMode::Insert => self.enter_visual(SelectionKind::Block),
+            Key::Char(c) if matches!(self.interaction_mode, InteractionMode::Visual(_)) => self.process_visual_key(c)?,
             Key::Char(c) => {
                 if self.interaction_mode == InteractionMode::Command {
-                    match pressed_key {
-                        Key::Char('i') => {
-                            self.just_entered = false;
-                            self.interaction_mode = InteractionMode::Insert;
-                            Terminal::cursor_bar();
-                        }
-                        Key::Char(':') => {
-                            if let Some(command_name) = self.prompt(":", |_, _|{})? {
-                                let is_forced = command_name.contains('!');
-                                if let Some(command) = self.command_handler.get_command(&command_name) {
-                                    let command_params = command.regex.replace(&command_name.clone(), "").to_string();
-                                    (command.function)(self, command_params.split(' ').collect(), is_forced);
+                    // Keys outside the operator + motion grammar come back with their count
+                    if let Some((c, count)) = self.process_normal_key(c)? {
+                        match c {
+                            'i' => {
+                                self.just_entered = false;
+                                self.interaction_mode = InteractionMode::Insert;
+                                self.terminal.cursor_bar();
+                            }
+                            'u' => self.undo(count.unwrap_or(1)),
+                            '"' => self.select_register()?,
+                            'p' => self.put(false, count.unwrap_or(1)),
+                            'P' => self.put(true, count.unwrap_or(1)),
+                            'v' => self.enter_visual(SelectionKind::Char),
+                            'V' => self.enter_visual(SelectionKind::Line),
+                            ':' => {
+                                if !self.command_prompt("")? {
+                                    return Ok(());
+                                }
+                            },
+                            '/' => {
+                                self.just_entered = false;
+                                self.interaction_mode = InteractionMode::Search;
+                                let mut query = String::new();
+                                self.prompt("/", |editor, result| {
+                                    query = result.clone();
+                                    Commands::search_command(editor, result, false, true);
+                                })?;
+                                if self.interaction_mode == InteractionMode::Command {
+                                    self.status_message = StatusMessage::from("ERR: Search Aborted".to_owned(), Option::from(self.config.theme.error));
                                 } else {
-                                    self.status_message = StatusMessage::from("ERR: Invalid command".to_owned(), Option::from(crate::ERROR_COLOR));
+                                    self.interaction_mode = InteractionMode::Command;
+                                    Commands::search_command(self, &query, false, false);
                                 }
-                            } else {
-                                self.status_message = StatusMessage::from("ERR: Command aborted".to_owned(), Option::from(crate::ERROR_COLOR));
-                                return Ok(());
+
                             }
-                        },
-                        Key::Char('/') => {
-                            self.just_entered = false;
-                            self.interaction_mode = InteractionMode::Search;
-                            let mut query = String::new();
-                            self.prompt("/", |editor, result| {
-                                query = result.clone();
-                                Commands::search_command(editor, result, false, true);
-                            })?;
-                            if self.interaction_mode == InteractionMode::Command {
-                                self.status_message = StatusMessage::from("ERR: Search Aborted".to_owned(), Option::from(crate::ERROR_COLOR));
-                            } else {
-                                self.interaction_mode = InteractionMode::Command;
-                                Commands::search_command(self, &query, false, false);
+                            _ => {
+                                self.navigate(c);
                             }
-
                         }
-                        // Navigation
-                        Key::Char('k') => self.move_cursor(Key::Up),
-                        Key::Char('j') => self.move_cursor(Key::Down),
-                        Key::Char('l') => self.move_cursor(Key::Right),
-                        Key::Char('h') => self.move_cursor(Key::Left),
-                        Key::Char('H') => self.cursor_position.y = self.offset.y,
-                        #[allow(clippy::integer_division)]
-                        Key::Char('M') => self.cursor_position.y = self.offset.y.saturating_add(usize::from(self.terminal.size().height / 2)).saturating_sub(1),
-                        Key::Char('L') => self.cursor_position.y = self.offset.y.saturating_add(usize::from(self.terminal.size().height).saturating_sub(1)),
-                        _ => (),
                     }
                 } else {
                     self.document.insert(&self.cursor_position, c);
-                    self.move_cursor(Key::Right);
+                    // A refused character leaves the cursor where it was
+                    if !self.document.is_read_only() {
+                        self.move_cursor(Key::Right);
+                    }
                 }
             },
+            Key::Delete if matches!(self.interaction_mode, InteractionMode::Visual(_)) => self.process_visual_key('d')?,
             Key::Delete => self.document.delete(&self.cursor_position),
             Key::Backspace => {
-                if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
+                if self.document.is_read_only() {
+                    // Refused without moving, as typing is
+                    self.document.delete(&self.cursor_position);
+                } else if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
                     self.move_cursor(Key::Left);
                     self.document.delete(&self.cursor_position);
                 }
@@ -222,6 +1188,9 @@ impl Editor {
             | Key::Home => self.move_cursor(pressed_key),
             _ => (),
         }
+        if self.interaction_mode != InteractionMode::Insert {
+            self.document.commit_history();
+        }
         self.scroll();
         if self.quit_times < QUIT_TIMES {
             self.quit_times = QUIT_TIMES;
@@ -229,24 +1198,404 @@ impl Editor {
         }
         Ok(())
     }
-    pub(crate) fn scroll(&mut self) {
-        let Position { x, y } = self.cursor_position;
-        let width = usize::from(self.terminal.size().width);
-        let height = usize::from(self.terminal.size().height);
-        let mut offset = &mut self.offset;
-        if y < offset.y {
-            offset.y = y;
-        } else if y >= offset.y.saturating_add(height) {
-            offset.y = y.saturating_sub(height).saturating_add(1);
+    /// Handles a key in hex mode: hex digits overwrite the digit under the cursor, and
+    /// there are movement keys and `/`, `n` and `N` to search for bytes. Returns `false`
+    /// for keys that work as in text mode (`:`, `Esc` and `Ctrl` keys such as `Ctrl-w`)
+    fn process_hex_key(&mut self, key: Key) -> Result<bool, std::io::Error> {
+        let line = isize::try_from(hex::BYTES_PER_LINE).unwrap_or(isize::MAX);
+        let page = line.saturating_mul(isize::try_from(self.viewport().height).unwrap_or(isize::MAX));
+        match key {
+            Key::Char(':') | Key::Ctrl(_) | Key::Esc => return Ok(false),
+            Key::Char('/') => {
+                let pattern = self.prompt("/", |_, _| {})?;
+                match pattern.as_deref().map(hex::parse_pattern) {
+                    Some(Ok(bytes)) => {
+                        if let Some(hex) = self.document.hex_mut() {
+                            hex.last_search = bytes;
+                        }
+                        self.find_bytes(true);
+                    }
+                    Some(Err(message)) => self.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(self.config.theme.error)),
+                    None => (),
+                }
+                return Ok(true);
+            }
+            Key::Char(c @ ('n' | 'N')) => {
+                self.find_bytes(c == 'n');
+                return Ok(true);
+            }
+            _ => (),
         }
-        if x < offset.x {
-            offset.x = x;
-        } else if x >= offset.x.saturating_add(width) {
-            offset.x = x.saturating_sub(width).saturating_add(1);
+        if matches!(key, Key::Char(c) if c.is_ascii_hexdigit()) && self.document.is_read_only() {
+            self.status_message = StatusMessage::from("ERR: Document is read-only (:set noreadonly to allow changes)".to_owned(), Option::from(self.config.theme.error));
+            return Ok(true);
+        }
+        let hex = match self.document.hex_mut() {
+            Some(hex) => hex,
+            None => return Ok(false),
+        };
+        let column = isize::try_from(hex.cursor.checked_rem(hex::BYTES_PER_LINE).unwrap_or(0)).unwrap_or(0);
+        match key {
+            Key::Char(c) if c.is_ascii_hexdigit() => {
+                let digit = c.to_digit(16).and_then(|digit| u8::try_from(digit).ok()).unwrap_or(0);
+                if !hex.set_nibble(digit) {
+                    self.status_message = StatusMessage::from("ERR: End of file".to_owned(), Option::from(self.config.theme.error));
+                }
+            }
+            Key::Left | Key::Char('h') => hex.move_left(),
+            Key::Right | Key::Char('l') => hex.move_by(1),
+            Key::Up | Key::Char('k') => hex.move_by(line.saturating_neg()),
+            Key::Down | Key::Char('j') => hex.move_by(line),
+            Key::PageUp => hex.move_by(page.saturating_neg()),
+            Key::PageDown => hex.move_by(page),
+            Key::Home => hex.move_by(column.saturating_neg()),
+            Key::End => hex.move_by(line.saturating_sub(1).saturating_sub(column)),
+            Key::Char('g') => hex.move_by(isize::MIN),
+            Key::Char('G') => hex.move_by(isize::MAX),
+            _ => (),
+        }
+        Ok(true)
+    }
+    /// Moves the hex mode cursor to the next (or previous) occurrence of the bytes last
+    /// searched for
+    fn find_bytes(&mut self, forward: bool) {
+        let found = match self.document.hex_mut() {
+            Some(hex) if !hex.last_search.is_empty() => hex.find(&hex.last_search, forward).map(|offset| hex.goto(offset)),
+            _ => return,
+        };
+        if found.is_none() {
+            self.status_message = StatusMessage::from("ERR: Pattern not found".to_owned(), Option::from(self.config.theme.error));
+        }
+    }
+    /// Switches the current buffer between text and hex mode
+    pub fn toggle_hex(&mut self) {
+        match self.document.toggle_hex() {
+            Ok(()) => {
+                let mode = if self.document.hex().is_some() { "Hex mode" } else { "Text mode" };
+                self.status_message = StatusMessage::from(mode.to_owned(), None);
+                self.clamp_cursor();
+                self.scroll();
+            }
+            Err(message) => self.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(self.config.theme.error)),
+        }
+    }
+    /// Moves the cursor to byte `offset` of the file, as it would be saved
+    pub fn goto_byte(&mut self, offset: usize) {
+        if let Some(hex) = self.document.hex_mut() {
+            if let Err(message) = hex.goto(offset) {
+                self.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(self.config.theme.error));
+            }
+            self.scroll();
+            return;
+        }
+        let format = self.document.format();
+        let ending = if format.line_ending == LineEnding::Dos { 2 } else { 1 };
+        let mut remaining = offset.saturating_sub(if format.bom { 3 } else { 0 });
+        for y in 0..self.document.len() {
+            let row = match self.document.row(y) {
+                Some(row) => row,
+                None => break,
+            };
+            let len = row.as_bytes().len();
+            if remaining < len.saturating_add(ending) || y.saturating_add(1) == self.document.len() {
+                self.cursor_position = Position { x: row.grapheme_index(remaining.min(len)), y };
+                break;
+            }
+            remaining = remaining.saturating_sub(len.saturating_add(ending));
+        }
+        self.clamp_cursor();
+        self.scroll();
+    }
+    /// Reads a full Command mode command starting with `c` (e.g. `3dw`) and runs it,
+    /// returning the key and count instead if it is not an operator or motion
+    fn process_normal_key(&mut self, c: char) -> Result<Option<(char, Option<usize>)>, std::io::Error> {
+        match self.read_command(Parser::default(), c)? {
+            Step::Done(Action::Move(motion, count)) => self.apply_motion(motion, count),
+            Step::Done(Action::Operate(operator, motion, count)) => self.operate(operator, motion, count),
+            Step::Done(Action::Other(c, count)) => return Ok(Some((c, count))),
+            Step::Pending | Step::Invalid => self.register = None,
+        }
+        Ok(None)
+    }
+    /// Feeds `parser` the key `c` and then further keys until it has a whole command
+    fn read_command(&mut self, mut parser: Parser, c: char) -> Result<Step, std::io::Error> {
+        let mut step = parser.feed(c);
+        while step == Step::Pending {
+            step = match self.read_key()? {
+                Key::Char(c) => parser.feed(c),
+                _ => Step::Invalid,
+            };
+        }
+        Ok(step)
+    }
+    /// Moves the cursor over `motion`, stepping through `move_cursor` for `h/j/k/l`
+    fn apply_motion(&mut self, motion: Motion, count: Option<usize>) {
+        let key = match motion {
+            Motion::Left => Key::Left,
+            Motion::Right => Key::Right,
+            Motion::Up => Key::Up,
+            Motion::Down => Key::Down,
+            _ => {
+                if let Some(target) = motion.target(&self.document, self.cursor_position, count) {
+                    self.cursor_position = target;
+                    self.clamp_cursor();
+                }
+                return;
+            }
+        };
+        for _ in 0..count.unwrap_or(1) {
+            self.move_cursor(key);
+        }
+    }
+    fn operate(&mut self, operator: Operator, motion: Motion, count: Option<usize>) {
+        let outcome = match normal::operate(&mut self.document, self.cursor_position, operator, motion, count, &self.config) {
+            Some(outcome) => outcome,
+            None => {
+                self.register = None;
+                return;
+            }
+        };
+        if let Some((yank, deleted)) = outcome.yank {
+            self.store_yank(yank, deleted);
+        }
+        self.cursor_position = outcome.cursor;
+        self.clamp_cursor();
+        if outcome.insert {
+            self.just_entered = false;
+            self.interaction_mode = InteractionMode::Insert;
+            self.terminal.cursor_bar();
+        }
+    }
+    /// Handles the jumps within the screen (`H`, `M` and `L`), returning whether `c` was one
+    fn navigate(&mut self, c: char) -> bool {
+        match c {
+            'H' => self.cursor_position.y = self.offset.y,
+            #[allow(clippy::integer_division)]
+            'M' => self.cursor_position.y = self.offset.y.saturating_add(usize::from(self.viewport().height / 2)).saturating_sub(1),
+            'L' => self.cursor_position.y = self.offset.y.saturating_add(usize::from(self.viewport().height).saturating_sub(1)),
+            _ => return false,
+        }
+        true
+    }
+    /// Prompts for an ex command (starting with `initial`) and runs it, returning
+    /// `false` if the prompt was aborted
+    fn command_prompt(&mut self, initial: &str) -> Result<bool, std::io::Error> {
+        if let Some(command_line) = self.prompt_with(":", initial, |_, _|{})? {
+            self.execute_command(&command_line);
+            Ok(true)
+        } else {
+            self.status_message = StatusMessage::from("ERR: Command aborted".to_owned(), Option::from(self.config.theme.error));
+            Ok(false)
+        }
+    }
+    /// Runs an ex command line such as `w`, `q!` or `'<,'>d`
+    pub fn execute_command(&mut self, command_line: &str) {
+        let (range, command_name) = match self.command_handler.parse_range(self, command_line) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                self.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(self.config.theme.error));
+                return;
+            }
+        };
+        let command_name = command_name.trim();
+        if command_name.is_empty() {
+            // A bare range (e.g. `:42`) jumps to its last line
+            if let Some(range) = range {
+                self.cursor_position = Position { x: 0, y: range.end };
+                self.clamp_cursor();
+            }
+            return;
+        }
+        let is_forced = command_name.contains('!');
+        if let Some(command) = self.command_handler.get_command(command_name) {
+            let command_params = command.regex.replace(command_name, "").to_string();
+            (command.function)(self, command_params.split(' ').collect(), is_forced, range);
+        } else {
+            self.status_message = StatusMessage::from("ERR: Invalid command".to_owned(), Option::from(self.config.theme.error));
+        }
+    }
+    /// Enters Visual mode of the given kind, switching kind or leaving it if already selecting
+    fn enter_visual(&mut self, kind: SelectionKind) {
+        match self.interaction_mode {
+            InteractionMode::Visual(current) if current == kind => self.exit_visual(),
+            InteractionMode::Visual(_) => self.interaction_mode = InteractionMode::Visual(kind),
+            _ => {
+                self.just_entered = false;
+                self.visual_anchor = self.cursor_position;
+                self.interaction_mode = InteractionMode::Visual(kind);
+            }
+        }
+    }
+    fn exit_visual(&mut self) {
+        if let Some(selection) = self.selection() {
+            self.visual_marks = Some((selection.start(), selection.end()));
         }
+        self.interaction_mode = InteractionMode::Command;
+    }
+    #[must_use] pub fn selection(&self) -> Option<Selection> {
+        match self.interaction_mode {
+            InteractionMode::Visual(kind) => Some(Selection {
+                anchor: self.visual_anchor,
+                cursor: self.cursor_position,
+                kind,
+            }),
+            _ => None,
+        }
+    }
+    fn process_visual_key(&mut self, c: char) -> Result<(), std::io::Error> {
+        let selection = match self.selection() {
+            Some(selection) => selection,
+            None => return Ok(()),
+        };
+        match c {
+            'v' => self.enter_visual(SelectionKind::Char),
+            'V' => self.enter_visual(SelectionKind::Line),
+            'o' => std::mem::swap(&mut self.visual_anchor, &mut self.cursor_position),
+            '"' => self.select_register()?,
+            'y' => {
+                self.store_yank(selection.yank(&self.document), false);
+                self.exit_visual();
+                self.cursor_position = selection.start();
+            }
+            'd' | 'x' => {
+                self.exit_visual();
+                let (yank, cursor) = selection.delete(&mut self.document);
+                self.store_yank(yank, true);
+                self.cursor_position = cursor;
+            }
+            'c' => {
+                self.exit_visual();
+                let (yank, cursor) = selection.change(&mut self.document);
+                self.store_yank(yank, true);
+                self.cursor_position = cursor;
+                self.interaction_mode = InteractionMode::Insert;
+                self.terminal.cursor_bar();
+            }
+            '>' | '<' | '~' | 'u' | 'U' => {
+                self.exit_visual();
+                match c {
+                    '>' => selection.indent(&mut self.document, &self.config.indent()),
+                    '<' => selection.outdent(&mut self.document, self.config.tab_width),
+                    'u' => selection.convert_case(&mut self.document, Case::Lower),
+                    'U' => selection.convert_case(&mut self.document, Case::Upper),
+                    _ => selection.convert_case(&mut self.document, Case::Toggle),
+                }
+                self.cursor_position = selection.start();
+            }
+            ':' => {
+                self.exit_visual();
+                self.command_prompt("'<,'>")?;
+            }
+            _ => {
+                match self.read_command(Parser::motions_only(), c)? {
+                    Step::Done(Action::Move(Motion::Object(object), count)) => {
+                        if let Some(selected) = normal::object_selection(&self.document, self.cursor_position, object, count) {
+                            if selected.kind == SelectionKind::Line {
+                                self.interaction_mode = InteractionMode::Visual(SelectionKind::Line);
+                            }
+                            self.visual_anchor = selected.anchor;
+                            self.cursor_position = selected.cursor;
+                        }
+                    }
+                    Step::Done(Action::Move(motion, count)) => self.apply_motion(motion, count),
+                    Step::Done(Action::Other(c, _)) => {
+                        self.navigate(c);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        self.clamp_cursor();
+        Ok(())
+    }
+    /// Highlights the part of the Visual selection that is on screen
+    fn highlight_selection(&mut self) {
+        let selection = match self.selection() {
+            Some(selection) => selection,
+            None => return,
+        };
+        let (first, last) = selection.rows();
+        let top = first.max(self.offset.y);
+        let bottom = last.min(self.offset.y.saturating_add(usize::from(self.viewport().height)));
+        for y in top..=bottom {
+            let row_len = self.document.row(y).map_or(0, Row::len);
+            if let Some((start, end)) = selection.columns(y, row_len) {
+                for x in start..end {
+                    self.document.highlight(&Position { x, y }, highlighting::Type::Selection);
+                }
+            }
+        }
+    }
+    /// Reads the register name following `"`, used by the next yank, delete or put
+    fn select_register(&mut self) -> Result<(), std::io::Error> {
+        match self.read_key()? {
+            Key::Char(name) if Registers::is_valid(name) => self.register = Some(name),
+            Key::Char(name) => {
+                self.register = None;
+                self.status_message = StatusMessage::from(format!("ERR: Invalid register \"{}", name), Option::from(self.config.theme.error));
+            }
+            _ => self.register = None,
+        }
+        Ok(())
+    }
+    /// Stores yanked or deleted text in the register picked with `"`, or the default ones
+    pub fn store_yank(&mut self, yank: Yank, deleted: bool) {
+        let register = self.register.take();
+        let result = if deleted {
+            self.registers.delete(register, yank, &self.config.clipboard)
+        } else {
+            self.registers.yank(register, yank, &self.config.clipboard)
+        };
+        if let Err(message) = result {
+            self.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(self.config.theme.error));
+        }
+    }
+    /// Puts the register picked with `"` (or the unnamed one) `count` times after the cursor,
+    /// or before it
+    fn put(&mut self, before: bool, count: usize) {
+        match self.registers.get(self.register.take(), &self.config.clipboard) {
+            Ok(yank) => {
+                let at = self.cursor_position;
+                for _ in 0..count {
+                    self.cursor_position = yank.put(&mut self.document, &at, before);
+                }
+                self.clamp_cursor();
+            }
+            Err(message) => {
+                self.status_message = StatusMessage::from(format!("ERR: {}", message), Option::from(self.config.theme.error));
+            }
+        }
+    }
+    /// Runs `operation` over the rows in `range` (or the cursor row) as a linewise selection
+    pub fn with_lines(&mut self, range: Option<LineRange>, operation: fn(&mut Self, &Selection)) {
+        let range = range.unwrap_or(LineRange { start: self.cursor_position.y, end: self.cursor_position.y });
+        let selection = Selection::lines(range.start, range.end);
+        operation(self, &selection);
+        self.clamp_cursor();
+    }
+    /// Width of the line number column in the current window, including its trailing space
+    fn gutter_width(&self) -> usize {
+        gutter_width(&self.config, &self.document)
+    }
+    /// The screen column of the cursor within its row, with tabs expanded
+    fn cursor_column(&self) -> usize {
+        self.document
+            .row(self.cursor_position.y)
+            .map_or(0, |row| row.display_column(self.cursor_position.x, self.config.tab_width))
+    }
+    pub(crate) fn scroll(&mut self) {
+        let height = usize::from(self.viewport().height);
+        if let Some(hex) = self.document.hex_mut() {
+            hex.scroll_to_cursor(height);
+            return;
+        }
+        let Position { y, .. } = self.cursor_position;
+        let x = self.cursor_column();
+        let width = usize::from(self.viewport().width).saturating_sub(self.gutter_width());
+        scroll_into_view(&mut self.offset, x, y, width, height);
     }
     fn move_cursor(&mut self, key: Key) {
-        let terminal_height = usize::from(self.terminal.size().height);
+        let terminal_height = usize::from(self.viewport().height);
         let Position { mut y, mut x } = self.cursor_position;
         let height = self.document.len();
         let mut width = self.document.row(y).map_or(0, row::Row::len);
@@ -302,88 +1651,158 @@ impl Editor {
 
         self.cursor_position = Position { x, y }
     }
-    fn draw_welcome_message(&self) {
+    fn draw_welcome_message(&self, width: usize) {
         let mut welcome_message = format!("Lecturn v{}", VERSION);
-        let width = usize::from(self.terminal.size().width);
         let len = welcome_message.len();
         #[allow(clippy::integer_arithmetic, clippy::integer_division)]
         let padding = width.saturating_sub(len) / 2;
         let spaces = " ".repeat(padding.saturating_sub(1));
         welcome_message = format!("{}{}", spaces, welcome_message);
         welcome_message.truncate(width);
-        println!("{}\r", welcome_message);
+        self.terminal.print(format_args!("{:<width$}", welcome_message, width = width));
     }
-    pub fn draw_row(&self, row: &Row) {
-        let width = usize::from(self.terminal.size().width);
-        let start = self.offset.x;
-        #[allow(clippy::integer_arithmetic)]
-        let end = self.offset.x + width - self.terminal.size().height.to_string().len();
-        let row = row.render(start, end);
-        println!("{}\r", row);
-    }
-    #[allow(clippy::integer_arithmetic, clippy::integer_division)]
-    fn draw_rows(&self) {
-        let height = self.terminal.size().height;
-        for terminal_row in 0..height {
-            Terminal::clear_current_line();
-            if let Some(row) = self
-                .document
-                .row(self.offset.y.saturating_add(usize::from(terminal_row)))
-            {
-                Terminal::set_fg_color(Rgb(249, 241, 165));
-                let line_number = self.offset.y.saturating_add(usize::from(terminal_row) + 1);
-                print!("{}{} ", " ".repeat(self.document.len().to_string().len() - line_number.to_string().len()), line_number);
-                Terminal::reset_fg_color();
-                self.draw_row(row);
-
-            } else if self.document.is_empty() && terminal_row == height / 3 && self.just_entered {
-                self.draw_welcome_message();
+    /// Draws the part of `row` from screen column `start` that fits in `width` columns,
+    /// padding the rest with spaces
+    pub fn draw_row(&self, row: &Row, start: usize, width: usize) {
+        let shown = row.display_column(row.len(), self.config.tab_width).saturating_sub(start).min(width);
+        let rendered = row.render(start, start.saturating_add(width), &self.config);
+        self.terminal.print(format_args!("{}{}", rendered, " ".repeat(width.saturating_sub(shown))));
+    }
+    /// Draws the window numbered `id` into `area`: its rows, then its status line on the
+    /// last row, then the separator to its right if another window is there
+    fn draw_window(&self, id: usize, area: Rect) {
+        let (document, cursor_position, offset) = match self.window_view(id) {
+            Some(view) => view,
+            None => return,
+        };
+        let width = usize::from(area.width);
+        let gutter_width = gutter_width(&self.config, document).min(width);
+        let text_height = area.height.saturating_sub(1);
+        for screen_row in 0..text_height {
+            self.terminal.cursor_position(&Position { x: usize::from(area.x), y: usize::from(area.y.saturating_add(screen_row)) });
+            let y = offset.y.saturating_add(usize::from(screen_row));
+            if let Some(hex) = document.hex() {
+                let mut line = hex.render_line(hex.scroll.saturating_add(usize::from(screen_row))).unwrap_or_default();
+                line.truncate(width);
+                self.terminal.print(format_args!("{}{}", line, " ".repeat(width.saturating_sub(line.len()))));
+            } else if let Some(row) = document.row(y) {
+                let line_number = match self.config.line_numbers {
+                    LineNumbers::Relative if y != cursor_position.y => Some(y.abs_diff(cursor_position.y)),
+                    LineNumbers::Off => None,
+                    _ => Some(y.saturating_add(1)),
+                };
+                if let Some(line_number) = line_number {
+                    self.terminal.set_fg_color(self.config.theme.line_number);
+                    let mut number = format!("{:>width$} ", line_number, width = gutter_width.saturating_sub(1));
+                    number.truncate(gutter_width);
+                    self.terminal.print(number);
+                    self.terminal.reset_fg_color();
+                }
+                self.draw_row(row, offset.x, width.saturating_sub(gutter_width));
+            } else if document.is_empty() && self.just_entered && self.windows.len() == 1 && Some(screen_row) == text_height.checked_div(3) {
+                self.draw_welcome_message(width);
             } else {
-                println!();
+                self.terminal.print(" ".repeat(width));
+            }
+        }
+        self.terminal.cursor_position(&Position { x: usize::from(area.x), y: usize::from(area.y.saturating_add(text_height)) });
+        self.draw_status_bar(document, cursor_position, width);
+        if area.x.saturating_add(area.width) < self.terminal.size().width {
+            self.terminal.set_bg_color(self.config.theme.status_bg);
+            for screen_row in 0..area.height {
+                self.terminal.cursor_position(&Position {
+                    x: usize::from(area.x.saturating_add(area.width)),
+                    y: usize::from(area.y.saturating_add(screen_row)),
+                });
+                self.terminal.print(" ");
             }
+            self.terminal.reset_bg_color();
         }
     }
-    fn draw_status_bar(&self) {
-        let mut status;
-        let width = usize::from(self.terminal.size().width);
-        let modified_indicator = if self.document.is_dirty() {
-            " [+]"
-        } else {
-            ""
-        };
-        let mut file_name = "[No Name]".to_owned();
-        #[allow(clippy::pattern_type_mismatch)]
-        if let Some(name) = &self.document.file_name {
-            file_name = name.clone();
-            file_name.truncate(20);
+    /// Draws a label for every tab on the top row: its number and the name of the buffer in
+    /// its focused window, with the current tab in the status bar colours
+    fn draw_tab_line(&self) {
+        self.terminal.cursor_position(&Position::default());
+        let mut width = usize::from(self.terminal.size().width);
+        for (index, tab) in self.tabs.iter().enumerate() {
+            let document = if index == self.current_tab {
+                Some(&self.document)
+            } else {
+                tab.windows
+                    .get(tab.current_window)
+                    .and_then(|window| self.buffer_index(window.buffer))
+                    .and_then(|buffer| self.buffer_document(buffer))
+            };
+            let document = match document {
+                Some(document) => document,
+                None => continue,
+            };
+            let name = buffer::name(document);
+            let name = Path::new(name).file_name().map_or(name.into(), |file_name| file_name.to_string_lossy());
+            let mut label = format!(" {} {}{} ", index.saturating_add(1), name, buffer::modified_indicator(document));
+            label.truncate(width);
+            width = width.saturating_sub(label.len());
+            if index == self.current_tab {
+                self.terminal.set_bg_color(self.config.theme.status_bg);
+                self.terminal.set_fg_color(self.config.theme.status_fg);
+            }
+            self.terminal.print(label);
+            self.terminal.reset_fg_color();
+            self.terminal.reset_bg_color();
         }
+        self.terminal.print(" ".repeat(width));
+    }
+    fn draw_status_bar(&self, document: &Document, cursor_position: Position, width: usize) {
+        let mut status;
+        let modified_indicator = buffer::modified_indicator(document);
+        let mut file_name = buffer::name(document).to_owned();
+        file_name.truncate(20);
+        let read_only_indicator = if document.is_read_only() || !document.is_writable() { " [RO]" } else { "" };
         status = format!(
-            "{}{}",
+            "{}{}{}",
             file_name,
-            modified_indicator
+            modified_indicator,
+            read_only_indicator
         );
 
-        let position_indicator = format!(
-            "{},{}",
-            self.cursor_position.y.saturating_add(1),
-            self.cursor_position.x.saturating_add(1),
-        );
+        let language = document.language().map_or("no ft", |language| &language.name);
+        let position_indicator = match document.hex() {
+            Some(hex) => format!("hex | {} | 0x{:08x}", document.format(), hex.cursor),
+            None => format!(
+                "{} | {} | {},{}",
+                language,
+                document.format(),
+                cursor_position.y.saturating_add(1),
+                cursor_position.x.saturating_add(1),
+            ),
+        };
         #[allow(clippy::integer_arithmetic)]
         let len = status.len() + position_indicator.len();
         status.push_str(&" ".repeat(width.saturating_sub(len)));
         status = format!("{}{}", status, position_indicator);
         status.truncate(width);
-        Terminal::set_bg_color(STATUS_BG_COLOR);
-        Terminal::set_fg_color(STATUS_FG_COLOR);
-        println!("{}\r", status);
-        Terminal::reset_fg_color();
-        Terminal::reset_bg_color();
+        self.terminal.set_bg_color(self.config.theme.status_bg);
+        self.terminal.set_fg_color(self.config.theme.status_fg);
+        self.terminal.print(status);
+        self.terminal.reset_fg_color();
+        self.terminal.reset_bg_color();
     }
     fn draw_message_bar(&mut self) {
-        Terminal::clear_current_line();
-        if self.interaction_mode == InteractionMode::Insert {
-            self.status_message = StatusMessage::from("-- INSERT --".to_owned(), None);
-        } else if self.status_message.text == *"-- INSERT --" {
+        self.terminal.cursor_position(&Position { x: 0, y: usize::from(self.terminal.size().height.saturating_add(1)) });
+        self.terminal.clear_current_line();
+        let mode_indicators = ["-- INSERT --", "-- VISUAL --", "-- VISUAL LINE --", "-- VISUAL BLOCK --"];
+        let mode_indicator = match self.interaction_mode {
+            InteractionMode::Insert => mode_indicators.first(),
+            InteractionMode::Visual(SelectionKind::Char) => mode_indicators.get(1),
+            InteractionMode::Visual(SelectionKind::Line) => mode_indicators.get(2),
+            InteractionMode::Visual(SelectionKind::Block) => mode_indicators.get(3),
+            _ => None,
+        };
+        // An error (such as a refused edit) is shown over the mode until it expires
+        let showing_error = self.status_message.color.is_some() && Instant::now() - self.status_message.time < Duration::new(5, 0);
+        if let Some(indicator) = mode_indicator.filter(|_| !showing_error) {
+            self.status_message = StatusMessage::from((*indicator).to_owned(), None);
+        } else if mode_indicators.contains(&self.status_message.text.as_str()) {
             self.status_message = StatusMessage::from("".to_owned(), None);
         }
 
@@ -392,26 +1811,32 @@ impl Editor {
             let mut text = message.text.clone();
             text.truncate(usize::from(self.terminal.size().width));
             if let Some(color) = message.color {
-                Terminal::set_bg_color(color);
+                self.terminal.set_bg_color(color);
             }
-            Terminal::set_fg_color(color::Rgb(255, 255, 255));
-            print!("{}", text);
-            Terminal::reset_bg_color();
-            Terminal::reset_fg_color();
+            self.terminal.set_fg_color(self.config.theme.message_fg);
+            self.terminal.print(text);
+            self.terminal.reset_bg_color();
+            self.terminal.reset_fg_color();
         }
     }
-    fn prompt<C>(&mut self, prompt: &str, mut callback: C) -> Result<Option<String>, std::io::Error>
+    fn prompt<C>(&mut self, prompt: &str, callback: C) -> Result<Option<String>, std::io::Error>
     where
         C: FnMut(&mut Self, &String)
     {
-        let mut result = String::new();
+        self.prompt_with(prompt, "", callback)
+    }
+    fn prompt_with<C>(&mut self, prompt: &str, initial: &str, mut callback: C) -> Result<Option<String>, std::io::Error>
+    where
+        C: FnMut(&mut Self, &String)
+    {
+        let mut result = initial.to_owned();
         loop {
-            Terminal::cursor_hide();
+            self.terminal.cursor_hide();
             self.status_message = StatusMessage::from(format!("{}{}", prompt, result), None);
             callback(self, &result);
             self.refresh_screen(false)?;
 
-            match Terminal::read_key()? {
+            match self.read_key()? {
                 Key::Backspace => {
                     if !result.is_empty() {
                         result.truncate(result.len().saturating_sub(1));
@@ -440,6 +1865,149 @@ impl Editor {
 }
 
 fn die(e: &std::io::Error) {
-    Terminal::clear_screen();
+    print!("{}", termion::clear::All);
     panic!("{}", e);
 }
+
+/// The index `steps` away from `index` in a list of `len`, wrapping around at either end
+fn wrapping_step(index: usize, steps: isize, len: usize) -> usize {
+    let distance = steps.unsigned_abs().checked_rem(len).unwrap_or(0);
+    let index = if steps < 0 {
+        index.saturating_add(len.saturating_sub(distance))
+    } else {
+        index.saturating_add(distance)
+    };
+    index.checked_rem(len).unwrap_or(0)
+}
+
+/// Moves `offset` as little as needed for screen column `x` of row `y` to be within a
+/// view `width` by `height` cells
+fn scroll_into_view(offset: &mut Position, x: usize, y: usize, width: usize, height: usize) {
+    if y < offset.y {
+        offset.y = y;
+    } else if y >= offset.y.saturating_add(height) {
+        offset.y = y.saturating_sub(height).saturating_add(1);
+    }
+    if x < offset.x {
+        offset.x = x;
+    } else if x >= offset.x.saturating_add(width) {
+        offset.x = x.saturating_sub(width).saturating_add(1);
+    }
+}
+
+/// Width of the line number column for `document`, including its trailing space
+fn gutter_width(config: &Config, document: &Document) -> usize {
+    if config.line_numbers == LineNumbers::Off {
+        0
+    } else {
+        document.len().to_string().len().saturating_add(1)
+    }
+}
+
+fn config_error_message(errors: &[String]) -> Option<String> {
+    let first = errors.first()?;
+    let more = errors.len().saturating_sub(1);
+    if more == 0 {
+        Some(format!("ERR: config: {}", first))
+    } else {
+        Some(format!("ERR: config: {} (and {} more)", first, more))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{open, play, start, TempFile};
+
+    #[test]
+    fn writes_swap_files_while_idle() {
+        let file = TempFile::new("editor-idle", "notes.txt", "one\n");
+        let swap = swap::path_for(&file.path.to_string_lossy());
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, "izero \x1b");
+        assert!(!swap.exists());
+        editor.swap_written = Instant::now().checked_sub(SWAP_INTERVAL).unwrap();
+        screen.idle();
+        editor.step().unwrap();
+        assert!(fs::read_to_string(&swap).unwrap().contains("zero one"));
+    }
+
+    #[test]
+    fn writes_swap_files_when_the_terminal_goes() {
+        let file = TempFile::new("editor-hangup", "notes.txt", "one\n");
+        let swap = swap::path_for(&file.path.to_string_lossy());
+        let (mut editor, screen) = open(&file.path);
+        // No more keys once these are read, as when the terminal is closed
+        screen.type_text("izero ");
+        let run = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| editor.run()));
+        assert!(run.is_err());
+        assert!(fs::read_to_string(&swap).unwrap().contains("zero one"));
+    }
+
+    #[test]
+    fn closes_saved_files_whose_undo_history_cannot_be_written() {
+        let file = TempFile::new("editor-undo-file", "notes.txt", "one\n");
+        // A directory where the undo file would be can't be written over
+        let undo_file = file.path.with_file_name(".notes.txt.un~");
+        fs::create_dir(&undo_file).unwrap();
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, "izero \x1b:w\n");
+        assert!(editor.status_message.text.starts_with("File saved, but could not write undo history: "));
+        assert!(!editor.document.is_dirty());
+        play(&mut editor, &screen, ":wq\n");
+        assert!(editor.should_quit);
+        assert_eq!(fs::read_to_string(&file.path).unwrap(), "zero one\n");
+    }
+
+    #[test]
+    fn saves_read_only_documents_only_when_forced() {
+        let file = TempFile::new("editor-read-only", "notes.txt", "one\n");
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, ":set ro\nizero \x1b");
+        assert_eq!(editor.status_message.text, "ERR: Document is read-only (:set noreadonly to allow changes)");
+        assert_eq!((editor.cursor_position.x, editor.document.text().as_str()), (0, "one"));
+        editor.status_message = StatusMessage::from(String::new(), None);
+        screen.press(vec![Key::Char('l'), Key::Char('l'), Key::Char('i'), Key::Backspace, Key::Backspace, Key::Esc]);
+        play(&mut editor, &screen, "");
+        assert_eq!(editor.status_message.text, "ERR: Document is read-only (:set noreadonly to allow changes)");
+        assert_eq!((editor.cursor_position.x, editor.document.text().as_str()), (2, "one"));
+        play(&mut editor, &screen, "0");
+        play(&mut editor, &screen, ":set noro\nizero \x1b:set ro\n:w\n");
+        assert_eq!(editor.status_message.text, "ERR: File is read-only (add ! to override)");
+        assert_eq!(fs::read_to_string(&file.path).unwrap(), "one\n");
+        play(&mut editor, &screen, ":w!\n");
+        assert_eq!(fs::read_to_string(&file.path).unwrap(), "zero one\n");
+        assert!(!editor.document.is_dirty() && editor.document.is_read_only());
+    }
+
+    #[test]
+    fn writes_to_stdout_instead_of_the_file() {
+        let file = TempFile::new("editor-stdout", "message.txt", "one\n");
+        let out = file.sibling("out", "");
+        let (mut editor, screen) = open(&file.path);
+        editor.stdout = Some(File::create(&out).unwrap());
+        play(&mut editor, &screen, "izero \x1b:wq\n");
+        assert!(editor.should_quit);
+        // Nothing is written until the editor has finished with the terminal
+        assert_eq!(fs::read_to_string(&out).unwrap(), "");
+        editor.finish();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "zero one\n");
+        assert_eq!(fs::read_to_string(&file.path).unwrap(), "one\n");
+    }
+
+    #[test]
+    fn keeps_other_unsaved_buffers_from_being_lost_on_stdout() {
+        let file = TempFile::new("editor-stdout-buffers", "message.txt", "one\n");
+        let other = file.sibling("other.txt", "two\n");
+        let name = |path: &Path| crate::args::File { name: path.to_string_lossy().into_owned(), jump: None };
+        let (mut editor, screen) = start(&Args { files: vec![name(&file.path), name(&other)], ..Args::default() });
+        editor.stdout = Some(File::create(file.sibling("out", "")).unwrap());
+        play(&mut editor, &screen, "izero \x1b:bn\n:wq\n");
+        assert!(!editor.should_quit);
+        let message = format!("There are unsaved changes in buffer 1 \"{}\". Run :wq! to write anyway", file.path.display());
+        assert_eq!(editor.status_message.text, message);
+        play(&mut editor, &screen, ":wq!\n");
+        assert!(editor.should_quit);
+        assert_eq!(editor.output.as_deref(), Some(&b"two\n"[..]));
+    }
+}
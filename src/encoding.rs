@@ -0,0 +1,250 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The character encoding a file is read and written in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// ISO-8859-1, where every byte is the code point of the same number
+    Latin1,
+    /// Latin-1 with printable characters such as `€` and curly quotes in 0x80-0x9F
+    Windows1252,
+}
+
+/// Characters standing in for bytes that are not valid in the file's encoding, so they
+/// are written back exactly as they were. Real characters in this range (which are
+/// private use) are read as their bytes too, so nothing is ever mistaken for one
+const ESCAPE_START: u32 = 0x0010_ff00;
+
+/// Windows-1252 characters for bytes 0x80-0x9F; the five bytes it leaves undefined
+/// keep their Latin-1 meaning
+const WINDOWS_1252: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+/// How many bytes are checked for a NUL when telling binary files from text, as git does
+const BINARY_CHECK_LEN: usize = 8000;
+
+impl Encoding {
+    /// The `:set fileencoding=` name for it
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "latin1",
+            Encoding::Windows1252 => "cp1252",
+        }
+    }
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-16le" | "utf16le" => Some(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" | "utf-16" | "utf16" => Some(Encoding::Utf16Be),
+            "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "cp1252" | "windows-1252" => Some(Encoding::Windows1252),
+            _ => None,
+        }
+    }
+    /// Whether it can encode every character, and so a byte order mark
+    #[must_use]
+    pub fn is_unicode(self) -> bool {
+        matches!(self, Encoding::Utf8 | Encoding::Utf16Le | Encoding::Utf16Be)
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A file's contents as text
+pub struct Decoded {
+    pub text: String,
+    pub encoding: Encoding,
+    /// It contains NUL bytes, so is probably not text at all
+    pub binary: bool,
+}
+
+/// Works out the encoding of `bytes` and decodes them. UTF-16 is only recognised by its
+/// byte order mark, which is kept as the first character. Anything that is not valid
+/// UTF-8 or UTF-16 is read as Windows-1252 (or Latin-1 if it has none of its extra
+/// characters), unless it is mostly UTF-8, in which case the invalid bytes are escaped
+#[must_use]
+pub fn decode(bytes: &[u8]) -> Decoded {
+    let utf16 = match bytes {
+        [0xff, 0xfe, ..] => Some(Encoding::Utf16Le),
+        [0xfe, 0xff, ..] => Some(Encoding::Utf16Be),
+        _ => None,
+    };
+    if let Some(encoding) = utf16 {
+        if let Some(text) = decode_utf16(bytes, encoding) {
+            return Decoded { text, encoding, binary: false };
+        }
+    }
+    let binary = bytes.iter().take(BINARY_CHECK_LEN).any(|&byte| byte == 0);
+    let (text, invalid, non_ascii) = decode_utf8(bytes);
+    if invalid == 0 || binary || non_ascii > 0 {
+        return Decoded { text, encoding: Encoding::Utf8, binary };
+    }
+    let encoding = if bytes.iter().any(|byte| (0x80..0xa0).contains(byte)) {
+        Encoding::Windows1252
+    } else {
+        Encoding::Latin1
+    };
+    let text = bytes.iter().map(|&byte| decode_byte(byte, encoding)).collect();
+    Decoded { text, encoding, binary }
+}
+
+/// `None` if the code units are not valid UTF-16
+fn decode_utf16(bytes: &[u8], encoding: Encoding) -> Option<String> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let units = bytes.chunks_exact(2).map(|pair| match (encoding, pair) {
+        (Encoding::Utf16Le, &[low, high]) => u16::from_le_bytes([low, high]),
+        (_, &[high, low]) => u16::from_be_bytes([high, low]),
+        _ => 0,
+    });
+    char::decode_utf16(units).collect::<Result<String, _>>().ok()
+}
+
+/// Decodes UTF-8, escaping invalid bytes. Also returns how many bytes were invalid and
+/// how many valid characters were not ASCII
+fn decode_utf8(mut bytes: &[u8]) -> (String, usize, usize) {
+    let (mut text, mut invalid, mut non_ascii) = (String::new(), 0_usize, 0_usize);
+    while !bytes.is_empty() {
+        let (valid, rest) = match std::str::from_utf8(bytes) {
+            Ok(valid) => (valid, &[][..]),
+            Err(error) => {
+                let (valid, rest) = bytes.split_at(error.valid_up_to());
+                (std::str::from_utf8(valid).unwrap_or_default(), rest)
+            }
+        };
+        for c in valid.chars() {
+            if is_escape(c) {
+                let mut buffer = [0; 4];
+                text.extend(c.encode_utf8(&mut buffer).bytes().map(escape));
+            } else {
+                non_ascii = non_ascii.saturating_add(usize::from(!c.is_ascii()));
+                text.push(c);
+            }
+        }
+        let bad_len = match std::str::from_utf8(rest) {
+            Ok(_) => 0,
+            Err(error) => error.error_len().unwrap_or(rest.len()),
+        };
+        let (bad, rest) = rest.split_at(bad_len);
+        invalid = invalid.saturating_add(bad.len());
+        text.extend(bad.iter().map(|&byte| escape(byte)));
+        bytes = rest;
+    }
+    (text, invalid, non_ascii)
+}
+
+fn decode_byte(byte: u8, encoding: Encoding) -> char {
+    match byte.checked_sub(0x80) {
+        Some(index) if encoding == Encoding::Windows1252 && index < 0x20 => {
+            WINDOWS_1252.get(usize::from(index)).copied().unwrap_or_else(|| char::from(byte))
+        }
+        _ => char::from(byte),
+    }
+}
+
+fn escape(byte: u8) -> char {
+    char::from_u32(ESCAPE_START.saturating_add(u32::from(byte))).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+/// Whether `c` stands for a byte that was not valid in the file's encoding
+#[must_use]
+pub fn is_escape(c: char) -> bool {
+    u32::from(c) >= ESCAPE_START
+}
+
+/// Encodes `text` for writing, with escaped bytes written back as they were.
+/// # Errors
+/// Will return `Err` with the (zero-based) line of the first character `encoding`
+/// cannot represent
+pub fn encode(text: &str, encoding: Encoding) -> Result<Vec<u8>, usize> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(text.len());
+    let mut line: usize = 0;
+    for c in text.chars() {
+        if c == '\n' {
+            line = line.saturating_add(1);
+        }
+        if is_escape(c) {
+            bytes.push(u8::try_from(u32::from(c).saturating_sub(ESCAPE_START)).map_err(|_| line)?);
+            continue;
+        }
+        match encoding {
+            Encoding::Utf8 => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                for unit in c.encode_utf16(&mut [0; 2]).iter() {
+                    let unit = if encoding == Encoding::Utf16Le { unit.to_le_bytes() } else { unit.to_be_bytes() };
+                    bytes.extend_from_slice(&unit);
+                }
+            }
+            Encoding::Latin1 => bytes.push(u8::try_from(u32::from(c)).map_err(|_| line)?),
+            Encoding::Windows1252 => bytes.push(encode_windows_1252(c).ok_or(line)?),
+        }
+    }
+    Ok(bytes)
+}
+
+fn encode_windows_1252(c: char) -> Option<u8> {
+    if let Some(index) = WINDOWS_1252.iter().position(|&special| special == c) {
+        return u8::try_from(index).ok().map(|index| index.saturating_add(0x80));
+    }
+    match u8::try_from(u32::from(c)) {
+        Ok(byte) if !(0x80..0xa0).contains(&byte) => Some(byte),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> Decoded {
+        let decoded = decode(bytes);
+        assert_eq!(encode(&decoded.text, decoded.encoding).unwrap(), bytes);
+        decoded
+    }
+
+    #[test]
+    fn detects_utf16_by_its_bom() {
+        let decoded = round_trip(b"\xff\xfeh\0i\0\n\0");
+        assert_eq!((decoded.encoding, decoded.text.as_str()), (Encoding::Utf16Le, "\u{feff}hi\n"));
+        let decoded = round_trip(b"\xfe\xff\0h\xd8\x3d\xde\x00");
+        assert_eq!((decoded.encoding, decoded.text.as_str()), (Encoding::Utf16Be, "\u{feff}h\u{1f600}"));
+        assert!(!decoded.binary);
+    }
+
+    #[test]
+    fn falls_back_to_8_bit_encodings() {
+        let decoded = round_trip(b"caf\xe9 \x80");
+        assert_eq!((decoded.encoding, decoded.text.as_str()), (Encoding::Windows1252, "café €"));
+        let decoded = round_trip(b"caf\xe9\n");
+        assert_eq!((decoded.encoding, decoded.text.as_str()), (Encoding::Latin1, "café\n"));
+        assert_eq!(encode("\u{20ac}", Encoding::Latin1), Err(0));
+        assert_eq!(encode("a\nb\n日本", Encoding::Windows1252), Err(2));
+        assert_eq!(encode("é", Encoding::Utf16Be).unwrap(), b"\0\xe9");
+    }
+
+    #[test]
+    fn keeps_invalid_bytes() {
+        let decoded = round_trip("naïve \u{10ff41} ".as_bytes().iter().copied().chain(*b"\xff\xc3").collect::<Vec<u8>>().as_slice());
+        assert_eq!(decoded.encoding, Encoding::Utf8);
+        assert_eq!(decoded.text.chars().filter(|&c| is_escape(c)).count(), 6);
+        let decoded = round_trip(b"\x7fELF\x02\x01\0\0\xff");
+        assert!(decoded.binary);
+    }
+}
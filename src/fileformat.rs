@@ -0,0 +1,171 @@
+use crate::encoding::{self, Encoding};
+use crate::Row;
+use std::fmt;
+
+const BOM: &str = "\u{feff}";
+
+/// What separates the lines of a file on disk
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineEnding {
+    /// `\n`
+    Unix,
+    /// `\r\n`
+    Dos,
+}
+
+impl LineEnding {
+    /// The `:set fileformat=` name for it
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Unix => "unix",
+            LineEnding::Dos => "dos",
+        }
+    }
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(LineEnding::Unix),
+            "dos" => Some(LineEnding::Dos),
+            _ => None,
+        }
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Dos => "\r\n",
+        }
+    }
+}
+
+/// How a file's text is laid out on disk, remembered when it is opened so that saving
+/// writes it back the same way
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FileFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    /// The file starts with a UTF-8 byte order mark
+    pub bom: bool,
+    /// The last line ends with a line ending too
+    pub final_newline: bool,
+}
+
+impl FileFormat {
+    #[must_use]
+    pub fn default() -> Self {
+        Self {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Unix,
+            bom: false,
+            final_newline: true,
+        }
+    }
+    /// Works out the format of `contents`, decoded from `encoding`, and splits it into
+    /// rows. A file is only taken to be `dos` if every line ends with `\r\n`; otherwise
+    /// any `\r` is kept in its row
+    #[must_use]
+    pub fn detect(contents: &str, encoding: Encoding) -> (Self, Vec<Row>) {
+        let (bom, contents) = match contents.strip_prefix(BOM) {
+            Some(rest) => (true, rest),
+            None => (false, contents),
+        };
+        let newlines = contents.matches('\n').count();
+        let line_ending = if newlines > 0 && contents.matches("\r\n").count() == newlines {
+            LineEnding::Dos
+        } else {
+            LineEnding::Unix
+        };
+        let ending = line_ending.as_str();
+        let format = Self {
+            encoding,
+            line_ending,
+            bom,
+            final_newline: contents.is_empty() || contents.ends_with(ending),
+        };
+        if contents.is_empty() {
+            return (format, vec![]);
+        }
+        let text = contents.strip_suffix(ending).unwrap_or(contents);
+        (format, text.split(ending).map(Row::from).collect())
+    }
+    /// The bytes to write for `rows`
+    /// # Errors
+    /// Will return `Err` with the first row that has characters the encoding cannot represent
+    pub fn join<'a>(self, rows: impl Iterator<Item = &'a Row>) -> Result<Vec<u8>, usize> {
+        let ending = self.line_ending.as_str();
+        let mut contents = String::new();
+        if self.bom {
+            contents.push_str(BOM);
+        }
+        let mut rows = rows.peekable();
+        while let Some(row) = rows.next() {
+            contents.push_str(row.as_str());
+            if self.final_newline || rows.peek().is_some() {
+                contents.push_str(ending);
+            }
+        }
+        encoding::encode(&contents, self.encoding)
+    }
+}
+
+/// As shown in the status bar, e.g. `utf-8 dos` or `latin1 unix [noeol]`
+impl fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.encoding, self.line_ending.name())?;
+        if self.bom {
+            write!(f, " [BOM]")?;
+        }
+        if !self.final_newline {
+            write!(f, " [noeol]")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(contents: &str) -> (FileFormat, Vec<String>, String) {
+        let (format, rows) = FileFormat::detect(contents, Encoding::Utf8);
+        let lines = rows.iter().map(|row| row.as_str().to_owned()).collect();
+        let saved = String::from_utf8(format.join(rows.iter()).unwrap()).unwrap();
+        (format, lines, saved)
+    }
+
+    #[test]
+    fn keeps_line_endings() {
+        let (format, lines, saved) = round_trip("one\r\ntwo\r\n");
+        assert_eq!(format.line_ending, LineEnding::Dos);
+        assert_eq!(lines, ["one", "two"]);
+        assert_eq!(saved, "one\r\ntwo\r\n");
+
+        let (format, lines, saved) = round_trip("one\r\ntwo\n");
+        assert_eq!(format.line_ending, LineEnding::Unix);
+        assert_eq!(lines, ["one\r", "two"]);
+        assert_eq!(saved, "one\r\ntwo\n");
+    }
+
+    #[test]
+    fn keeps_a_missing_final_newline_and_bom() {
+        let (format, lines, saved) = round_trip("\u{feff}one\ntwo");
+        assert!(format.bom && !format.final_newline);
+        assert_eq!(lines, ["one", "two"]);
+        assert_eq!(saved, "\u{feff}one\ntwo");
+        assert_eq!(format.to_string(), "utf-8 unix [BOM] [noeol]");
+
+        assert_eq!(round_trip("\n").1, [""]);
+        assert_eq!(round_trip("\n").2, "\n");
+        assert!(round_trip("").1.is_empty());
+    }
+
+    #[test]
+    fn converts_line_endings() {
+        let (mut format, rows) = FileFormat::detect("one\ntwo\n", Encoding::Utf8);
+        format.line_ending = LineEnding::Dos;
+        assert_eq!(format.join(rows.iter()).unwrap(), b"one\r\ntwo\r\n");
+        format.encoding = Encoding::Utf16Le;
+        format.bom = true;
+        assert_eq!(format.join(rows.iter()).unwrap(), b"\xff\xfeo\0n\0e\0\r\0\n\0t\0w\0o\0\r\0\n\0");
+    }
+}
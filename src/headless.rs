@@ -0,0 +1,293 @@
+use crate::args::{Args, File};
+use crate::config::Config;
+use crate::editor::Editor;
+use crate::screen::{Cell, Screen};
+use crate::syntax::Language;
+use crate::terminal::{Backend, Input};
+use crate::Terminal;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+use termion::event::Key;
+
+struct State {
+    screen: Screen,
+    width: usize,
+    height: usize,
+    /// Keys, resizes and waits to read in turn
+    inputs: VecDeque<Input>,
+    /// Bytes written since the screen was made
+    written: usize,
+}
+
+/// A backend drawing on a grid of cells in memory, with keys from a script rather than
+/// the keyboard. Clones share the screen, so a test can keep one to look at what the
+/// editor drew
+#[derive(Clone)]
+pub struct Headless {
+    state: Rc<RefCell<State>>,
+}
+
+impl Headless {
+    pub fn new(width: u16, height: u16) -> Self {
+        let (width, height) = (usize::from(width), usize::from(height));
+        Self {
+            state: Rc::new(RefCell::new(State {
+                screen: Screen::new(width, height),
+                width,
+                height,
+                inputs: VecDeque::new(),
+                written: 0,
+            })),
+        }
+    }
+    /// Queues keys for the editor to read
+    pub fn press(&self, keys: impl IntoIterator<Item = Key>) {
+        self.state.borrow_mut().inputs.extend(keys.into_iter().map(Input::Key));
+    }
+    /// Queues a key for each character of `text`, with `\x1b` for Esc and `\n` for Enter
+    pub fn type_text(&self, text: &str) {
+        self.press(text.chars().map(|c| if c == '\x1b' { Key::Esc } else { Key::Char(c) }));
+    }
+    /// Queues a wait with no key pressed, as if the user had stopped typing
+    pub fn idle(&self) {
+        self.state.borrow_mut().inputs.push_back(Input::Idle);
+    }
+    /// Changes the size of the screen, which the editor reads after any keys queued before
+    pub fn resize(&self, width: u16, height: u16) {
+        let mut state = self.state.borrow_mut();
+        state.width = usize::from(width);
+        state.height = usize::from(height);
+        state.screen.resize(usize::from(width), usize::from(height));
+        state.inputs.push_back(Input::Resize);
+    }
+    /// How many queued keys, resizes and waits are still to be read
+    pub fn pending(&self) -> usize {
+        self.state.borrow().inputs.len()
+    }
+    /// The text of each row of the screen, without trailing spaces
+    pub fn rows(&self) -> Vec<String> {
+        self.state.borrow().screen.rows()
+    }
+    pub fn cell(&self, x: usize, y: usize) -> Option<Cell> {
+        self.state.borrow().screen.cell(x, y).cloned()
+    }
+    /// Where the cursor is shown, or `None` if it is hidden
+    pub fn cursor(&self) -> Option<(usize, usize)> {
+        self.state.borrow().screen.cursor().map(|cursor| (cursor.x, cursor.y))
+    }
+    /// How many bytes the editor has written to the screen
+    pub fn written(&self) -> usize {
+        self.state.borrow().written
+    }
+}
+
+impl Backend for Headless {
+    fn size(&self) -> Result<(u16, u16), io::Error> {
+        let state = self.state.borrow();
+        let size = |cells: usize| u16::try_from(cells).unwrap_or(u16::MAX);
+        Ok((size(state.width), size(state.height)))
+    }
+    fn write(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        let mut state = self.state.borrow_mut();
+        state.written = state.written.saturating_add(bytes.len());
+        state.screen.write(&String::from_utf8_lossy(bytes));
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+    fn read_input(&mut self, _timeout: Duration) -> Result<Input, io::Error> {
+        self.state
+            .borrow_mut()
+            .inputs
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no more keys"))
+    }
+    fn restore(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// A file in a directory of its own, removed when dropped
+pub struct TempFile {
+    dir: PathBuf,
+    pub path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(test: &str, name: &str, contents: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("lecturn-headless-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        Self { dir, path }
+    }
+    /// Writes another file next to this one, returning its path
+    pub fn sibling(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// An editor started with `args` on a 50x8 screen, with the default config and
+/// languages, drawn once
+pub fn start(args: &Args) -> (Editor, Headless) {
+    let screen = Headless::new(50, 8);
+    let terminal = Terminal::new(Box::new(screen.clone())).unwrap();
+    let mut editor = Editor::with_terminal(args, Config::default(), Language::built_in(), Some(terminal));
+    editor.start();
+    editor.refresh_screen(true).unwrap();
+    (editor, screen)
+}
+
+/// An editor with `path` open
+pub fn open(path: &Path) -> (Editor, Headless) {
+    start(&Args {
+        files: vec![File { name: path.to_string_lossy().into_owned(), jump: None }],
+        ..Args::default()
+    })
+}
+
+/// Types `keys` into the editor, redrawing after each command as `Editor::run` does
+pub fn play(editor: &mut Editor, screen: &Headless, keys: &str) {
+    screen.type_text(keys);
+    while screen.pending() > 0 && !editor.should_quit {
+        editor.step().unwrap();
+        editor.refresh_screen(true).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The screen's rows, with `<file>` for the file's name in the status line, which is
+    /// cut short and differs from run to run
+    fn snapshot(screen: &Headless, file: &TempFile) -> Vec<String> {
+        let name: String = file.path.to_string_lossy().chars().take(20).collect();
+        screen.rows().into_iter().map(|row| row.replacen(&name, "<file>", 1)).collect()
+    }
+
+    #[test]
+    fn draws_an_opened_file() {
+        let file = TempFile::new("open", "notes.txt", "one\ntwo\nthree\n");
+        let (editor, screen) = open(&file.path);
+        assert_eq!(
+            snapshot(&screen, &file),
+            ["1 one", "2 two", "3 three", "", "", "", "<file>      no ft | utf-8 unix | 1,1", ""]
+        );
+        assert_eq!(screen.cursor(), Some((2, 0)));
+        let theme = &editor.config.theme;
+        assert_eq!(screen.cell(0, 0).map(|cell| cell.fg), Some(Some(theme.line_number)));
+        assert_eq!(screen.cell(49, 6).map(|cell| cell.bg), Some(Some(theme.status_bg)));
+    }
+
+    #[test]
+    fn edits_text() {
+        let file = TempFile::new("edit", "notes.txt", "one\ntwo\nthree\n");
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, "jiand ");
+        assert_eq!(
+            snapshot(&screen, &file),
+            ["1 one", "2 and two", "3 three", "", "", "", "<file> [+]  no ft | utf-8 unix | 2,5", "-- INSERT --"]
+        );
+        play(&mut editor, &screen, "\x1bGdd");
+        assert_eq!(
+            snapshot(&screen, &file),
+            ["1 one", "2 and two", "", "", "", "", "<file> [+]  no ft | utf-8 unix | 2,1", ""]
+        );
+        assert_eq!(screen.cursor(), Some((2, 1)));
+    }
+
+    #[test]
+    fn searches() {
+        let file = TempFile::new("search", "notes.txt", "one\ntwo\nthree\n");
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, "/t\n");
+        assert_eq!(screen.cursor(), Some((2, 1)));
+        // N moves on to the next match, and Enter leaves search mode
+        play(&mut editor, &screen, "/t\nN\n");
+        assert_eq!(screen.cursor(), Some((2, 2)));
+        play(&mut editor, &screen, "/four\n");
+        assert_eq!(
+            snapshot(&screen, &file),
+            ["1 one", "2 two", "3 three", "", "", "", "<file>      no ft | utf-8 unix | 3,1", "No results found"]
+        );
+    }
+
+    #[test]
+    fn redraws_only_what_changed() {
+        let file = TempFile::new("redraw", "notes.txt", "one\ntwo\nthree\n");
+        let (mut editor, screen) = open(&file.path);
+        let first = screen.written();
+        assert!(first > 200);
+        // Only the column in the status line changes, besides the cursor
+        play(&mut editor, &screen, "l");
+        let moved = screen.written().saturating_sub(first);
+        assert!(moved < 64, "{} bytes written", moved);
+        assert_eq!(screen.cursor(), Some((3, 0)));
+        assert_eq!(snapshot(&screen, &file).get(6).map(String::as_str), Some("<file>      no ft | utf-8 unix | 1,2"));
+    }
+
+    #[test]
+    fn redraws_on_resize() {
+        let file = TempFile::new("resize", "notes.txt", "one\ntwo\nthree\nfour\nfive\nsix\n");
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, "jjjjj");
+        screen.resize(50, 5);
+        // Drawn again straight away, while still waiting for a key that never comes
+        assert!(editor.step().is_err());
+        assert_eq!(
+            snapshot(&screen, &file),
+            ["4 four", "5 five", "6 six", "<file>      no ft | utf-8 unix | 6,1", ""]
+        );
+        assert_eq!(screen.cursor(), Some((2, 2)));
+    }
+
+    #[test]
+    fn keeps_the_visual_selection_on_resize() {
+        let file = TempFile::new("resize-visual", "notes.txt", "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\n");
+        let other = file.sibling("other.txt", "alpha\nbeta\ngamma\n");
+        let (mut editor, screen) = open(&file.path);
+        screen.resize(50, 12);
+        play(&mut editor, &screen, &format!("G:sp {}\nVj", other.display()));
+        screen.resize(50, 10);
+        assert!(editor.step().is_err());
+        assert_eq!(screen.rows()[9], "-- VISUAL LINE --");
+        // The selection is still there to be marked, and the marks outlast another resize
+        play(&mut editor, &screen, "\x1b");
+        screen.resize(50, 8);
+        assert!(editor.step().is_err());
+        // The other window scrolls to keep its cursor on the last row in view
+        assert_eq!(snapshot(&screen, &file)[3..7], ["6 six", "7 seven", "8 eight", "<file>      no ft | utf-8 unix | 8,1"]);
+        play(&mut editor, &screen, ":'<,'>d\n");
+        assert_eq!(editor.document.text(), "gamma");
+    }
+
+    #[test]
+    fn saves() {
+        let file = TempFile::new("save", "notes.txt", "one\ntwo\nthree\n");
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, "ddp:w\n");
+        assert_eq!(
+            snapshot(&screen, &file),
+            ["1 two", "2 one", "3 three", "", "", "", "<file>      no ft | utf-8 unix | 2,1", "File saved successfully"]
+        );
+        assert_eq!(fs::read_to_string(&file.path).unwrap(), "two\none\nthree\n");
+        play(&mut editor, &screen, ":q\n");
+        assert!(editor.should_quit);
+    }
+}
@@ -0,0 +1,278 @@
+use std::convert::TryFrom;
+
+pub const BYTES_PER_LINE: usize = 16;
+/// Where the hex bytes start in a line, after the offset
+const BYTES_COLUMN: usize = 10;
+
+/// A document's bytes as shown and edited in hex mode, kept apart from its rows so that
+/// saving writes back exactly these bytes
+pub struct Hex {
+    bytes: Vec<u8>,
+    /// The byte the cursor is on
+    pub cursor: usize,
+    /// The cursor is on the low (second) digit of the byte rather than the high one
+    low_nibble: bool,
+    /// The last digit of the file has been typed over, so the cursor is past it
+    end_reached: bool,
+    /// The first line shown
+    pub scroll: usize,
+    /// Edited since hex mode was entered
+    changed: bool,
+    /// Edited since the last save
+    modified: bool,
+    /// The bytes last searched for, for `n` and `N`
+    pub last_search: Vec<u8>,
+}
+
+impl Hex {
+    #[must_use]
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            cursor: 0,
+            low_nibble: false,
+            end_reached: false,
+            scroll: 0,
+            changed: false,
+            modified: false,
+            last_search: vec![],
+        }
+    }
+    #[must_use] pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+    #[must_use] pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+    #[must_use] pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+    pub fn mark_saved(&mut self) {
+        self.modified = false;
+    }
+    /// How many lines the bytes take up, with at least one for an empty file
+    #[must_use]
+    pub fn lines(&self) -> usize {
+        self.bytes.len().div_ceil(BYTES_PER_LINE).max(1)
+    }
+    /// Line `line` as drawn: its offset, its bytes in hex and the same bytes as ASCII,
+    /// e.g. `00000010  48 65 6c 6c 6f ...  |Hello...|`
+    #[must_use]
+    pub fn render_line(&self, line: usize) -> Option<String> {
+        let start = line.checked_mul(BYTES_PER_LINE)?;
+        if start >= self.bytes.len() && line > 0 {
+            return None;
+        }
+        let bytes = self.bytes.get(start..).unwrap_or_default();
+        let bytes = bytes.get(..BYTES_PER_LINE).unwrap_or(bytes);
+        let mut hex = String::new();
+        for index in 0..BYTES_PER_LINE {
+            if Some(index) == BYTES_PER_LINE.checked_div(2) {
+                hex.push(' ');
+            }
+            match bytes.get(index) {
+                Some(byte) => hex.push_str(&format!("{:02x} ", byte)),
+                None => hex.push_str("   "),
+            }
+        }
+        let ascii: String = bytes
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { char::from(byte) } else { '.' })
+            .collect();
+        Some(format!("{:08x}  {} |{}|", start, hex, ascii))
+    }
+    /// The column of the cursor within its line
+    #[must_use]
+    pub fn cursor_column(&self) -> usize {
+        let index = self.cursor.checked_rem(BYTES_PER_LINE).unwrap_or(0);
+        let gap = usize::from(index >= BYTES_PER_LINE.checked_div(2).unwrap_or(0));
+        BYTES_COLUMN
+            .saturating_add(index.saturating_mul(3))
+            .saturating_add(gap)
+            .saturating_add(usize::from(self.low_nibble))
+            .saturating_add(usize::from(self.end_reached))
+    }
+    #[must_use]
+    pub fn cursor_line(&self) -> usize {
+        self.cursor.checked_div(BYTES_PER_LINE).unwrap_or(0)
+    }
+    /// Overwrites the digit under the cursor with `digit` (0-15) and moves on to the next
+    /// one. Returns `false` if there is no digit left to overwrite, at the end of the file
+    pub fn set_nibble(&mut self, digit: u8) -> bool {
+        let byte = match self.bytes.get_mut(self.cursor) {
+            Some(byte) if !self.end_reached => byte,
+            _ => return false,
+        };
+        *byte = if self.low_nibble {
+            (*byte & 0xf0) | (digit & 0x0f)
+        } else {
+            (*byte & 0x0f) | (digit << 4)
+        };
+        self.changed = true;
+        self.modified = true;
+        if !self.low_nibble {
+            self.low_nibble = true;
+        } else if self.cursor.saturating_add(1) < self.bytes.len() {
+            self.move_by(1);
+        } else {
+            self.end_reached = true;
+        }
+        true
+    }
+    /// Moves the cursor back one digit
+    pub fn move_left(&mut self) {
+        if self.end_reached {
+            self.end_reached = false;
+        } else if self.low_nibble {
+            self.low_nibble = false;
+        } else {
+            self.move_by(-1);
+        }
+    }
+    /// Moves the cursor `delta` bytes, staying within the file, to the high digit
+    pub fn move_by(&mut self, delta: isize) {
+        let last = self.bytes.len().saturating_sub(1);
+        self.cursor = if delta < 0 {
+            self.cursor.saturating_sub(delta.unsigned_abs())
+        } else {
+            self.cursor.saturating_add(delta.unsigned_abs()).min(last)
+        };
+        self.low_nibble = false;
+        self.end_reached = false;
+    }
+    /// Moves the cursor to byte `offset`
+    /// # Errors
+    /// Will return `Err` with a message if the file is not that long
+    pub fn goto(&mut self, offset: usize) -> Result<(), String> {
+        if offset >= self.bytes.len() && offset > 0 {
+            return Err(format!("Offset 0x{:x} is past the end (0x{:x} bytes)", offset, self.bytes.len()));
+        }
+        self.cursor = offset;
+        self.low_nibble = false;
+        self.end_reached = false;
+        Ok(())
+    }
+    /// The next occurrence of `pattern` after the cursor (or before it, if not `forward`),
+    /// wrapping around the ends of the file
+    #[must_use]
+    pub fn find(&self, pattern: &[u8], forward: bool) -> Option<usize> {
+        if pattern.is_empty() {
+            return None;
+        }
+        let starts: Vec<usize> = self
+            .bytes
+            .windows(pattern.len())
+            .enumerate()
+            .filter(|&(_, window)| window == pattern)
+            .map(|(start, _)| start)
+            .collect();
+        if forward {
+            starts.iter().find(|&&start| start > self.cursor).or_else(|| starts.first()).copied()
+        } else {
+            starts.iter().rev().find(|&&start| start < self.cursor).or_else(|| starts.last()).copied()
+        }
+    }
+    /// Scrolls so the cursor is within `height` lines from the top
+    pub fn scroll_to_cursor(&mut self, height: usize) {
+        let line = self.cursor_line();
+        if line < self.scroll {
+            self.scroll = line;
+        } else if line >= self.scroll.saturating_add(height) {
+            self.scroll = line.saturating_sub(height.saturating_sub(1));
+        }
+    }
+}
+
+/// Parses a byte offset in hex (`0x1f00`) or decimal (`7936`)
+/// # Errors
+/// Will return `Err` with a message if it is neither
+pub fn parse_offset(text: &str) -> Result<usize, String> {
+    let text = text.trim();
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid offset {}", text))
+}
+
+/// Parses the bytes to search for: hex digits, optionally separated by spaces
+/// (`de ad be ef`), or text in double quotes (`"ELF"`)
+/// # Errors
+/// Will return `Err` with a message if it is neither
+pub fn parse_pattern(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim();
+    if let Some(quoted) = text.strip_prefix('"') {
+        let quoted = quoted.strip_suffix('"').unwrap_or(quoted);
+        return if quoted.is_empty() { Err("Empty pattern".to_owned()) } else { Ok(quoted.as_bytes().to_vec()) };
+    }
+    let digits: Vec<u8> = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).and_then(|digit| u8::try_from(digit).ok()))
+        .collect::<Option<_>>()
+        .ok_or_else(|| format!("Invalid byte pattern {}", text))?;
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err(format!("Invalid byte pattern {}", text));
+    }
+    Ok(digits.chunks_exact(2).map(|pair| pair.iter().fold(0, |byte, &digit| (byte << 4) | digit)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_offset_hex_and_ascii() {
+        let hex = Hex::new(b"Hello, world!\n\x00\xff\x7fA".to_vec());
+        assert_eq!(
+            hex.render_line(0).unwrap(),
+            "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 ff  |Hello, world!...|"
+        );
+        assert_eq!(
+            hex.render_line(1).unwrap(),
+            "00000010  7f 41                                             |.A|"
+        );
+        assert!(hex.render_line(2).is_none());
+        assert!(Hex::new(vec![]).render_line(0).is_some());
+    }
+
+    #[test]
+    fn overwrites_nibbles() {
+        let mut hex = Hex::new(vec![0x00, 0x11, 0x22]);
+        assert!(hex.set_nibble(0xa) && hex.set_nibble(0xb) && hex.set_nibble(0xc));
+        assert_eq!(hex.bytes(), [0xab, 0xc1, 0x22]);
+        assert_eq!((hex.cursor, hex.low_nibble), (1, true));
+        assert_eq!(hex.cursor_column(), 14);
+        hex.move_by(10);
+        assert!(hex.set_nibble(0xf) && hex.set_nibble(0xf));
+        // Typing stops at the end of the file, with the cursor past the last digit
+        assert!(!hex.set_nibble(0xe));
+        assert_eq!(hex.bytes(), [0xab, 0xc1, 0xff]);
+        assert_eq!((hex.cursor, hex.cursor_column()), (2, 18));
+        hex.move_left();
+        assert!(hex.set_nibble(0xe));
+        assert_eq!(hex.bytes(), [0xab, 0xc1, 0xfe]);
+        hex.move_left();
+        hex.move_left();
+        assert_eq!((hex.cursor, hex.low_nibble), (2, false));
+        hex.move_left();
+        assert_eq!((hex.cursor, hex.low_nibble), (1, false));
+        assert!(hex.is_modified());
+        assert!(!Hex::new(vec![]).set_nibble(1));
+    }
+
+    #[test]
+    fn finds_byte_patterns_and_offsets() {
+        let mut hex = Hex::new(b"\x7fELF..ELF".to_vec());
+        let pattern = parse_pattern("45 4c46").unwrap();
+        assert_eq!(hex.find(&pattern, true), Some(1));
+        hex.goto(1).unwrap();
+        assert_eq!(hex.find(&pattern, true), Some(6));
+        assert_eq!(hex.find(&pattern, false), Some(6));
+        assert_eq!(hex.find(&parse_pattern("\"ELF\"").unwrap(), true), Some(6));
+        assert!(parse_pattern("4").is_err() && parse_pattern("zz").is_err());
+        assert_eq!(parse_offset("0x1f"), Ok(31));
+        assert_eq!(parse_offset("31"), Ok(31));
+        assert!(hex.goto(0x20).is_err());
+    }
+}
@@ -1,23 +1,37 @@
+use crate::config::Theme;
 use termion::color::{Rgb};
 
-#[derive(PartialEq, Clone)]
+#[derive(PartialEq, Clone, Copy)]
 pub enum Type {
     SearchSelected,
     Search,
+    Selection,
+    Keyword,
+    DataType,
+    String,
+    Comment,
+    Number,
     None,
 }
 
+/// Colours to draw in, with `None` for the terminal's own
 pub struct Highlight {
-    pub bg_color: Rgb,
-    pub fg_color: Rgb,
+    pub bg_color: Option<Rgb>,
+    pub fg_color: Option<Rgb>,
 }
 
 impl Type {
-    pub fn to_color(&self) -> Highlight {
+    pub fn to_color(&self, theme: &Theme) -> Highlight {
         match *self {
-            Type::Search => Highlight { fg_color: Rgb(0, 0, 0), bg_color: Rgb(249, 241, 165) },
-            Type::SearchSelected => Highlight { fg_color: Rgb(0, 0, 0), bg_color: Rgb(255, 255, 255) },
-            Type::None => Highlight { fg_color: Rgb(255, 255, 255), bg_color: Rgb(0, 0, 0) },
+            Type::Search => Highlight { fg_color: Some(theme.search_fg), bg_color: Some(theme.search_bg) },
+            Type::SearchSelected => Highlight { fg_color: Some(theme.search_selected_fg), bg_color: Some(theme.search_selected_bg) },
+            Type::Selection => Highlight { fg_color: Some(theme.selection_fg), bg_color: Some(theme.selection_bg) },
+            Type::Keyword => Highlight { fg_color: Some(theme.keyword), bg_color: theme.text_bg },
+            Type::DataType => Highlight { fg_color: Some(theme.data_type), bg_color: theme.text_bg },
+            Type::String => Highlight { fg_color: Some(theme.string), bg_color: theme.text_bg },
+            Type::Comment => Highlight { fg_color: Some(theme.comment), bg_color: theme.text_bg },
+            Type::Number => Highlight { fg_color: Some(theme.number), bg_color: theme.text_bg },
+            Type::None => Highlight { fg_color: theme.text_fg, bg_color: theme.text_bg },
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,356 @@
+use crate::Position;
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+
+const UNDO_FILE_HEADER: &str = "lecturn-undo 1";
+
+#[derive(Clone)]
+pub enum Change {
+    Insert { at: Position, text: String },
+    Delete { at: Position, text: String },
+}
+
+impl Change {
+    #[must_use]
+    pub fn inverse(&self) -> Self {
+        match self {
+            Change::Insert { at, text } => Change::Delete { at: *at, text: text.clone() },
+            Change::Delete { at, text } => Change::Insert { at: *at, text: text.clone() },
+        }
+    }
+    #[must_use]
+    pub fn position(&self) -> Position {
+        match self {
+            Change::Insert { at, .. } | Change::Delete { at, .. } => *at,
+        }
+    }
+}
+
+struct Node {
+    parent: usize,
+    last_child: Option<usize>,
+    changes: Vec<Change>,
+}
+
+/// An undo tree. Node 0 is the unmodified document; every other node holds
+/// the group of changes that turned its parent into it.
+pub struct History {
+    nodes: Vec<Node>,
+    current: usize,
+    saved: usize,
+    pending: Vec<Change>,
+}
+
+impl History {
+    #[must_use]
+    pub fn default() -> Self {
+        Self {
+            nodes: vec![Node { parent: 0, last_child: None, changes: vec![] }],
+            current: 0,
+            saved: 0,
+            pending: vec![],
+        }
+    }
+    pub fn record(&mut self, change: Change) {
+        self.pending.push(change);
+    }
+    /// Seals the pending changes into a single undo step
+    pub fn commit(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            parent: self.current,
+            last_child: None,
+            changes: std::mem::take(&mut self.pending),
+        });
+        if let Some(parent) = self.nodes.get_mut(self.current) {
+            parent.last_child = Some(index);
+        }
+        self.current = index;
+    }
+    /// Returns the changes to apply (in order) to step back one change
+    pub fn undo(&mut self) -> Option<Vec<Change>> {
+        self.commit();
+        if self.current == 0 {
+            return None;
+        }
+        let changes = self.undo_node();
+        Some(changes)
+    }
+    /// Returns the changes to apply (in order) to step forward one change
+    pub fn redo(&mut self) -> Option<Vec<Change>> {
+        self.commit();
+        let child = self.nodes.get(self.current)?.last_child?;
+        self.current = child;
+        self.nodes.get(child).map(|node| node.changes.clone())
+    }
+    /// Returns the changes to apply (in order) to reach the state after change `target`,
+    /// walking up and down the tree through the closest common ancestor
+    pub fn jump(&mut self, target: usize) -> Option<Vec<Change>> {
+        self.commit();
+        if target >= self.nodes.len() {
+            return None;
+        }
+        let target_path = self.path_to_root(target);
+        let mut changes = vec![];
+        while !target_path.contains(&self.current) {
+            changes.append(&mut self.undo_node());
+        }
+        let ancestor = self.current;
+        for &node in target_path.iter().rev().skip_while(|&&node| node != ancestor).skip(1) {
+            if let Some(parent) = self.nodes.get_mut(self.current) {
+                parent.last_child = Some(node);
+            }
+            self.current = node;
+            if let Some(node) = self.nodes.get(node) {
+                changes.extend(node.changes.iter().cloned());
+            }
+        }
+        Some(changes)
+    }
+    #[must_use] pub fn current(&self) -> usize {
+        self.current
+    }
+    #[must_use] pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+    #[must_use] pub fn is_empty(&self) -> bool {
+        self.nodes.len() == 1 && self.pending.is_empty()
+    }
+    pub fn mark_saved(&mut self) {
+        self.commit();
+        self.saved = self.current;
+    }
+    #[must_use] pub fn is_saved(&self) -> bool {
+        self.pending.is_empty() && self.saved == self.current
+    }
+    fn undo_node(&mut self) -> Vec<Change> {
+        let current = self.current;
+        let mut changes = vec![];
+        if let Some(node) = self.nodes.get(current) {
+            changes = node.changes.iter().rev().map(Change::inverse).collect();
+            self.current = node.parent;
+        }
+        if let Some(parent) = self.nodes.get_mut(self.current) {
+            parent.last_child = Some(current);
+        }
+        changes
+    }
+    fn path_to_root(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while node != 0 {
+            node = self.nodes.get(node).map_or(0, |n| n.parent);
+            path.push(node);
+        }
+        path
+    }
+
+    /// # Errors
+    /// Will return `Err` if the undo file cannot be written
+    pub fn write_to(&mut self, path: &str, content_hash: u64) -> Result<(), Error> {
+        self.commit();
+        let mut file = fs::File::create(path)?;
+        writeln!(file, "{}", UNDO_FILE_HEADER)?;
+        writeln!(file, "hash {:016x}", content_hash)?;
+        writeln!(file, "current {}", self.current)?;
+        for node in self.nodes.iter().skip(1) {
+            writeln!(file, "node {}", node.parent)?;
+            for change in &node.changes {
+                let (kind, at, text) = match change {
+                    Change::Insert { at, text } => ('i', at, text),
+                    Change::Delete { at, text } => ('d', at, text),
+                };
+                writeln!(file, "{} {} {} {}", kind, at.x, at.y, escape(text))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads an undo file, only accepting it when it was written for a document
+    /// with the same contents
+    /// # Errors
+    /// Will return `Err` if the file cannot be read, is malformed or belongs to different contents
+    pub fn read_from(path: &str, content_hash: u64) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)?;
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid undo file");
+        let mut lines = contents.lines();
+        if lines.next() != Some(UNDO_FILE_HEADER) {
+            return Err(invalid());
+        }
+        let hash = lines.next().and_then(|l| l.strip_prefix("hash ")).ok_or_else(invalid)?;
+        if u64::from_str_radix(hash, 16).map_err(|_| invalid())? != content_hash {
+            return Err(Error::new(ErrorKind::InvalidData, "undo file does not match the file contents"));
+        }
+        let current = lines
+            .next()
+            .and_then(|l| l.strip_prefix("current "))
+            .and_then(|n| n.parse::<usize>().ok())
+            .ok_or_else(invalid)?;
+
+        let mut history = Self::default();
+        for line in lines {
+            let mut parts = line.splitn(4, ' ');
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some("node"), Some(parent), None, None) => {
+                    let parent = parent.parse::<usize>().map_err(|_| invalid())?;
+                    let index = history.nodes.len();
+                    history.nodes.get_mut(parent).ok_or_else(invalid)?.last_child = Some(index);
+                    history.nodes.push(Node { parent, last_child: None, changes: vec![] });
+                }
+                (Some(kind), Some(x), Some(y), Some(text)) => {
+                    let at = Position {
+                        x: x.parse().map_err(|_| invalid())?,
+                        y: y.parse().map_err(|_| invalid())?,
+                    };
+                    let text = unescape(text);
+                    let change = match kind {
+                        "i" => Change::Insert { at, text },
+                        "d" => Change::Delete { at, text },
+                        _ => return Err(invalid()),
+                    };
+                    if history.nodes.len() == 1 {
+                        return Err(invalid());
+                    }
+                    if let Some(node) = history.nodes.last_mut() {
+                        node.changes.push(change);
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        if current >= history.nodes.len() {
+            return Err(invalid());
+        }
+        history.current = current;
+        history.saved = current;
+        Ok(history)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn at(x: usize, y: usize) -> Position {
+        Position { x, y }
+    }
+
+    fn insert(x: usize, y: usize, text: &str) -> Change {
+        Change::Insert { at: at(x, y), text: text.to_owned() }
+    }
+
+    /// Each change as its kind, position and text, to compare them
+    fn describe(changes: &[Change]) -> Vec<(char, usize, usize, String)> {
+        changes
+            .iter()
+            .map(|change| match change {
+                Change::Insert { at, text } => ('i', at.x, at.y, text.clone()),
+                Change::Delete { at, text } => ('d', at.x, at.y, text.clone()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn groups_changes_into_steps() {
+        let mut history = History::default();
+        assert!(history.is_empty());
+        history.record(insert(0, 0, "a"));
+        history.record(insert(1, 0, "b"));
+        history.commit();
+        history.record(insert(2, 0, "c"));
+        // Pending changes are sealed before undoing
+        assert_eq!(describe(&history.undo().unwrap()), [('d', 2, 0, "c".to_owned())]);
+        assert_eq!(
+            describe(&history.undo().unwrap()),
+            [('d', 1, 0, "b".to_owned()), ('d', 0, 0, "a".to_owned())]
+        );
+        assert!(history.undo().is_none());
+        assert_eq!(
+            describe(&history.redo().unwrap()),
+            [('i', 0, 0, "a".to_owned()), ('i', 1, 0, "b".to_owned())]
+        );
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.current(), 1);
+    }
+
+    #[test]
+    fn jumps_between_branches() {
+        let mut history = History::default();
+        history.record(insert(0, 0, "a"));
+        history.commit();
+        history.undo();
+        history.record(insert(0, 0, "b"));
+        history.commit();
+        assert_eq!(
+            describe(&history.jump(1).unwrap()),
+            [('d', 0, 0, "b".to_owned()), ('i', 0, 0, "a".to_owned())]
+        );
+        assert_eq!(history.current(), 1);
+        assert!(history.jump(3).is_none());
+    }
+
+    #[test]
+    fn tracks_the_saved_state() {
+        let mut history = History::default();
+        assert!(history.is_saved());
+        history.record(insert(0, 0, "a"));
+        assert!(!history.is_saved());
+        history.mark_saved();
+        assert!(history.is_saved());
+        history.undo();
+        assert!(!history.is_saved());
+        history.redo();
+        assert!(history.is_saved());
+    }
+
+    #[test]
+    fn round_trips_through_an_undo_file() {
+        let path = env::temp_dir().join(format!("lecturn-history-{}", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let mut history = History::default();
+        history.record(insert(0, 0, "one\\two\nthree\r\n"));
+        history.record(Change::Delete { at: at(3, 1), text: "trailing\r".to_owned() });
+        history.commit();
+        history.record(insert(0, 2, "x"));
+        history.commit();
+        history.undo();
+        history.write_to(&path, 42).unwrap();
+
+        assert_eq!(History::read_from(&path, 7).err().map(|error| error.kind()), Some(ErrorKind::InvalidData));
+        let mut read = History::read_from(&path, 42).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(read.current(), 1);
+        assert_eq!(read.len(), 3);
+        assert!(read.is_saved());
+        assert_eq!(
+            describe(&read.undo().unwrap()),
+            [('i', 3, 1, "trailing\r".to_owned()), ('d', 0, 0, "one\\two\nthree\r\n".to_owned())]
+        );
+        assert_eq!(describe(&read.jump(2).unwrap()).len(), 3);
+    }
+}
@@ -0,0 +1,57 @@
+#![warn(clippy::all, clippy::pedantic, clippy::restriction)]
+#![allow(
+    clippy::missing_docs_in_private_items,
+    clippy::implicit_return,
+    clippy::shadow_reuse,
+    clippy::print_stdout,
+    clippy::wildcard_enum_match_arm,
+    clippy::else_if_without_else
+)]
+mod document;
+mod editor;
+mod row;
+mod terminal;
+pub mod args;
+mod buffer;
+mod commands;
+mod config;
+mod diff;
+mod encoding;
+mod fileformat;
+#[cfg(test)]
+mod headless;
+mod hex;
+mod highlighting;
+mod history;
+mod motion;
+mod normal;
+mod registers;
+mod rope;
+mod save;
+mod screen;
+mod search;
+mod selection;
+mod substitute;
+mod swap;
+mod syntax;
+mod textobject;
+mod window;
+
+use std::env;
+use std::path::PathBuf;
+pub use document::Document;
+pub use editor::Editor;
+pub use editor::Position;
+pub use row::Row;
+pub use terminal::Terminal;
+pub use commands::Commands;
+
+/// `$XDG_CONFIG_HOME/lecturn`, falling back to `~/.config/lecturn`
+#[must_use]
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("lecturn"))
+}
@@ -7,30 +7,35 @@
     clippy::wildcard_enum_match_arm,
     clippy::else_if_without_else
 )]
-mod document;
-mod editor;
-mod row;
-mod terminal;
-mod commands;
-mod highlighting;
 
-use termion::color;
-pub use document::Document;
-use editor::Editor;
-pub use editor::Position;
-pub use row::Row;
-pub use terminal::Terminal;
-pub use commands::Commands;
-
-pub const ERROR_COLOR: color::Rgb = color::Rgb(197, 15, 31);
+use std::env;
+use std::io::{self, Write};
+use std::process;
+use lecturn::args::{self, Parsed};
+use lecturn::Editor;
 
+#[allow(clippy::exit, clippy::print_stderr)]
 fn main() {
-    Editor::default().run();
+    let args = match args::parse(env::args().skip(1)) {
+        Ok(Parsed::Edit(args)) => args,
+        Ok(Parsed::Help) => {
+            // Fine if it goes to a pipe that closes early, as with `| head`
+            let _ = writeln!(io::stdout(), "{}", args::USAGE);
+            return;
+        }
+        Ok(Parsed::Version) => {
+            println!("lecturn {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(message) => {
+            eprintln!("lecturn: {}\nTry 'lecturn --help' for more information.", message);
+            process::exit(2);
+        }
+    };
+    Editor::new(&args).run();
 }
 
 /*
 TODO (GENERAL IDEAS):
-    - Add customisable colors
-    - Add customisable syntax highlighting
     - Add more navigation features (goto, etc)
  */
\ No newline at end of file
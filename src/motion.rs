@@ -0,0 +1,323 @@
+use crate::syntax::is_word;
+use crate::textobject::TextObject;
+use crate::{Document, Position, Row};
+
+/// How much text a motion covers when an operator is applied over it
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum MotionKind {
+    /// Up to, but not including, the target
+    Exclusive,
+    /// Up to and including the target
+    Inclusive,
+    /// Every row from the cursor to the target
+    Linewise,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    /// `w`, or `W` when `big` (a WORD is any run of non-blank characters)
+    WordForward { big: bool },
+    /// `b` / `B`
+    WordBackward { big: bool },
+    /// `e` / `E`
+    WordEnd { big: bool },
+    /// `0`
+    LineStart,
+    /// `^`
+    FirstNonBlank,
+    /// `$`
+    LineEnd,
+    /// `gg`
+    FirstLine,
+    /// `G`
+    LastLine,
+    /// `f{c}`
+    FindForward(char),
+    /// `t{c}`
+    TillForward(char),
+    /// `%`
+    MatchingPair,
+    /// The rows from the cursor down, from an operator repeated as in `dd` or `>>`
+    Lines,
+    /// A text object such as `iw` or `a(`, which picks a range instead of moving
+    Object(TextObject),
+}
+
+impl Motion {
+    /// The motion for a single key, if it is one
+    #[must_use]
+    pub fn from_key(c: char) -> Option<Self> {
+        let motion = match c {
+            'h' => Motion::Left,
+            'l' => Motion::Right,
+            'k' => Motion::Up,
+            'j' => Motion::Down,
+            'w' | 'W' => Motion::WordForward { big: c == 'W' },
+            'b' | 'B' => Motion::WordBackward { big: c == 'B' },
+            'e' | 'E' => Motion::WordEnd { big: c == 'E' },
+            '0' => Motion::LineStart,
+            '^' => Motion::FirstNonBlank,
+            '$' => Motion::LineEnd,
+            'G' => Motion::LastLine,
+            '%' => Motion::MatchingPair,
+            _ => return None,
+        };
+        Some(motion)
+    }
+    #[must_use]
+    pub fn kind(self) -> MotionKind {
+        match self {
+            Motion::Up | Motion::Down | Motion::FirstLine | Motion::LastLine | Motion::Lines => MotionKind::Linewise,
+            Motion::WordEnd { .. }
+            | Motion::LineEnd
+            | Motion::FindForward(_)
+            | Motion::TillForward(_)
+            | Motion::MatchingPair => MotionKind::Inclusive,
+            _ => MotionKind::Exclusive,
+        }
+    }
+    /// Where the motion takes the cursor from `at`, repeated `count` times (for `G` and `gg`,
+    /// the row to go to). `None` if it cannot move, e.g. `f` without a match
+    #[must_use]
+    pub fn target(self, document: &Document, at: Position, count: Option<usize>) -> Option<Position> {
+        let times = count.unwrap_or(1).max(1);
+        let last_row = document.len().saturating_sub(1);
+        let target = match self {
+            Motion::Left => {
+                if at.x == 0 {
+                    return None;
+                }
+                Position { x: at.x.saturating_sub(times), y: at.y }
+            }
+            Motion::Right => {
+                let len = row_len(document, at.y);
+                if at.x >= len {
+                    return None;
+                }
+                Position { x: at.x.saturating_add(times).min(len), y: at.y }
+            }
+            Motion::Up => {
+                if at.y == 0 {
+                    return None;
+                }
+                let y = at.y.saturating_sub(times);
+                Position { x: at.x.min(row_len(document, y)), y }
+            }
+            Motion::Down => {
+                if at.y >= last_row {
+                    return None;
+                }
+                let y = at.y.saturating_add(times).min(last_row);
+                Position { x: at.x.min(row_len(document, y)), y }
+            }
+            Motion::WordForward { big } => repeat(times, at, |at| word_forward(document, at, big)),
+            Motion::WordBackward { big } => repeat(times, at, |at| word_backward(document, at, big)),
+            Motion::WordEnd { big } => repeat(times, at, |at| word_end(document, at, big)),
+            Motion::LineStart => Position { x: 0, y: at.y },
+            Motion::FirstNonBlank => Position { x: first_non_blank(document.row(at.y)?), y: at.y },
+            Motion::LineEnd => {
+                let y = at.y.saturating_add(times.saturating_sub(1)).min(last_row);
+                Position { x: row_len(document, y).saturating_sub(1), y }
+            }
+            Motion::FirstLine | Motion::LastLine => {
+                let default = if self == Motion::FirstLine { 0 } else { last_row };
+                let y = count.map_or(default, |line| line.saturating_sub(1)).min(last_row);
+                Position { x: first_non_blank(document.row(y)?), y }
+            }
+            Motion::FindForward(c) => find_forward(document.row(at.y)?, at, c, times)?,
+            Motion::TillForward(c) => {
+                let found = find_forward(document.row(at.y)?, at, c, times)?;
+                Position { x: found.x.saturating_sub(1), y: at.y }
+            }
+            Motion::MatchingPair => matching_pair(document, at)?,
+            Motion::Lines => Position { x: at.x, y: at.y.saturating_add(times.saturating_sub(1)).min(last_row) },
+            Motion::Object(object) => object.range(document, at, count)?.start,
+        };
+        Some(target)
+    }
+}
+
+fn repeat(times: usize, at: Position, step: impl Fn(Position) -> Position) -> Position {
+    (0..times).fold(at, |at, _| step(at))
+}
+
+pub fn row_len(document: &Document, y: usize) -> usize {
+    document.row(y).map_or(0, Row::len)
+}
+
+/// The index of the first grapheme of `row` that is not whitespace
+#[must_use]
+pub fn first_non_blank(row: &Row) -> usize {
+    (0..row.len())
+        .find(|&x| row.grapheme(x).is_some_and(|grapheme| !is_blank(grapheme)))
+        .unwrap_or(0)
+}
+
+fn is_blank(grapheme: &str) -> bool {
+    grapheme.chars().all(char::is_whitespace)
+}
+
+/// What a position holds as far as word motions are concerned
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Class {
+    /// Whitespace, or the line break at the end of a non-empty row
+    Blank,
+    /// An empty row, which counts as a word of its own
+    EmptyLine,
+    Word,
+    Punctuation,
+}
+
+/// The class of the grapheme at `at`; with `big`, every non-blank grapheme is part of a word
+#[must_use]
+pub fn class(document: &Document, at: Position, big: bool) -> Class {
+    match document.row(at.y) {
+        Some(row) if row.is_empty() => Class::EmptyLine,
+        Some(row) => match row.grapheme(at.x) {
+            Some(grapheme) if is_blank(grapheme) => Class::Blank,
+            Some(grapheme) if big || is_word(grapheme) => Class::Word,
+            Some(_) => Class::Punctuation,
+            None => Class::Blank,
+        },
+        None => Class::Blank,
+    }
+}
+
+/// The position after `at`, stepping onto the line break at the end of each row
+#[must_use]
+pub fn next(document: &Document, at: Position) -> Option<Position> {
+    if at.x < row_len(document, at.y) {
+        Some(Position { x: at.x.saturating_add(1), y: at.y })
+    } else if at.y.saturating_add(1) < document.len() {
+        Some(Position { x: 0, y: at.y.saturating_add(1) })
+    } else {
+        None
+    }
+}
+
+/// The position before `at`, stepping onto the line break at the end of each row
+#[must_use]
+pub fn previous(document: &Document, at: Position) -> Option<Position> {
+    if at.x > 0 {
+        Some(Position { x: at.x.saturating_sub(1), y: at.y })
+    } else if at.y > 0 {
+        let y = at.y.saturating_sub(1);
+        Some(Position { x: row_len(document, y), y })
+    } else {
+        None
+    }
+}
+
+fn word_forward(document: &Document, mut at: Position, big: bool) -> Position {
+    let start = class(document, at, big);
+    if start == Class::EmptyLine {
+        at = match next(document, at) {
+            Some(next) => next,
+            None => return at,
+        };
+    }
+    while start != Class::Blank && class(document, at, big) == start {
+        at = match next(document, at) {
+            Some(next) => next,
+            None => return at,
+        };
+    }
+    while class(document, at, big) == Class::Blank {
+        at = match next(document, at) {
+            Some(next) => next,
+            None => return at,
+        };
+    }
+    at
+}
+
+fn word_end(document: &Document, at: Position, big: bool) -> Position {
+    let mut at = match next(document, at) {
+        Some(next) => next,
+        None => return at,
+    };
+    while matches!(class(document, at, big), Class::Blank | Class::EmptyLine) {
+        at = match next(document, at) {
+            Some(next) => next,
+            None => return at,
+        };
+    }
+    let word = class(document, at, big);
+    while let Some(next) = next(document, at).filter(|&next| class(document, next, big) == word) {
+        at = next;
+    }
+    at
+}
+
+fn word_backward(document: &Document, at: Position, big: bool) -> Position {
+    let mut at = match previous(document, at) {
+        Some(previous) => previous,
+        None => return at,
+    };
+    while class(document, at, big) == Class::Blank {
+        at = match previous(document, at) {
+            Some(previous) => previous,
+            None => return at,
+        };
+    }
+    let word = class(document, at, big);
+    if word == Class::EmptyLine {
+        return at;
+    }
+    while let Some(previous) = previous(document, at).filter(|&previous| class(document, previous, big) == word) {
+        at = previous;
+    }
+    at
+}
+
+/// Whether `at` is on the last grapheme of a word, so `e` would move on to the next one
+#[must_use]
+pub fn is_word_end(document: &Document, at: Position, big: bool) -> bool {
+    let word = class(document, at, big);
+    !matches!(word, Class::Blank | Class::EmptyLine)
+        && next(document, at).is_none_or(|next| class(document, next, big) != word)
+}
+
+fn find_forward(row: &Row, at: Position, c: char, times: usize) -> Option<Position> {
+    let target = c.to_string();
+    let x = (at.x.saturating_add(1)..row.len())
+        .filter(|&x| row.grapheme(x) == Some(target.as_str()))
+        .nth(times.saturating_sub(1))?;
+    Some(Position { x, y: at.y })
+}
+
+const PAIRS: [(&str, &str); 3] = [("(", ")"), ("[", "]"), ("{", "}")];
+
+/// The bracket matching the first one at or after `at` on its row, searching across rows
+/// and skipping nested pairs
+fn matching_pair(document: &Document, at: Position) -> Option<Position> {
+    let row = document.row(at.y)?;
+    let (x, grapheme) = (at.x..row.len()).find_map(|x| {
+        let grapheme = row.grapheme(x)?;
+        PAIRS.iter().any(|&(open, close)| grapheme == open || grapheme == close).then_some((x, grapheme))
+    })?;
+    let (&(open, close), forward) = PAIRS
+        .iter()
+        .find_map(|pair| if pair.0 == grapheme { Some((pair, true)) } else if pair.1 == grapheme { Some((pair, false)) } else { None })?;
+    let mut position = Position { x, y: at.y };
+    let mut depth = 0_usize;
+    loop {
+        position = if forward { next(document, position)? } else { previous(document, position)? };
+        let grapheme = match document.row(position.y).and_then(|row| row.grapheme(position.x)) {
+            Some(grapheme) => grapheme,
+            None => continue,
+        };
+        if grapheme == (if forward { open } else { close }) {
+            depth = depth.saturating_add(1);
+        } else if grapheme == (if forward { close } else { open }) {
+            if depth == 0 {
+                return Some(position);
+            }
+            depth = depth.saturating_sub(1);
+        }
+    }
+}
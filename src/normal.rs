@@ -0,0 +1,439 @@
+use crate::config::Config;
+use crate::motion::{self, Motion, MotionKind};
+use crate::selection::{Case, Selection, SelectionKind, Yank};
+use crate::textobject::TextObject;
+use crate::{Document, Position};
+use std::convert::TryFrom;
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Outdent,
+    Lowercase,
+    Uppercase,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Action {
+    /// Move the cursor, e.g. `5j`
+    Move(Motion, Option<usize>),
+    /// Apply an operator over a motion, e.g. `3dw`
+    Operate(Operator, Motion, Option<usize>),
+    /// A key that is not part of the grammar, with any count typed before it
+    Other(char, Option<usize>),
+}
+
+#[derive(PartialEq, Eq)]
+pub enum Step {
+    /// More keys are needed, e.g. after `d` or `2`
+    Pending,
+    Done(Action),
+    /// The keys so far do not form a command
+    Invalid,
+}
+
+/// Parses Command mode keys of the form `[count][operator][count]motion`, one key at a time
+pub struct Parser {
+    count: Option<usize>,
+    /// The pending operator and the count typed before it
+    operator: Option<(Operator, Option<usize>)>,
+    /// `g`, `f`, `t` or, after an operator, `i` or `a`, waiting for the key that completes it
+    prefix: Option<char>,
+    /// Treat operator keys as `Other`, for Visual mode where they act on the selection
+    motions_only: bool,
+}
+
+impl Parser {
+    #[must_use]
+    pub fn default() -> Self {
+        Self {
+            count: None,
+            operator: None,
+            prefix: None,
+            motions_only: false,
+        }
+    }
+    #[must_use]
+    pub fn motions_only() -> Self {
+        Self {
+            motions_only: true,
+            ..Self::default()
+        }
+    }
+    pub fn feed(&mut self, c: char) -> Step {
+        if let Some(prefix) = self.prefix.take() {
+            return match (prefix, c) {
+                ('f', _) => self.motion(Motion::FindForward(c)),
+                ('t', _) => self.motion(Motion::TillForward(c)),
+                ('g', 'g') => self.motion(Motion::FirstLine),
+                ('g', 'u') if !self.motions_only => self.operator(Operator::Lowercase),
+                ('g', 'U') if !self.motions_only => self.operator(Operator::Uppercase),
+                ('i' | 'a', _) => match TextObject::from_key(c, prefix == 'a') {
+                    Some(object) => self.motion(Motion::Object(object)),
+                    None => Step::Invalid,
+                },
+                _ => Step::Invalid,
+            };
+        }
+        match c {
+            '0'..='9' if c != '0' || self.count.is_some() => {
+                let digit = c.to_digit(10).and_then(|digit| usize::try_from(digit).ok()).unwrap_or(0);
+                self.count = Some(self.count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                Step::Pending
+            }
+            'f' | 't' | 'g' => {
+                self.prefix = Some(c);
+                Step::Pending
+            }
+            // Outside an operator (or Visual mode), `i` and `a` start Insert mode instead
+            'i' | 'a' if self.operator.is_some() || self.motions_only => {
+                self.prefix = Some(c);
+                Step::Pending
+            }
+            _ if self.motions_only => match Motion::from_key(c) {
+                Some(motion) => self.motion(motion),
+                None => Step::Done(Action::Other(c, self.count)),
+            },
+            'd' => self.operator(Operator::Delete),
+            'c' => self.operator(Operator::Change),
+            'y' => self.operator(Operator::Yank),
+            '>' => self.operator(Operator::Indent),
+            '<' => self.operator(Operator::Outdent),
+            // `guu` and `gUU` are short for `gugu` and `gUgU`
+            'u' if matches!(self.operator, Some((Operator::Lowercase, _))) => self.operator(Operator::Lowercase),
+            'U' if matches!(self.operator, Some((Operator::Uppercase, _))) => self.operator(Operator::Uppercase),
+            'x' | 'D' | 'C' | 'Y' if self.operator.is_none() => {
+                let (operator, motion) = match c {
+                    'x' => (Operator::Delete, Motion::Right),
+                    'D' => (Operator::Delete, Motion::LineEnd),
+                    'C' => (Operator::Change, Motion::LineEnd),
+                    _ => (Operator::Yank, Motion::Lines),
+                };
+                Step::Done(Action::Operate(operator, motion, self.count))
+            }
+            _ => match Motion::from_key(c) {
+                Some(motion) => self.motion(motion),
+                None if self.operator.is_some() => Step::Invalid,
+                None => Step::Done(Action::Other(c, self.count)),
+            },
+        }
+    }
+    fn operator(&mut self, operator: Operator) -> Step {
+        match self.operator.take() {
+            // An operator repeated (`dd`, `>>`) works on whole rows
+            Some((pending, count)) if pending == operator => {
+                Step::Done(Action::Operate(operator, Motion::Lines, multiply(count, self.count)))
+            }
+            Some(_) => Step::Invalid,
+            None => {
+                self.operator = Some((operator, self.count.take()));
+                Step::Pending
+            }
+        }
+    }
+    fn motion(&mut self, motion: Motion) -> Step {
+        match self.operator.take() {
+            Some((operator, count)) => Step::Done(Action::Operate(operator, motion, multiply(count, self.count))),
+            None => Step::Done(Action::Move(motion, self.count)),
+        }
+    }
+}
+
+/// Counts typed before and after an operator multiply, so `2d3w` deletes six words
+fn multiply(first: Option<usize>, second: Option<usize>) -> Option<usize> {
+    match (first, second) {
+        (None, None) => None,
+        _ => Some(first.unwrap_or(1).saturating_mul(second.unwrap_or(1))),
+    }
+}
+
+/// What applying an operator leaves for the editor to act on
+pub struct Outcome {
+    pub cursor: Position,
+    /// The text that was yanked or deleted, and whether it was deleted
+    pub yank: Option<(Yank, bool)>,
+    /// Whether to continue in Insert mode, as after `c`
+    pub insert: bool,
+}
+
+/// Applies `operator` from `at` over `motion`, or returns `None` if the motion goes nowhere
+pub fn operate(
+    document: &mut Document,
+    at: Position,
+    operator: Operator,
+    motion: Motion,
+    count: Option<usize>,
+    config: &Config,
+) -> Option<Outcome> {
+    let selection = motion_selection(document, at, operator, motion, count)?;
+    let (first, _) = selection.rows();
+    let mut outcome = Outcome {
+        cursor: selection.start(),
+        yank: None,
+        insert: false,
+    };
+    match operator {
+        Operator::Delete => {
+            let (yank, cursor) = selection.delete(document);
+            outcome.yank = Some((yank, true));
+            outcome.cursor = cursor;
+        }
+        Operator::Change => {
+            let (yank, cursor) = selection.change(document);
+            outcome.yank = Some((yank, true));
+            outcome.cursor = cursor;
+            outcome.insert = true;
+        }
+        Operator::Yank => {
+            outcome.yank = Some((selection.yank(document), false));
+            if selection.kind == SelectionKind::Line {
+                outcome.cursor = Position { x: at.x, y: first };
+            }
+        }
+        Operator::Indent | Operator::Outdent => {
+            let (first, last) = selection.rows();
+            let lines = Selection::lines(first, last);
+            if operator == Operator::Indent {
+                lines.indent(document, &config.indent());
+            } else {
+                lines.outdent(document, config.tab_width);
+            }
+            outcome.cursor = Position { x: document.row(first).map_or(0, motion::first_non_blank), y: first };
+        }
+        Operator::Lowercase => selection.convert_case(document, Case::Lower),
+        Operator::Uppercase => selection.convert_case(document, Case::Upper),
+    }
+    Some(outcome)
+}
+
+/// The selection a text object covers at `at`
+#[must_use]
+pub fn object_selection(document: &Document, at: Position, object: TextObject, count: Option<usize>) -> Option<Selection> {
+    let range = object.range(document, at, count)?;
+    if range.linewise {
+        Some(Selection::lines(range.start.y, range.end.y))
+    } else {
+        charwise(document, range.start, range.end)
+    }
+}
+
+/// The text `operator` applies to when moving from `at` over `motion`
+fn motion_selection(document: &Document, at: Position, operator: Operator, motion: Motion, count: Option<usize>) -> Option<Selection> {
+    if let Motion::Object(object) = motion {
+        return object_selection(document, at, object, count);
+    }
+    // `cw` changes to the end of the word, like `ce`, keeping the space after it
+    if let (Operator::Change, Motion::WordForward { big }) = (operator, motion) {
+        if !matches!(motion::class(document, at, big), motion::Class::Blank | motion::Class::EmptyLine) {
+            let times = count.unwrap_or(1).max(1);
+            let target = if motion::is_word_end(document, at, big) {
+                if times == 1 {
+                    at
+                } else {
+                    Motion::WordEnd { big }.target(document, at, Some(times.saturating_sub(1)))?
+                }
+            } else {
+                Motion::WordEnd { big }.target(document, at, Some(times))?
+            };
+            return charwise(document, at, exclusive_end(document, target));
+        }
+    }
+    let target = motion.target(document, at, count)?;
+    match motion.kind() {
+        MotionKind::Linewise => Some(Selection::lines(at.y.min(target.y), at.y.max(target.y))),
+        MotionKind::Inclusive => {
+            let (start, end) = ordered(at, target);
+            charwise(document, start, exclusive_end(document, end))
+        }
+        MotionKind::Exclusive => {
+            let (start, mut end) = ordered(at, target);
+            // A word motion that crosses into the next row stops at the end of the last word
+            // rather than taking in the line break
+            if matches!(motion, Motion::WordForward { .. }) && end.x == 0 && end.y > start.y {
+                let y = end.y.saturating_sub(1);
+                end = Position { x: motion::row_len(document, y), y };
+            }
+            charwise(document, start, end)
+        }
+    }
+}
+
+fn ordered(a: Position, b: Position) -> (Position, Position) {
+    if (a.y, a.x) <= (b.y, b.x) {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// The position just after `at`, without going past the end of its row
+fn exclusive_end(document: &Document, at: Position) -> Position {
+    Position { x: at.x.saturating_add(1).min(motion::row_len(document, at.y)), y: at.y }
+}
+
+/// The charwise selection of `start` up to (not including) `end`
+fn charwise(document: &Document, start: Position, end: Position) -> Option<Selection> {
+    if start == end {
+        return None;
+    }
+    let last = if end.x > 0 {
+        Position { x: end.x.saturating_sub(1), y: end.y }
+    } else {
+        let y = end.y.saturating_sub(1);
+        Position { x: motion::row_len(document, y), y }
+    };
+    Some(Selection {
+        anchor: start,
+        cursor: last,
+        kind: SelectionKind::Char,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `keys` to a parser from `cursor`, applying each command to a document holding `text`
+    fn replay(text: &str, cursor: (usize, usize), keys: &str) -> (String, Position, Vec<Yank>) {
+        let mut document = Document::scratch(text);
+        let mut cursor = Position { x: cursor.0, y: cursor.1 };
+        let mut yanks = vec![];
+        let mut parser = Parser::default();
+        for c in keys.chars() {
+            match parser.feed(c) {
+                Step::Pending => continue,
+                Step::Done(Action::Move(motion, count)) => {
+                    cursor = motion.target(&document, cursor, count).unwrap_or(cursor);
+                }
+                Step::Done(Action::Operate(operator, motion, count)) => {
+                    if let Some(outcome) = operate(&mut document, cursor, operator, motion, count, &Config::default()) {
+                        cursor = outcome.cursor;
+                        yanks.extend(outcome.yank.map(|(yank, _)| yank));
+                    }
+                }
+                Step::Done(Action::Other(c, _)) => panic!("unexpected key {}", c),
+                Step::Invalid => panic!("invalid command at {}", c),
+            }
+            parser = Parser::default();
+        }
+        (document.text(), cursor, yanks)
+    }
+
+    fn at(x: usize, y: usize) -> Position {
+        Position { x, y }
+    }
+
+    #[test]
+    fn counts_multiply_and_motions_move() {
+        let text = "one\ntwo\nthree\nfour\nfive\nsix\nseven";
+        assert!(replay(text, (0, 0), "5j").1 == at(0, 5));
+        assert!(replay(text, (0, 6), "2k").1 == at(0, 4));
+        assert!(replay(text, (0, 0), "G").1 == at(0, 6));
+        assert!(replay(text, (0, 6), "3G").1 == at(0, 2));
+        assert!(replay(text, (2, 4), "gg").1 == at(0, 0));
+        assert!(replay("foo bar baz", (0, 0), "2w").1 == at(8, 0));
+        assert!(replay("foo bar baz", (10, 0), "b").1 == at(8, 0));
+        assert!(replay("foo bar baz", (0, 0), "e").1 == at(2, 0));
+        assert!(replay("foo.bar baz", (0, 0), "w").1 == at(3, 0));
+        assert!(replay("foo.bar baz", (0, 0), "W").1 == at(8, 0));
+        assert!(replay("  indented", (8, 0), "^").1 == at(2, 0));
+        assert!(replay("  indented", (8, 0), "0").1 == at(0, 0));
+        assert!(replay("hello", (0, 0), "$").1 == at(4, 0));
+        assert!(replay("a,b,c", (0, 0), "2f,").1 == at(3, 0));
+        assert!(replay("a,b,c", (0, 0), "t,").1 == at(0, 0));
+        assert!(replay("f(a, (b))", (0, 0), "%").1 == at(8, 0));
+        assert!(replay("{\n  x\n}", (0, 2), "%").1 == at(0, 0));
+    }
+
+    #[test]
+    fn word_motions_cross_rows() {
+        assert!(replay("foo\n  bar", (0, 0), "w").1 == at(2, 1));
+        assert!(replay("foo\n\nbar", (0, 0), "w").1 == at(0, 1));
+        assert!(replay("foo\n\nbar", (0, 2), "b").1 == at(0, 1));
+        assert!(replay("foo\n\nbar", (2, 0), "e").1 == at(2, 2));
+    }
+
+    #[test]
+    fn operators_apply_over_motions() {
+        assert_eq!(replay("one two three four", (0, 0), "3dw").0, "four");
+        assert_eq!(replay("one two three four", (0, 0), "2d2w").0, "");
+        assert_eq!(replay("hello world", (5, 0), "d$").0, "hello");
+        assert_eq!(replay("hello world", (6, 0), "D").0, "hello ");
+        assert_eq!(replay("hello world", (0, 0), "x").0, "ello world");
+        assert_eq!(replay("hello world", (0, 0), "3x").0, "lo world");
+        assert_eq!(replay("a,b,c", (0, 0), "df,").0, "b,c");
+        assert_eq!(replay("a,b,c", (0, 0), "dt,").0, ",b,c");
+        assert_eq!(replay("f(a, (b)) + 1", (1, 0), "d%").0, "f + 1");
+        assert_eq!(replay("foo bar", (4, 0), "db").0, "bar");
+        assert_eq!(replay("foo bar", (0, 0), "de").0, " bar");
+        assert_eq!(replay("foo bar", (4, 0), "d0").0, "bar");
+    }
+
+    #[test]
+    fn word_delete_stops_at_the_end_of_the_row() {
+        assert_eq!(replay("foo bar\nbaz", (4, 0), "dw").0, "foo \nbaz");
+        assert_eq!(replay("foo", (0, 0), "dw").0, "");
+    }
+
+    #[test]
+    fn linewise_operators() {
+        let text = "one\ntwo\nthree\nfour";
+        assert_eq!(replay(text, (0, 1), "dd").0, "one\nthree\nfour");
+        assert_eq!(replay(text, (0, 1), "2dd").0, "one\nfour");
+        assert_eq!(replay(text, (0, 0), "dj").0, "three\nfour");
+        assert_eq!(replay(text, (0, 3), "dk").0, "one\ntwo");
+        assert_eq!(replay(text, (0, 1), "dG").0, "one");
+        assert_eq!(replay(text, (0, 2), "dgg").0, "four");
+        let (result, cursor, yanks) = replay(text, (2, 1), "yj");
+        assert_eq!(result, text);
+        assert!(cursor == at(2, 1));
+        assert_eq!(yanks.first().map(|yank| yank.text.as_str()), Some("two\nthree\n"));
+        assert_eq!(replay(text, (0, 0), "2>>").0, "    one\n    two\nthree\nfour");
+        assert_eq!(replay("    one\n    two", (0, 0), "<j").0, "one\ntwo");
+    }
+
+    #[test]
+    fn change_and_case_operators() {
+        assert_eq!(replay("foo bar", (0, 0), "cw").0, " bar");
+        assert_eq!(replay("a bar", (0, 0), "cw").0, " bar");
+        assert_eq!(replay("foo bar baz", (0, 0), "c2w").0, " baz");
+        assert_eq!(replay("one\ntwo", (0, 1), "cc").0, "one\n");
+        assert_eq!(replay("hello world", (0, 0), "gUw").0, "HELLO world");
+        assert_eq!(replay("HELLO WORLD", (0, 0), "guu").0, "hello world");
+        assert_eq!(replay("hello\nworld", (0, 0), "gUgUj").0, "HELLO\nworld");
+    }
+
+    #[test]
+    fn operators_apply_over_text_objects() {
+        assert_eq!(replay("foo bar baz", (5, 0), "ciw").0, "foo  baz");
+        assert_eq!(replay("foo bar baz", (5, 0), "daw").0, "foo baz");
+        assert_eq!(replay("f(a, (b), c) + 1", (2, 0), "da(").0, "f + 1");
+        assert_eq!(replay("call(\n    x,\n    y\n)", (4, 1), "di(").0, "call(\n)");
+        let (text, _, yanks) = replay(r#"say "hi there" now"#, (1, 0), "yi\"");
+        assert_eq!(text, r#"say "hi there" now"#);
+        assert_eq!(yanks.first().map(|yank| yank.text.as_str()), Some("hi there"));
+        assert_eq!(replay("one\ntwo\n\nthree", (0, 1), "dap").0, "three");
+        assert_eq!(replay("<p>old</p>", (4, 0), "cit").0, "<p></p>");
+    }
+
+    #[test]
+    fn parser_reports_other_keys_and_invalid_sequences() {
+        let mut parser = Parser::default();
+        assert!(parser.feed('3') == Step::Pending);
+        assert!(parser.feed('p') == Step::Done(Action::Other('p', Some(3))));
+        let mut parser = Parser::default();
+        assert!(parser.feed('d') == Step::Pending);
+        assert!(parser.feed('y') == Step::Invalid);
+        let mut parser = Parser::motions_only();
+        assert!(parser.feed('d') == Step::Done(Action::Other('d', None)));
+        let mut parser = Parser::default();
+        assert!(parser.feed('i') == Step::Done(Action::Other('i', None)));
+        let mut parser = Parser::default();
+        assert!(parser.feed('d') == Step::Pending);
+        assert!(parser.feed('i') == Step::Pending);
+        assert!(parser.feed('z') == Step::Invalid);
+    }
+}
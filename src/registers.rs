@@ -0,0 +1,284 @@
+use crate::selection::{SelectionKind, Yank};
+use std::collections::HashMap;
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// The shell commands the `"+` register copies to and pastes from
+pub struct Clipboard {
+    pub copy: String,
+    pub paste: String,
+}
+
+impl Clipboard {
+    /// `pbcopy` on macOS, `wl-copy` under Wayland and `xclip` everywhere else
+    #[must_use]
+    pub fn default() -> Self {
+        let (copy, paste) = if cfg!(target_os = "macos") {
+            ("pbcopy", "pbpaste")
+        } else if env::var_os("WAYLAND_DISPLAY").is_some() {
+            ("wl-copy", "wl-paste --no-newline")
+        } else {
+            ("xclip -selection clipboard", "xclip -selection clipboard -o")
+        };
+        Self {
+            copy: copy.to_owned(),
+            paste: paste.to_owned(),
+        }
+    }
+    /// Pipes `text` into the copy command
+    /// # Errors
+    /// Will return `Err` with a message if the command cannot be run or fails
+    pub fn copy(&self, text: &str) -> Result<(), String> {
+        let mut child = shell(&self.copy)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|error| format!("Clipboard command \"{}\" failed: {}", self.copy, error))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(text.as_bytes())
+                .map_err(|error| format!("Clipboard command \"{}\" failed: {}", self.copy, error))?;
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|error| format!("Clipboard command \"{}\" failed: {}", self.copy, error))?;
+        check_status(&self.copy, &output)
+    }
+    /// Reads the output of the paste command
+    /// # Errors
+    /// Will return `Err` with a message if the command cannot be run or fails
+    pub fn paste(&self) -> Result<String, String> {
+        let output = shell(&self.paste)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|error| format!("Clipboard command \"{}\" failed: {}", self.paste, error))?;
+        check_status(&self.paste, &output)?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+fn check_status(command: &str, output: &std::process::Output) -> Result<(), String> {
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(format!("Clipboard command \"{}\" failed: {}", command, stderr.trim()))
+}
+
+/// The registers yanks and deletes are stored in:
+/// - `"` (unnamed), holding whatever was last yanked or deleted
+/// - `0`, the last yank, and `1`–`9`, the last nine deletes with the newest in `1`
+/// - `a`–`z`, written only when named; `A`–`Z` append to them
+/// - `+`, the system clipboard
+pub struct Registers {
+    registers: HashMap<char, Yank>,
+}
+
+impl Registers {
+    #[must_use]
+    pub fn default() -> Self {
+        Self {
+            registers: HashMap::new(),
+        }
+    }
+    #[must_use] pub fn is_valid(name: char) -> bool {
+        name.is_ascii_alphanumeric() || name == '"' || name == '+'
+    }
+    /// Stores yanked text in register `name`, or `0` if no register was named
+    /// # Errors
+    /// Will return `Err` with a message if the register is invalid or the clipboard fails
+    pub fn yank(&mut self, name: Option<char>, yank: Yank, clipboard: &Clipboard) -> Result<(), String> {
+        self.write(name, yank, clipboard, false)
+    }
+    /// Stores deleted text in register `name`, or shifts it into `1` if no register was named
+    /// # Errors
+    /// Will return `Err` with a message if the register is invalid or the clipboard fails
+    pub fn delete(&mut self, name: Option<char>, yank: Yank, clipboard: &Clipboard) -> Result<(), String> {
+        self.write(name, yank, clipboard, true)
+    }
+    fn write(&mut self, name: Option<char>, yank: Yank, clipboard: &Clipboard, deleted: bool) -> Result<(), String> {
+        let yank = match name {
+            None | Some('"') => {
+                if deleted {
+                    for number in (1..9).rev() {
+                        if let (Some(from), Some(to)) = (char::from_digit(number, 10), char::from_digit(number.saturating_add(1), 10)) {
+                            if let Some(older) = self.registers.remove(&from) {
+                                self.registers.insert(to, older);
+                            }
+                        }
+                    }
+                    self.registers.insert('1', yank.clone());
+                } else {
+                    self.registers.insert('0', yank.clone());
+                }
+                yank
+            }
+            Some('+') => {
+                clipboard.copy(&yank.text)?;
+                yank
+            }
+            Some(name) if name.is_ascii_uppercase() => {
+                let name = name.to_ascii_lowercase();
+                let yank = match self.registers.remove(&name) {
+                    Some(existing) => append(existing, &yank),
+                    None => yank,
+                };
+                self.registers.insert(name, yank.clone());
+                yank
+            }
+            Some(name) if Self::is_valid(name) => {
+                self.registers.insert(name, yank.clone());
+                yank
+            }
+            Some(name) => return Err(format!("Invalid register \"{}", name)),
+        };
+        self.registers.insert('"', yank);
+        Ok(())
+    }
+    /// The contents of register `name`, or the unnamed register if none was named
+    /// # Errors
+    /// Will return `Err` with a message if the register is empty or the clipboard fails
+    pub fn get(&self, name: Option<char>, clipboard: &Clipboard) -> Result<Yank, String> {
+        let name = name.unwrap_or('"').to_ascii_lowercase();
+        if name == '+' {
+            let text = clipboard.paste()?;
+            let kind = if text.ends_with('\n') { SelectionKind::Line } else { SelectionKind::Char };
+            return Ok(Yank { text, kind });
+        }
+        self.registers
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| format!("Nothing in register {}", name))
+    }
+}
+
+/// Joins two yanks for an append to a named register; adding lines makes the whole register linewise
+fn append(existing: Yank, added: &Yank) -> Yank {
+    if existing.kind == SelectionKind::Line || added.kind == SelectionKind::Line {
+        let mut text = existing.text;
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(&added.text);
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        Yank { text, kind: SelectionKind::Line }
+    } else {
+        Yank { text: existing.text + &added.text, kind: existing.kind }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Document, Position};
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A clipboard backed by a temporary file instead of the system one
+    fn fake_clipboard(name: &str) -> (Clipboard, PathBuf) {
+        let path = env::temp_dir().join(format!("lecturn-clipboard-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        let clipboard = Clipboard {
+            copy: format!("cat > '{}'", path.display()),
+            paste: format!("cat '{}'", path.display()),
+        };
+        (clipboard, path)
+    }
+
+    fn chars(text: &str) -> Yank {
+        Yank { text: text.to_owned(), kind: SelectionKind::Char }
+    }
+
+    fn lines(text: &str) -> Yank {
+        Yank { text: text.to_owned(), kind: SelectionKind::Line }
+    }
+
+    #[test]
+    fn clipboard_register_round_trips_through_commands() {
+        let (clipboard, path) = fake_clipboard("round-trip");
+        let mut registers = Registers::default();
+        registers.yank(Some('+'), lines("one\ntwo\n"), &clipboard).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+
+        let pasted = registers.get(Some('+'), &clipboard).unwrap();
+        assert_eq!(pasted.text, "one\ntwo\n");
+        assert!(pasted.kind == SelectionKind::Line);
+
+        fs::write(&path, "word").unwrap();
+        assert!(registers.get(Some('+'), &clipboard).unwrap().kind == SelectionKind::Char);
+        // The unnamed register follows the last write, including to the clipboard
+        assert_eq!(registers.get(None, &clipboard).unwrap().text, "one\ntwo\n");
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn failing_clipboard_command_is_reported() {
+        let clipboard = Clipboard {
+            copy: "echo no display >&2; exit 1".to_owned(),
+            paste: "exit 1".to_owned(),
+        };
+        let mut registers = Registers::default();
+        let error = registers.yank(Some('+'), chars("text"), &clipboard).unwrap_err();
+        assert!(error.contains("no display"), "{}", error);
+        assert!(registers.get(Some('+'), &clipboard).is_err());
+    }
+
+    #[test]
+    fn deletes_shift_through_numbered_registers() {
+        let (clipboard, _) = fake_clipboard("numbered");
+        let mut registers = Registers::default();
+        registers.yank(None, chars("yanked"), &clipboard).unwrap();
+        for number in 1..=10 {
+            registers.delete(None, chars(&number.to_string()), &clipboard).unwrap();
+        }
+        assert_eq!(registers.get(Some('0'), &clipboard).unwrap().text, "yanked");
+        assert_eq!(registers.get(Some('1'), &clipboard).unwrap().text, "10");
+        assert_eq!(registers.get(Some('9'), &clipboard).unwrap().text, "2");
+        assert_eq!(registers.get(None, &clipboard).unwrap().text, "10");
+    }
+
+    #[test]
+    fn named_registers_append_in_uppercase() {
+        let (clipboard, _) = fake_clipboard("named");
+        let mut registers = Registers::default();
+        registers.yank(Some('a'), chars("foo"), &clipboard).unwrap();
+        registers.yank(Some('A'), chars("bar"), &clipboard).unwrap();
+        assert_eq!(registers.get(Some('a'), &clipboard).unwrap().text, "foobar");
+        registers.yank(Some('A'), lines("baz\n"), &clipboard).unwrap();
+        let appended = registers.get(Some('a'), &clipboard).unwrap();
+        assert_eq!(appended.text, "foobar\nbaz\n");
+        assert!(appended.kind == SelectionKind::Line);
+        // Named writes leave `0` alone
+        assert!(registers.get(Some('0'), &clipboard).is_err());
+        assert!(registers.yank(Some('!'), chars("x"), &clipboard).is_err());
+    }
+
+    #[test]
+    fn put_places_text_by_kind() {
+        let mut doc = Document::scratch("one\ntwo");
+        let cursor = lines("new\n").put(&mut doc, &Position::default(), false);
+        assert_eq!(doc.text(), "one\nnew\ntwo");
+        assert!(cursor == Position { x: 0, y: 1 });
+        lines("top\n").put(&mut doc, &Position::default(), true);
+        assert_eq!(doc.text(), "top\none\nnew\ntwo");
+
+        let mut doc = Document::scratch("ac");
+        let cursor = chars("b").put(&mut doc, &Position::default(), false);
+        assert_eq!(doc.text(), "abc");
+        assert!(cursor == Position { x: 1, y: 0 });
+
+        let mut doc = Document::scratch("ab\ncd");
+        Yank { text: "12\n34\n56".to_owned(), kind: SelectionKind::Block }.put(&mut doc, &Position::default(), false);
+        assert_eq!(doc.text(), "a12b\nc34d\n 56");
+    }
+}
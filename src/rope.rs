@@ -0,0 +1,302 @@
+use std::iter::FromIterator;
+
+/// A B-tree of items addressed by position. Inserting, removing and looking up an
+/// item costs O(log n) instead of the O(n) shifting a `Vec` needs, so a `Document`
+/// with millions of rows stays as quick to edit as a small one.
+pub struct Rope<T> {
+    root: Node<T>,
+}
+
+const MAX_LEAF_LEN: usize = 512;
+const MAX_CHILDREN: usize = 32;
+
+enum Node<T> {
+    Leaf(Vec<T>),
+    Branch { len: usize, children: Vec<Node<T>> },
+}
+
+impl<T> Node<T> {
+    fn len(&self) -> usize {
+        match self {
+            Node::Leaf(items) => items.len(),
+            Node::Branch { len, .. } => *len,
+        }
+    }
+    fn get(&self, mut index: usize) -> Option<&T> {
+        match self {
+            Node::Leaf(items) => items.get(index),
+            Node::Branch { children, .. } => {
+                for child in children {
+                    if index < child.len() {
+                        return child.get(index);
+                    }
+                    index = index.saturating_sub(child.len());
+                }
+                None
+            }
+        }
+    }
+    fn get_mut(&mut self, mut index: usize) -> Option<&mut T> {
+        match self {
+            Node::Leaf(items) => items.get_mut(index),
+            Node::Branch { children, .. } => {
+                for child in children {
+                    if index < child.len() {
+                        return child.get_mut(index);
+                    }
+                    index = index.saturating_sub(child.len());
+                }
+                None
+            }
+        }
+    }
+    /// Inserts `item` before `index`, returning the right half of this node if it had to split
+    fn insert(&mut self, mut index: usize, item: T) -> Option<Self> {
+        match self {
+            Node::Leaf(items) => {
+                items.insert(index.min(items.len()), item);
+                if items.len() > MAX_LEAF_LEN {
+                    let half = items.len().saturating_div(2);
+                    return Some(Node::Leaf(items.split_off(half)));
+                }
+                None
+            }
+            Node::Branch { len, children } => {
+                *len = len.saturating_add(1);
+                let last = children.len().saturating_sub(1);
+                let mut position = last;
+                for (i, child) in children.iter().enumerate() {
+                    if index <= child.len() {
+                        position = i;
+                        break;
+                    }
+                    index = index.saturating_sub(child.len());
+                }
+                let split = children.get_mut(position)?.insert(index, item)?;
+                children.insert(position.saturating_add(1), split);
+                if children.len() > MAX_CHILDREN {
+                    let right = children.split_off(children.len().saturating_div(2));
+                    let right_len = right.iter().map(Node::len).sum::<usize>();
+                    *len = len.saturating_sub(right_len);
+                    return Some(Node::Branch { len: right_len, children: right });
+                }
+                None
+            }
+        }
+    }
+    fn remove(&mut self, mut index: usize) -> Option<T> {
+        match self {
+            Node::Leaf(items) => {
+                if index < items.len() {
+                    Some(items.remove(index))
+                } else {
+                    None
+                }
+            }
+            Node::Branch { len, children } => {
+                let mut position = None;
+                for (i, child) in children.iter().enumerate() {
+                    if index < child.len() {
+                        position = Some(i);
+                        break;
+                    }
+                    index = index.saturating_sub(child.len());
+                }
+                let position = position?;
+                let child = children.get_mut(position)?;
+                let item = child.remove(index)?;
+                if child.len() == 0 && children.len() > 1 {
+                    children.remove(position);
+                }
+                *len = len.saturating_sub(1);
+                Some(item)
+            }
+        }
+    }
+}
+
+impl<T> Rope<T> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self { root: Node::Leaf(vec![]) }
+    }
+    #[must_use] pub fn len(&self) -> usize {
+        self.root.len()
+    }
+    #[must_use] pub fn get(&self, index: usize) -> Option<&T> {
+        self.root.get(index)
+    }
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.root.get_mut(index)
+    }
+    pub fn push(&mut self, item: T) {
+        self.insert(self.len(), item);
+    }
+    /// Inserts `item` before `index`, appending it when `index` is past the end
+    pub fn insert(&mut self, index: usize, item: T) {
+        if let Some(split) = self.root.insert(index, item) {
+            let left = std::mem::replace(&mut self.root, Node::Leaf(vec![]));
+            self.root = Node::Branch {
+                len: left.len().saturating_add(split.len()),
+                children: vec![left, split],
+            };
+        }
+    }
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        let item = self.root.remove(index);
+        // Collapse roots left with a single child so the tree never grows taller than it needs to
+        loop {
+            match &mut self.root {
+                Node::Branch { children, .. } if children.len() == 1 => {
+                    if let Some(child) = children.pop() {
+                        self.root = child;
+                    }
+                }
+                _ => break,
+            }
+        }
+        item
+    }
+    #[must_use] pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![std::slice::from_ref(&self.root).iter()],
+            leaf: [].iter(),
+        }
+    }
+}
+
+impl<T> FromIterator<T> for Rope<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut level: Vec<Node<T>> = vec![];
+        let mut leaf = Vec::with_capacity(MAX_LEAF_LEN);
+        for item in iter {
+            leaf.push(item);
+            if leaf.len() == MAX_LEAF_LEN {
+                level.push(Node::Leaf(std::mem::replace(&mut leaf, Vec::with_capacity(MAX_LEAF_LEN))));
+            }
+        }
+        if !leaf.is_empty() || level.is_empty() {
+            level.push(Node::Leaf(leaf));
+        }
+        // Build the tree bottom up, one full level of branches at a time
+        while level.len() > 1 {
+            let mut parents = vec![];
+            let mut children = vec![];
+            for node in level {
+                children.push(node);
+                if children.len() == MAX_CHILDREN {
+                    let len = children.iter().map(Node::len).sum();
+                    parents.push(Node::Branch { len, children: std::mem::take(&mut children) });
+                }
+            }
+            if !children.is_empty() {
+                let len = children.iter().map(Node::len).sum();
+                parents.push(Node::Branch { len, children });
+            }
+            level = parents;
+        }
+        Self {
+            root: level.pop().unwrap_or(Node::Leaf(vec![])),
+        }
+    }
+}
+
+pub struct Iter<'a, T> {
+    stack: Vec<std::slice::Iter<'a, Node<T>>>,
+    leaf: std::slice::Iter<'a, T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.leaf.next() {
+                return Some(item);
+            }
+            let node = loop {
+                let nodes = self.stack.last_mut()?;
+                if let Some(node) = nodes.next() {
+                    break node;
+                }
+                self.stack.pop();
+            };
+            match node {
+                Node::Leaf(items) => self.leaf = items.iter(),
+                Node::Branch { children, .. } => self.stack.push(children.iter()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depth<T>(node: &Node<T>) -> usize {
+        match node {
+            Node::Leaf(_) => 1,
+            Node::Branch { children, .. } => 1 + children.iter().map(depth).max().unwrap_or(0),
+        }
+    }
+
+    #[test]
+    fn splits_full_nodes() {
+        let mut rope = Rope::new();
+        for i in 0..=MAX_LEAF_LEN {
+            rope.push(i);
+        }
+        assert_eq!(depth(&rope.root), 2);
+        // Inserting at the front keeps splitting leaves until the root branch is full too
+        let count = MAX_LEAF_LEN * MAX_CHILDREN;
+        for i in 0..count {
+            rope.insert(0, count + MAX_LEAF_LEN - i);
+        }
+        assert_eq!(depth(&rope.root), 3);
+        assert_eq!(rope.len(), count + MAX_LEAF_LEN + 1);
+        assert!(rope.iter().zip(rope.iter().skip(1)).all(|(a, b)| a != b));
+        assert_eq!(rope.get(0), Some(&(MAX_LEAF_LEN + 1)));
+        assert_eq!(rope.get(count), Some(&0));
+        assert_eq!(rope.get(rope.len()), None);
+    }
+
+    #[test]
+    fn merges_as_it_empties() {
+        let mut rope: Rope<usize> = (0..MAX_LEAF_LEN * MAX_CHILDREN * 2).collect();
+        assert_eq!(depth(&rope.root), 3);
+        while rope.len() > 1 {
+            let middle = rope.len().checked_div(2).unwrap_or(0);
+            let expected = rope.get(middle).copied();
+            assert_eq!(rope.remove(middle), expected);
+        }
+        // Emptied nodes are dropped and single children replace the root
+        assert_eq!(depth(&rope.root), 1);
+        assert_eq!(rope.iter().copied().collect::<Vec<_>>(), [0]);
+        assert_eq!(rope.remove(1), None);
+        assert_eq!(rope.remove(0), Some(0));
+        assert_eq!(rope.len(), 0);
+        rope.push(7);
+        assert_eq!(rope.get(0), Some(&7));
+    }
+
+    #[test]
+    fn matches_a_vec() {
+        let mut rope = Rope::new();
+        let mut vec = vec![];
+        for i in 0..5_000_usize {
+            let at = i.wrapping_mul(7919) % (vec.len() + 1);
+            rope.insert(at, i);
+            vec.insert(at, i);
+            if i % 3 == 0 {
+                let at = i.wrapping_mul(104_729) % vec.len();
+                assert_eq!(rope.remove(at), Some(vec.remove(at)));
+            }
+        }
+        assert_eq!(rope.len(), vec.len());
+        assert!(rope.iter().eq(vec.iter()));
+        if let Some(item) = rope.get_mut(10) {
+            *item = 0;
+        }
+        assert_eq!(rope.get(10), Some(&0));
+    }
+}
@@ -1,12 +1,19 @@
 use std::cmp;
+use std::ops::Range;
 use unicode_segmentation::UnicodeSegmentation;
+use crate::config::Config;
+use crate::encoding;
 use crate::highlighting;
+use crate::search::Query;
+use crate::syntax::{self, Language};
 
 #[derive(Default)]
 pub struct Row {
     string: String,
     len: usize,
-    highlighting: Vec<highlighting::Type>
+    highlighting: Vec<highlighting::Type>,
+    syntax: Vec<highlighting::Type>,
+    syntax_end: syntax::State,
 }
 
 impl From<&str> for Row {
@@ -14,55 +21,68 @@ impl From<&str> for Row {
         Self {
             string: String::from(slice),
             len: slice.graphemes(true).count(),
-            highlighting: vec![highlighting::Type::None; slice.len()],
+            ..Self::default()
         }
     }
 }
 
 impl Row {
     #[must_use]
-    pub fn render(&self, start: usize, end: usize) -> String {
-        let end = cmp::min(end, self.string.len());
-        let start = cmp::min(start, end);
+    pub fn render(&self, start: usize, end: usize, config: &Config) -> String {
         let mut result = String::new();
         let mut current_highlighting = &highlighting::Type::None;
-        #[allow(clippy::integer_arithmetic)]
-        for (index, grapheme) in self.string[..]
-            .graphemes(true)
-            .enumerate()
-            .skip(start)
-            .take(end - start)
-        {
-            if let Some(c) = grapheme.chars().next() {
-                let highlighting_type = self
-                    .highlighting
-                    .get(index)
-                    .unwrap_or(&highlighting::Type::None);
-                if highlighting_type != current_highlighting {
-                    current_highlighting = highlighting_type;
-                    let highlight = highlighting_type.to_color();
-                    let start_highlight;
-                    if *highlighting_type == highlighting::Type::None {
-                        start_highlight =
-                            format!("{}{}", termion::color::Bg(termion::color::Reset), termion::color::Fg(termion::color::Reset));
-                    } else {
-                        start_highlight =
-                            format!("{}{}", termion::color::Bg(highlight.bg_color), termion::color::Fg(highlight.fg_color));
-                    }
-
-                    result.push_str(&start_highlight[..]);
-                }
-                if c == '\t' {
-                    result.push_str("    ");
-                } else {
-                    result.push(c);
-                }
+        let mut column: usize = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            let width = grapheme_width(grapheme, column, config.tab_width);
+            let grapheme_start = column;
+            column = column.saturating_add(width);
+            if column <= start {
+                continue;
+            }
+            if grapheme_start >= end {
+                break;
+            }
+            let highlighting_type = self
+                .highlighting
+                .get(index)
+                .filter(|&&highlight| highlight != highlighting::Type::None)
+                .or_else(|| self.syntax.get(index))
+                .unwrap_or(&highlighting::Type::None);
+            if highlighting_type != current_highlighting {
+                current_highlighting = highlighting_type;
+                let highlight = highlighting_type.to_color(&config.theme);
+                let bg = highlight.bg_color.map_or_else(
+                    || termion::color::Bg(termion::color::Reset).to_string(),
+                    |color| termion::color::Bg(color).to_string(),
+                );
+                let fg = highlight.fg_color.map_or_else(
+                    || termion::color::Fg(termion::color::Reset).to_string(),
+                    |color| termion::color::Fg(color).to_string(),
+                );
+                result.push_str(&bg);
+                result.push_str(&fg);
+            }
+            if grapheme == "\t" {
+                let visible = cmp::min(column, end).saturating_sub(cmp::max(grapheme_start, start));
+                result.push_str(&" ".repeat(visible));
+            } else if grapheme.chars().any(|c| c.is_control() || encoding::is_escape(c)) {
+                result.extend(grapheme.chars().map(visible));
+            } else {
+                result.push_str(grapheme);
             }
         }
         let end_highlight = format!("{}{}", termion::color::Bg(termion::color::Reset), termion::color::Fg(termion::color::Reset));
         result.push_str(&end_highlight[..]);
         result
     }
+    /// The screen column grapheme `x` starts at, with tabs expanded to the next tab stop
+    #[must_use]
+    pub fn display_column(&self, x: usize, tab_width: usize) -> usize {
+        self.string[..]
+            .graphemes(true)
+            .take(x)
+            .fold(0, |column, grapheme| column.saturating_add(grapheme_width(grapheme, column, tab_width)))
+    }
     #[must_use] pub fn len(&self) -> usize {
         self.len
     }
@@ -70,77 +90,174 @@ impl Row {
         self.len == 0
     }
     pub fn insert(&mut self, at: usize, c: char) {
-        if at >= self.len() {
-            self.string.push(c);
-            self.len = self.len.saturating_add(1);
-            return;
-        }
-        let mut result: String = String::new();
-        let mut length: usize = 0;
-        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
-            length = length.saturating_add(1);
-            if index == at {
-                length = length.saturating_add(1);
-                result.push(c);
-            }
-            result.push_str(grapheme);
-        }
-        self.len = length;
-        self.string = result;
+        self.insert_str(at, c.encode_utf8(&mut [0; 4]));
+    }
+    pub fn insert_str(&mut self, at: usize, text: &str) {
+        let byte_index = self.byte_index(at);
+        self.string.insert_str(byte_index, text);
+        self.len = self.string[..].graphemes(true).count();
     }
     pub fn delete(&mut self, at: usize) {
         if at >= self.len() {
             return;
         }
-        let mut result: String = String::new();
-        let mut length: usize = 0;
-        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
-            if index != at {
-                length = length.saturating_add(1);
-                result.push_str(grapheme);
-            }
-        }
-        self.len = length;
-        self.string = result;
+        let start = self.byte_index(at);
+        let end = self.byte_index(at.saturating_add(1));
+        self.string.replace_range(start..end, "");
+        self.len = self.len.saturating_sub(1);
     }
     pub fn append(&mut self, new: &Self)  {
-        self.string = format!("{}{}", self.string, new.string);
+        self.string.push_str(&new.string);
         self.len = self.len.saturating_add(new.len);
     }
     pub fn split(&mut self, at: usize) -> Self {
-        let mut row: String = String::new();
-        let mut length: usize = 0;
-        let mut split_row: String = String::new();
-        let mut split_length: usize = 0;
-        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
-            if index < at {
-                length = length.saturating_add(1);
-                row.push_str(grapheme);
-            } else {
-                split_length = split_length.saturating_add(1);
-                split_row.push_str(grapheme);
-            }
-        }
-
-        self.string = row;
-        self.len = length;
+        let at = cmp::min(at, self.len);
+        let split_row = self.string.split_off(self.byte_index(at));
+        let split_length = self.len.saturating_sub(at);
+        self.len = at;
         Self {
             string: split_row,
             len: split_length,
-            highlighting: vec!(),
+            ..Self::default()
         }
     }
-    #[must_use] pub fn find(&self, query: &str) -> Option<usize> {
-        self.string.find(query)
+    /// Byte offset of the grapheme at `at`, or the end of the row when `at` is past it
+    fn byte_index(&self, at: usize) -> usize {
+        self.string[..]
+            .grapheme_indices(true)
+            .nth(at)
+            .map_or(self.string.len(), |(index, _)| index)
+    }
+    /// The graphemes from `start` up to (not including) `end`
+    #[must_use] pub fn slice(&self, start: usize, end: usize) -> &str {
+        let start = self.byte_index(start);
+        let end = self.byte_index(end).max(start);
+        self.string.get(start..end).unwrap_or("")
+    }
+    /// The index of the grapheme starting at (or containing) byte `byte_index`
+    #[must_use] pub fn grapheme_index(&self, byte_index: usize) -> usize {
+        self.string[..]
+            .grapheme_indices(true)
+            .take_while(|&(index, grapheme)| index.saturating_add(grapheme.len()) <= byte_index)
+            .count()
+    }
+    #[must_use] pub fn as_str(&self) -> &str {
+        &self.string
+    }
+    #[must_use] pub fn grapheme(&self, at: usize) -> Option<&str> {
+        self.string[..].graphemes(true).nth(at)
+    }
+    /// The grapheme ranges of every match of `query`; a match starting or ending inside a
+    /// grapheme covers all of it
+    #[must_use] pub fn find(&self, query: &Query) -> Vec<Range<usize>> {
+        let mut starts: Vec<usize> = self.string.grapheme_indices(true).map(|(index, _)| index).collect();
+        starts.push(self.string.len());
+        query
+            .byte_ranges(&self.string)
+            .map(|range| {
+                let start = starts.partition_point(|&index| index <= range.start).saturating_sub(1);
+                let end = starts.partition_point(|&index| index < range.end).max(start);
+                start..end
+            })
+            .collect()
     }
     #[must_use] pub fn as_bytes(&self) -> &[u8] {
         self.string.as_bytes()
     }
-    #[allow(clippy::indexing_slicing)]
     pub fn add_highlighting(&mut self, highlight_type: highlighting::Type, index: usize) {
-        self.highlighting[index] = highlight_type;
+        if index >= self.highlighting.len() {
+            self.highlighting.resize(index.saturating_add(1), highlighting::Type::None);
+        }
+        if let Some(highlight) = self.highlighting.get_mut(index) {
+            *highlight = highlight_type;
+        }
     }
     pub fn reset_highlighting(&mut self) {
-        self.highlighting = vec![highlighting::Type::None; self.string.len()];
+        self.highlighting.clear();
+    }
+    /// Highlights the row's syntax starting in `state`, returning the state the next row starts in
+    pub fn highlight_syntax(&mut self, language: Option<&Language>, state: syntax::State) -> syntax::State {
+        if let Some(language) = language {
+            let (highlighting, end) = language.highlight(&self.string, state);
+            self.syntax = highlighting;
+            self.syntax_end = end;
+        } else {
+            self.syntax.clear();
+            self.syntax_end = None;
+        }
+        self.syntax_end
+    }
+    #[must_use] pub fn syntax_end(&self) -> syntax::State {
+        self.syntax_end
+    }
+}
+
+fn grapheme_width(grapheme: &str, column: usize, tab_width: usize) -> usize {
+    if grapheme == "\t" {
+        tab_width.saturating_sub(column.checked_rem(tab_width).unwrap_or(0))
+    } else {
+        1
+    }
+}
+
+/// What to draw for a character that would otherwise move the terminal's cursor or is
+/// not text: control characters as their symbol (e.g. `␍`) and bytes that were invalid
+/// in the file's encoding as `�`
+fn visible(c: char) -> char {
+    match u32::from(c) {
+        code @ 0..=0x1f => char::from_u32(code.saturating_add(0x2400)).unwrap_or(c),
+        0x7f => '\u{2421}',
+        _ if c.is_control() || encoding::is_escape(c) => char::REPLACEMENT_CHARACTER,
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "é" as e and a combining accent, and a family joined into one emoji
+    const TEXT: &str = "ae\u{301}b\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}c";
+
+    #[test]
+    fn indexes_by_grapheme() {
+        let row = Row::from(TEXT);
+        assert_eq!(row.len(), 5);
+        assert_eq!(row.grapheme(1), Some("e\u{301}"));
+        assert_eq!(row.grapheme(3), Some("\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}"));
+        assert_eq!(row.grapheme(5), None);
+        assert_eq!(row.slice(1, 3), "e\u{301}b");
+        assert_eq!(row.slice(4, 9), "c");
+        assert_eq!(row.grapheme_index(2), 1);
+        assert_eq!(row.grapheme_index(3), 1);
+        assert_eq!(row.grapheme_index(4), 2);
+        assert_eq!(row.grapheme_index(TEXT.len()), 5);
+    }
+
+    #[test]
+    fn edits_whole_graphemes() {
+        let mut row = Row::from(TEXT);
+        row.delete(3);
+        assert_eq!(row.as_str(), "ae\u{301}bc");
+        assert_eq!(row.len(), 4);
+        row.insert(1, 'x');
+        row.insert_str(9, "\u{301}");
+        // The accent joins the last grapheme rather than starting one of its own
+        assert_eq!(row.as_str(), "axe\u{301}bc\u{301}");
+        assert_eq!(row.len(), 5);
+        row.delete(5);
+        assert_eq!(row.len(), 5);
+    }
+
+    #[test]
+    fn splits_and_appends() {
+        let mut row = Row::from(TEXT);
+        let mut rest = row.split(2);
+        assert_eq!((row.as_str(), row.len()), ("ae\u{301}", 2));
+        assert_eq!(rest.len(), 3);
+        let end = rest.split(10);
+        assert!(end.is_empty());
+        row.append(&rest);
+        assert_eq!((row.as_str(), row.len()), (TEXT, 5));
     }
 }
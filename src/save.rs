@@ -0,0 +1,365 @@
+use std::env;
+use std::fmt;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Where the previous version of a file is copied before it is overwritten
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Backup {
+    Off,
+    /// `file~`, next to the file
+    Beside,
+    /// A directory of backups, each named after the whole path of its file with `/`
+    /// replaced by `%`, e.g. `%home%me%notes.txt~`, so files with the same name don't clash
+    Directory(PathBuf),
+}
+
+impl Backup {
+    /// `true` for `file~` backups, `false` for none, or a string naming a backup
+    /// directory (`~/` is expanded to the home directory)
+    /// # Errors
+    /// Will return `Err` with a message if `value` is none of these
+    pub fn from_value(value: &toml::Value) -> Result<Self, String> {
+        match value {
+            toml::Value::Boolean(true) => Ok(Backup::Beside),
+            toml::Value::Boolean(false) => Ok(Backup::Off),
+            toml::Value::String(directory) if !directory.is_empty() => {
+                let directory = match (directory.strip_prefix("~/"), env::var_os("HOME")) {
+                    (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+                    _ => PathBuf::from(directory),
+                };
+                Ok(Backup::Directory(directory))
+            }
+            _ => Err("expected true, false or a directory".to_owned()),
+        }
+    }
+    /// Where the backup of `path` goes, creating the backup directory if needed
+    fn path_for(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        match self {
+            Backup::Off => Ok(None),
+            Backup::Beside => {
+                let mut backup = path.as_os_str().to_owned();
+                backup.push("~");
+                Ok(Some(PathBuf::from(backup)))
+            }
+            Backup::Directory(directory) => {
+                fs::create_dir_all(directory)?;
+                let name = format!("{}~", path.to_string_lossy().replace('/', "%"));
+                Ok(Some(directory.join(name)))
+            }
+        }
+    }
+}
+
+/// Why a save failed
+#[derive(Debug)]
+pub enum SaveError {
+    /// Not allowed to write the file, or to create files in its directory
+    PermissionDenied(PathBuf),
+    /// The directory the file is in does not exist
+    DirectoryNotFound(PathBuf),
+    IsDirectory(PathBuf),
+    DiskFull,
+    ReadOnlyFilesystem,
+    /// The file was left alone because its backup could not be written
+    Backup(PathBuf, io::Error),
+    /// Line `1` (counting from 1) has characters encoding `0` cannot represent
+    Unencodable(&'static str, usize),
+    Other(io::Error),
+}
+
+impl SaveError {
+    /// Classifies an error from working on `path`
+    fn from_io(error: io::Error, path: &Path) -> Self {
+        match error.kind() {
+            ErrorKind::PermissionDenied => SaveError::PermissionDenied(path.to_path_buf()),
+            ErrorKind::NotFound => SaveError::DirectoryNotFound(path.parent().unwrap_or(path).to_path_buf()),
+            ErrorKind::IsADirectory => SaveError::IsDirectory(path.to_path_buf()),
+            ErrorKind::StorageFull => SaveError::DiskFull,
+            ErrorKind::ReadOnlyFilesystem => SaveError::ReadOnlyFilesystem,
+            _ => SaveError::Other(error),
+        }
+    }
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::PermissionDenied(path) => write!(f, "Permission denied: {}", path.display()),
+            SaveError::DirectoryNotFound(path) => write!(f, "Directory does not exist: {}", path.display()),
+            SaveError::IsDirectory(path) => write!(f, "{} is a directory", path.display()),
+            SaveError::DiskFull => write!(f, "No space left on device"),
+            SaveError::ReadOnlyFilesystem => write!(f, "Read-only file system"),
+            SaveError::Backup(path, error) => write!(f, "Could not write backup {}: {}", path.display(), error),
+            SaveError::Unencodable(encoding, line) => write!(f, "Line {} cannot be written as {} (:set fileencoding= to change)", line, encoding),
+            SaveError::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// What a file looked like when it was last read or written, for noticing when another
+/// program changes it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl Stamp {
+    /// The stamp of `path`, which has just been read or written as `contents`
+    /// # Errors
+    /// Will return `Err` if the file's metadata cannot be read
+    pub fn new(path: &Path, contents: &[u8]) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Self {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hash: hash(contents),
+        })
+    }
+    /// The file's stamp now if its contents have changed, or `None` if they have not.
+    /// The contents are only read when the modification time or size differ, and a file
+    /// that was only touched updates the stamp without counting as a change
+    /// # Errors
+    /// Will return `Err` if the file cannot be read, e.g. because it has been deleted
+    pub fn check(&mut self, path: &Path) -> io::Result<Option<Self>> {
+        let metadata = fs::metadata(path)?;
+        if metadata.modified().ok() == self.modified && metadata.len() == self.len {
+            return Ok(None);
+        }
+        let current = Self::new(path, &fs::read(path)?)?;
+        if current.hash == self.hash {
+            *self = current;
+            return Ok(None);
+        }
+        Ok(Some(current))
+    }
+}
+
+/// FNV-1a, which is plenty for telling two versions of a file apart
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Whether this process may write to the file at `path`. A file that does not exist yet
+/// counts as writable, as whether it can be created is only known by trying
+#[cfg(unix)]
+#[must_use]
+pub fn is_writable(path: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    if fs::metadata(path).is_err() {
+        return true;
+    }
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return true,
+    };
+    #[allow(unsafe_code)]
+    // SAFETY: `path` is a valid NUL-terminated string that outlives the call
+    let result = unsafe { libc::access(path.as_ptr(), libc::W_OK) };
+    result == 0
+}
+
+#[cfg(not(unix))]
+#[must_use]
+pub fn is_writable(path: &Path) -> bool {
+    fs::metadata(path).map_or(true, |metadata| !metadata.permissions().readonly())
+}
+
+/// Replaces the file at `path` with `contents` so that it is never left half written:
+/// the contents go to a temporary file in the same directory, which is synced to disk
+/// and renamed over the original with its permissions and owner. A symlink is followed
+/// and the file it points to replaced. If the owner cannot be kept, the file is
+/// overwritten in place instead
+/// # Errors
+/// Will return `Err` with the reason if the file or its backup cannot be written, in which
+/// case the original is left as it was
+pub fn write_atomic(path: &Path, contents: &[u8], backup: &Backup) -> Result<(), SaveError> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let original = match fs::metadata(&path) {
+        Ok(metadata) if metadata.is_dir() => return Err(SaveError::IsDirectory(path)),
+        Ok(metadata) => Some(metadata),
+        Err(error) if error.kind() == ErrorKind::NotFound => None,
+        Err(error) => return Err(SaveError::from_io(error, &path)),
+    };
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    if !directory.is_dir() {
+        return Err(SaveError::DirectoryNotFound(directory));
+    }
+    if original.is_some() {
+        if let Some(backup_path) = backup.path_for(&path).map_err(|error| SaveError::Backup(path.clone(), error))? {
+            fs::copy(&path, &backup_path).map_err(|error| SaveError::Backup(backup_path, error))?;
+        }
+    }
+    let file_name = path.file_name().map_or_else(|| "file".into(), |name| name.to_string_lossy());
+    let temp = directory.join(format!(".{}.lecturn-{}.tmp", file_name, std::process::id()));
+    let written = write_temp(&temp, contents, original.as_ref()).map_err(|error| SaveError::from_io(error, &temp));
+    match written {
+        Ok(true) => {
+            if let Err(error) = fs::rename(&temp, &path) {
+                let _ = fs::remove_file(&temp);
+                return Err(SaveError::from_io(error, &path));
+            }
+            // Make the rename itself durable; not every platform can sync a directory
+            let _ = File::open(&directory).and_then(|directory| directory.sync_all());
+            Ok(())
+        }
+        Ok(false) => {
+            let _ = fs::remove_file(&temp);
+            write_in_place(&path, contents).map_err(|error| SaveError::from_io(error, &path))
+        }
+        Err(error) => {
+            let _ = fs::remove_file(&temp);
+            Err(error)
+        }
+    }
+}
+
+/// Writes and syncs the temporary file, giving it the original's owner and permissions.
+/// Returns `false` if the owner could not be kept
+fn write_temp(temp: &Path, contents: &[u8], original: Option<&Metadata>) -> io::Result<bool> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(temp)?;
+    if let Some(original) = original {
+        if !keep_owner(temp, original)? {
+            return Ok(false);
+        }
+        fs::set_permissions(temp, original.permissions())?;
+    }
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn keep_owner(temp: &Path, original: &Metadata) -> io::Result<bool> {
+    use std::os::unix::fs::{chown, MetadataExt};
+    let created = fs::metadata(temp)?;
+    if created.uid() == original.uid() && created.gid() == original.gid() {
+        return Ok(true);
+    }
+    Ok(chown(temp, Some(original.uid()), Some(original.gid())).is_ok())
+}
+
+#[cfg(not(unix))]
+fn keep_owner(_temp: &Path, _original: &Metadata) -> io::Result<bool> {
+    Ok(true)
+}
+
+fn write_in_place(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).truncate(true).open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lecturn-save-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn replaces_contents_and_leaves_no_temp_file() {
+        let dir = temp_dir("replace");
+        let path = dir.join("file.txt");
+        fs::write(&path, "old contents that are longer").unwrap();
+        write_atomic(&path, b"new", &Backup::Off).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        write_atomic(&dir.join("created.txt"), b"fresh", &Backup::Off).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("created.txt")).unwrap(), "fresh");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_permissions_and_follows_symlinks() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        let dir = temp_dir("permissions");
+        let path = dir.join("script.sh");
+        fs::write(&path, "echo old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o751)).unwrap();
+        let link = dir.join("link.sh");
+        symlink(&path, &link).unwrap();
+        write_atomic(&link, b"echo new", &Backup::Off).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "echo new");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o751);
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn writes_backups_beside_or_in_a_directory() {
+        let dir = temp_dir("backup");
+        let path = dir.join("notes.txt");
+        fs::write(&path, "first").unwrap();
+        write_atomic(&path, b"second", &Backup::Beside).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("notes.txt~")).unwrap(), "first");
+
+        let backups = dir.join("backups");
+        write_atomic(&path, b"third", &Backup::Directory(backups.clone())).unwrap();
+        let canonical = fs::canonicalize(&path).unwrap();
+        let name = format!("{}~", canonical.to_string_lossy().replace('/', "%"));
+        assert_eq!(fs::read_to_string(backups.join(name)).unwrap(), "second");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn stamps_notice_changed_contents() {
+        let dir = temp_dir("stamp");
+        let path = dir.join("notes.txt");
+        fs::write(&path, "mine\n").unwrap();
+        let mut stamp = Stamp::new(&path, b"mine\n").unwrap();
+        assert!(stamp.check(&path).unwrap().is_none());
+        fs::write(&path, "mine\n").unwrap();
+        assert!(stamp.check(&path).unwrap().is_none());
+        fs::write(&path, "theirs\n").unwrap();
+        let current = stamp.check(&path).unwrap().unwrap();
+        assert_ne!(current, stamp);
+        fs::remove_file(&path).unwrap();
+        assert!(stamp.check(&path).is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn tells_whether_a_file_can_be_written() {
+        let dir = temp_dir("writable");
+        let path = dir.join("notes.txt");
+        assert!(is_writable(&path));
+        fs::write(&path, "mine\n").unwrap();
+        assert!(is_writable(&path));
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions).unwrap();
+        // Running as root, everything can be written
+        assert_eq!(is_writable(&path), OpenOptions::new().write(true).open(&path).is_ok());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reports_why_a_save_failed() {
+        let dir = temp_dir("errors");
+        let missing = dir.join("missing").join("file.txt");
+        assert!(matches!(write_atomic(&missing, b"", &Backup::Off), Err(SaveError::DirectoryNotFound(_))));
+        assert!(matches!(write_atomic(&dir, b"", &Backup::Off), Err(SaveError::IsDirectory(_))));
+
+        let path = dir.join("file.txt");
+        fs::write(&path, "kept").unwrap();
+        let blocked = Backup::Directory(path.clone());
+        assert!(matches!(write_atomic(&path, b"lost", &blocked), Err(SaveError::Backup(..))));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "kept");
+        let _ = fs::remove_dir_all(dir);
+    }
+}
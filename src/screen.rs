@@ -0,0 +1,368 @@
+use crate::Position;
+use std::convert::TryFrom;
+use termion::color::Rgb;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Unchanged cells between two changed ones that are drawn again rather than moved over,
+/// when drawing them is no longer than moving the cursor
+const MAX_REDRAWN_GAP: usize = 3;
+
+/// One cell of the screen
+#[derive(Clone, PartialEq, Debug)]
+pub struct Cell {
+    /// A grapheme, which the editor draws one column wide
+    pub symbol: String,
+    /// `None` for the terminal's default colour
+    pub fg: Option<Rgb>,
+    pub bg: Option<Rgb>,
+}
+
+impl Cell {
+    fn blank(bg: Option<Rgb>) -> Self {
+        Self { symbol: " ".to_owned(), fg: None, bg }
+    }
+    fn is_blank(&self) -> bool {
+        self.symbol == " " && self.bg.is_none()
+    }
+}
+
+/// A grid of cells drawn on by writing text and the escape sequences `termion` makes, as
+/// a terminal would be. Frames are drawn on one, then compared with the frame shown
+/// before to find what to send the terminal
+#[derive(Clone)]
+pub struct Screen {
+    width: usize,
+    height: usize,
+    /// Row by row
+    cells: Vec<Cell>,
+    cursor: Position,
+    cursor_visible: bool,
+    /// Set by `ESC [ n SP q`, e.g. 1 for a blinking block
+    cursor_shape: Option<usize>,
+    fg: Option<Rgb>,
+    bg: Option<Rgb>,
+}
+
+impl Screen {
+    #[must_use]
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![Cell::blank(None); width.saturating_mul(height)],
+            cursor: Position::default(),
+            cursor_visible: true,
+            cursor_shape: None,
+            fg: None,
+            bg: None,
+        }
+    }
+    /// Blanks the screen at a new size, keeping the cursor on it
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.cells = vec![Cell::blank(None); width.saturating_mul(height)];
+        self.cursor = Position {
+            x: self.cursor.x.min(width.saturating_sub(1)),
+            y: self.cursor.y.min(height.saturating_sub(1)),
+        };
+    }
+    #[cfg(test)]
+    #[must_use]
+    pub fn cell(&self, x: usize, y: usize) -> Option<&Cell> {
+        if x >= self.width {
+            return None;
+        }
+        self.cells.get(y.saturating_mul(self.width).saturating_add(x))
+    }
+    fn cell_mut(&mut self, x: usize, y: usize) -> Option<&mut Cell> {
+        if x >= self.width {
+            return None;
+        }
+        self.cells.get_mut(y.saturating_mul(self.width).saturating_add(x))
+    }
+    /// The text of each row, without trailing spaces
+    #[cfg(test)]
+    #[must_use]
+    pub fn rows(&self) -> Vec<String> {
+        self.cells
+            .chunks(self.width.max(1))
+            .map(|row| row.iter().map(|cell| cell.symbol.as_str()).collect::<String>().trim_end().to_owned())
+            .collect()
+    }
+    /// Where the cursor is shown, or `None` if it is hidden
+    #[must_use]
+    pub fn cursor(&self) -> Option<&Position> {
+        self.cursor_visible.then_some(&self.cursor)
+    }
+    /// Applies text and escape sequences at the cursor. Anything past the right edge is
+    /// lost, as on a terminal that doesn't wrap
+    pub fn write(&mut self, text: &str) {
+        let mut rest = text;
+        while !rest.is_empty() {
+            let (text, sequence) = rest.split_at(rest.find('\x1b').unwrap_or(rest.len()));
+            self.put_text(text);
+            rest = self.control_sequence(sequence);
+        }
+    }
+    fn put_text(&mut self, text: &str) {
+        for grapheme in text.graphemes(true) {
+            match grapheme {
+                "\r" => self.cursor.x = 0,
+                "\n" | "\r\n" => {
+                    if grapheme == "\r\n" {
+                        self.cursor.x = 0;
+                    }
+                    self.cursor.y = self.cursor.y.saturating_add(1).min(self.height.saturating_sub(1));
+                }
+                _ => {
+                    let Position { x, y } = self.cursor;
+                    let (fg, bg) = (self.fg, self.bg);
+                    if let Some(cell) = self.cell_mut(x, y) {
+                        *cell = Cell { symbol: grapheme.to_owned(), fg, bg };
+                    }
+                    self.cursor.x = x.saturating_add(1).min(self.width);
+                }
+            }
+        }
+    }
+    /// Applies the sequence at the start of `text`, returning what follows it: `ESC [`,
+    /// the parameters, any intermediate bytes, then the final byte
+    fn control_sequence<'a>(&mut self, text: &'a str) -> &'a str {
+        let body = match text.strip_prefix("\x1b[") {
+            Some(body) => body,
+            None => return text.get(1..).unwrap_or_default(),
+        };
+        let (parameters, rest) = body.split_at(body.find(|c| !('\x30'..='\x3f').contains(&c)).unwrap_or(body.len()));
+        let mut rest = rest.trim_start_matches(|c| ('\x20'..='\x2f').contains(&c)).chars();
+        let numbers: Vec<Option<usize>> = parameters.split(';').map(|number| number.parse().ok()).collect();
+        // The nth parameter, where 0 and a missing one both mean 1
+        let count = |index: usize| numbers.get(index).copied().flatten().unwrap_or(1).max(1);
+        let (right, bottom) = (self.width.saturating_sub(1), self.height.saturating_sub(1));
+        match (parameters, rest.next()) {
+            (_, Some('H')) => {
+                self.cursor = Position {
+                    x: count(1).saturating_sub(1).min(right),
+                    y: count(0).saturating_sub(1).min(bottom),
+                };
+            }
+            (_, Some('A')) => self.cursor.y = self.cursor.y.saturating_sub(count(0)),
+            (_, Some('B')) => self.cursor.y = self.cursor.y.saturating_add(count(0)).min(bottom),
+            (_, Some('C')) => self.cursor.x = self.cursor.x.saturating_add(count(0)).min(right),
+            (_, Some('D')) => self.cursor.x = self.cursor.x.min(right).saturating_sub(count(0)),
+            ("2", Some('J')) => {
+                let blank = Cell::blank(self.bg);
+                self.cells.iter_mut().for_each(|cell| *cell = blank.clone());
+            }
+            ("" | "0" | "2", Some('K')) => {
+                let blank = Cell::blank(self.bg);
+                let start = if parameters == "2" { 0 } else { self.cursor.x };
+                for x in start..self.width {
+                    if let Some(cell) = self.cell_mut(x, self.cursor.y) {
+                        *cell = blank.clone();
+                    }
+                }
+            }
+            ("?25", Some('l')) => self.cursor_visible = false,
+            ("?25", Some('h')) => self.cursor_visible = true,
+            (_, Some('q')) => self.cursor_shape = numbers.first().copied().flatten(),
+            (_, Some('m')) => self.select_graphic_rendition(&numbers),
+            _ => (),
+        }
+        rest.as_str()
+    }
+    fn select_graphic_rendition(&mut self, numbers: &[Option<usize>]) {
+        let mut numbers = numbers.iter().map(|number| number.unwrap_or(0));
+        while let Some(number) = numbers.next() {
+            match number {
+                0 => {
+                    self.fg = None;
+                    self.bg = None;
+                }
+                38 | 48 => {
+                    let mut next = || numbers.next().and_then(|n| u8::try_from(n).ok()).unwrap_or(0);
+                    // Only truecolor, `2;r;g;b`, is drawn
+                    let color = (next() == 2).then(|| Rgb(next(), next(), next()));
+                    if number == 38 {
+                        self.fg = color;
+                    } else {
+                        self.bg = color;
+                    }
+                }
+                39 => self.fg = None,
+                49 => self.bg = None,
+                _ => (),
+            }
+        }
+    }
+    /// What to send a terminal showing `shown` so it shows this screen: only the cells
+    /// that changed, reached by the shortest cursor movement, with colours set only where
+    /// they differ from the cell drawn before. Without `shown`, or if its size differs,
+    /// the terminal is cleared and everything drawn. Leaves the terminal's colours at
+    /// their defaults
+    #[must_use]
+    pub fn diff(&self, shown: Option<&Self>) -> String {
+        let mut pen = Pen::default();
+        let cleared;
+        let shown = match shown {
+            Some(shown) if shown.width == self.width && shown.height == self.height => {
+                pen.cursor = (shown.cursor.x < self.width).then_some(shown.cursor);
+                shown
+            }
+            _ => {
+                pen.output.push_str("\x1b[0m\x1b[2J");
+                cleared = Self::new(self.width, self.height);
+                &cleared
+            }
+        };
+        for (y, (row, shown_row)) in self.cells.chunks(self.width.max(1)).zip(shown.cells.chunks(self.width.max(1))).enumerate() {
+            let changed: Vec<usize> = row.iter().zip(shown_row).enumerate().filter(|(_, (cell, shown))| cell != shown).map(|(x, _)| x).collect();
+            for (index, &x) in changed.iter().enumerate() {
+                let rest = row.get(x..).unwrap_or_default();
+                // One erase beats drawing several blanks to the end of the row
+                if changed.len().saturating_sub(index) > 2 && rest.iter().all(Cell::is_blank) {
+                    pen.move_to(Position { x, y });
+                    pen.set_colors(pen.fg, None);
+                    pen.output.push_str("\x1b[K");
+                    break;
+                }
+                pen.skip_to(Position { x, y }, row);
+                pen.draw(rest.first(), self.width);
+            }
+        }
+        if self.cursor_shape != shown.cursor_shape {
+            if let Some(shape) = self.cursor_shape {
+                pen.output.push_str(&format!("\x1b[{} q", shape));
+            }
+        }
+        pen.set_colors(None, None);
+        pen.move_to(Position { x: self.cursor.x.min(self.width.saturating_sub(1)), y: self.cursor.y });
+        if self.cursor_visible != shown.cursor_visible {
+            pen.output.push_str(if self.cursor_visible { "\x1b[?25h" } else { "\x1b[?25l" });
+        }
+        pen.output
+    }
+}
+
+/// The terminal's state while a diff is written to it
+#[derive(Default)]
+struct Pen {
+    output: String,
+    /// `None` when not known, e.g. after drawing in the last column
+    cursor: Option<Position>,
+    fg: Option<Rgb>,
+    bg: Option<Rgb>,
+}
+
+impl Pen {
+    fn set_colors(&mut self, fg: Option<Rgb>, bg: Option<Rgb>) {
+        let mut parameters = vec![];
+        if (fg, bg) != (self.fg, self.bg) && fg.is_none() && bg.is_none() {
+            parameters.push("0".to_owned());
+        } else {
+            if fg != self.fg {
+                parameters.push(fg.map_or_else(|| "39".to_owned(), |Rgb(r, g, b)| format!("38;2;{};{};{}", r, g, b)));
+            }
+            if bg != self.bg {
+                parameters.push(bg.map_or_else(|| "49".to_owned(), |Rgb(r, g, b)| format!("48;2;{};{};{}", r, g, b)));
+            }
+        }
+        if !parameters.is_empty() {
+            self.output.push_str(&format!("\x1b[{}m", parameters.join(";")));
+        }
+        self.fg = fg;
+        self.bg = bg;
+    }
+    /// Moves to `to` in `row`, by drawing the cells in between again if that is shorter
+    fn skip_to(&mut self, to: Position, row: &[Cell]) {
+        if let Some(cursor) = self.cursor {
+            let gap = row.get(cursor.x..to.x).unwrap_or_default();
+            if cursor.y == to.y && !gap.is_empty() && gap.len() <= MAX_REDRAWN_GAP && gap.iter().all(|cell| (cell.fg, cell.bg) == (self.fg, self.bg)) {
+                for cell in gap {
+                    self.output.push_str(&cell.symbol);
+                }
+                self.cursor = Some(to);
+                return;
+            }
+        }
+        self.move_to(to);
+    }
+    /// Moves the cursor by whichever is shorter: going straight to `to` or moving from
+    /// where the cursor is
+    fn move_to(&mut self, to: Position) {
+        let absolute = format!("\x1b[{};{}H", to.y.saturating_add(1), to.x.saturating_add(1));
+        let sequence = match self.cursor {
+            Some(cursor) if cursor == to => String::new(),
+            Some(cursor) => {
+                let step = |count: usize, letter: char| match count {
+                    0 => String::new(),
+                    1 => format!("\x1b[{}", letter),
+                    _ => format!("\x1b[{}{}", count, letter),
+                };
+                let vertical = if to.y < cursor.y { step(cursor.y.saturating_sub(to.y), 'A') } else { step(to.y.saturating_sub(cursor.y), 'B') };
+                let horizontal = if to.x == 0 && cursor.x > 0 {
+                    "\r".to_owned()
+                } else if to.x < cursor.x {
+                    step(cursor.x.saturating_sub(to.x), 'D')
+                } else {
+                    step(to.x.saturating_sub(cursor.x), 'C')
+                };
+                let relative = vertical + &horizontal;
+                if relative.len() < absolute.len() { relative } else { absolute }
+            }
+            None => absolute,
+        };
+        self.output.push_str(&sequence);
+        self.cursor = Some(to);
+    }
+    /// Draws `cell` at the cursor, which then moves on unless that was the last column
+    fn draw(&mut self, cell: Option<&Cell>, width: usize) {
+        if let (Some(cell), Some(cursor)) = (cell, self.cursor) {
+            self.set_colors(cell.fg, cell.bg);
+            self.output.push_str(&cell.symbol);
+            let x = cursor.x.saturating_add(1);
+            // The cursor waits at the edge until the next character, so where it is
+            // depends on the terminal
+            self.cursor = (x < width).then_some(Position { x, y: cursor.y });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(rows: &[&str]) -> Screen {
+        let mut screen = Screen::new(10, 3);
+        for (y, row) in rows.iter().enumerate() {
+            screen.write(&format!("\x1b[{};1H{}", y.saturating_add(1), row));
+        }
+        screen
+    }
+
+    #[test]
+    fn draws_what_changed() {
+        let shown = screen(&["one", "two", "three"]);
+        let mut frame = shown.clone();
+        frame.write(&format!("\x1b[2;2H{}W{}", termion::color::Fg(Rgb(1, 2, 3)), termion::color::Fg(termion::color::Reset)));
+        frame.write("\x1b[3;1H\x1b[2K\x1b[1;4H");
+        let diff = frame.diff(Some(&shown));
+        assert_eq!(diff, "\x1b[2;2H\x1b[38;2;1;2;3mW\x1b[B\r\x1b[K\x1b[0m\x1b[1;4H");
+        let mut terminal = shown;
+        terminal.write(&diff);
+        assert_eq!(terminal.rows(), ["one", "tWo", ""]);
+        assert_eq!(terminal.cell(1, 1).map(|cell| cell.fg), Some(Some(Rgb(1, 2, 3))));
+        assert_eq!(terminal.cursor().map(|cursor| (cursor.x, cursor.y)), Some((3, 0)));
+        assert_eq!(frame.diff(Some(&terminal)), "");
+    }
+
+    #[test]
+    fn redraws_everything_without_a_shown_frame() {
+        let frame = screen(&["one", "", "three"]);
+        let diff = frame.diff(None);
+        assert!(diff.starts_with("\x1b[0m\x1b[2J"));
+        let mut terminal = screen(&["old", "old", "old"]);
+        terminal.write(&diff);
+        assert_eq!(terminal.rows(), ["one", "", "three"]);
+    }
+}
@@ -0,0 +1,111 @@
+use regex::{Regex, RegexBuilder};
+use std::ops::Range;
+
+/// A compiled `/` search. The pattern is a regex with a few vim additions:
+/// - `\c` anywhere makes it case-insensitive and `\C` case-sensitive; without either it
+///   is case-insensitive unless it contains an uppercase letter (smartcase)
+/// - `\<` and `\>` match the start and end of a word, so `\<word\>` only finds whole words
+pub struct Query {
+    regex: Regex,
+}
+
+/// A match on row `y` spanning graphemes `start..end`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Match {
+    pub y: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Query {
+    /// # Errors
+    /// Will return `Err` with a message if the pattern is empty or not a valid regex
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let mut translated = String::new();
+        let (mut case, mut has_uppercase) = (None, false);
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                has_uppercase |= c.is_uppercase();
+                translated.push(c);
+                continue;
+            }
+            // Escaped letters are classes like `\S` and `\W`, which say nothing about case
+            match chars.next() {
+                Some('c') => case = Some(true),
+                Some('C') => case = Some(false),
+                Some('<' | '>') => translated.push_str(r"\b"),
+                Some(next) => {
+                    translated.push(c);
+                    translated.push(next);
+                }
+                None => translated.push(c),
+            }
+        }
+        if translated.is_empty() {
+            return Err("Empty pattern".to_owned());
+        }
+        let regex = RegexBuilder::new(&translated)
+            .case_insensitive(case.unwrap_or(!has_uppercase))
+            .build()
+            .map_err(|error| format!("Invalid pattern: {}", error))?;
+        Ok(Self { regex })
+    }
+    /// The byte ranges of every match in `text`, left to right
+    pub fn byte_ranges<'a>(&'a self, text: &'a str) -> impl Iterator<Item = Range<usize>> + 'a {
+        self.regex.find_iter(text).map(|found| found.range())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Document, Position, Row};
+
+    fn find(pattern: &str, text: &str) -> Vec<(usize, usize)> {
+        let row = Row::from(text);
+        row.find(&Query::parse(pattern).unwrap()).into_iter().map(|range| (range.start, range.end)).collect()
+    }
+
+    #[test]
+    fn finds_every_match_as_graphemes() {
+        assert_eq!(find("o", "foo boo"), [(1, 2), (2, 3), (5, 6), (6, 7)]);
+        assert_eq!(find("b", "ééé b é b"), [(4, 5), (8, 9)]);
+        assert_eq!(find("e\u{301}x", "ae\u{301}xe\u{301}x"), [(1, 3), (3, 5)]);
+        assert_eq!(find(r"\d+", "日本 12 語 345"), [(3, 5), (8, 11)]);
+        assert!(find("z", "abc").is_empty());
+    }
+
+    #[test]
+    fn uses_smartcase_unless_told_otherwise() {
+        assert_eq!(find("foo", "Foo foo FOO"), [(0, 3), (4, 7), (8, 11)]);
+        assert_eq!(find("Foo", "Foo foo FOO"), [(0, 3)]);
+        assert_eq!(find(r"Foo\c", "Foo foo FOO"), [(0, 3), (4, 7), (8, 11)]);
+        assert_eq!(find(r"\Cfoo", "Foo foo FOO"), [(4, 7)]);
+        assert_eq!(find(r"\S+", "ab CD"), [(0, 2), (3, 5)]);
+    }
+
+    #[test]
+    fn matches_whole_words() {
+        assert_eq!(find(r"\<in\>", "in inner pin in"), [(0, 2), (13, 15)]);
+        assert_eq!(find(r"\bin\b", "in inner"), [(0, 2)]);
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(Query::parse("").is_err());
+        assert!(Query::parse(r"\c").is_err());
+        assert!(Query::parse("(").is_err());
+    }
+
+    #[test]
+    fn document_find_reports_rows() {
+        let mut document = Document::default();
+        document.insert_text(&Position::default(), "añb\nb b");
+        let matches = document.find(&Query::parse("b").unwrap());
+        assert_eq!(
+            matches,
+            [Match { y: 0, start: 2, end: 3 }, Match { y: 1, start: 0, end: 1 }, Match { y: 1, start: 2, end: 3 }]
+        );
+    }
+}
@@ -0,0 +1,363 @@
+use crate::{Document, Position};
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum SelectionKind {
+    Char,
+    Line,
+    Block,
+}
+
+#[derive(Clone, Copy)]
+pub enum Case {
+    Toggle,
+    Lower,
+    Upper,
+}
+
+/// Text taken out of the document by a yank or delete, remembering how it was selected
+/// so it can be put back the same way
+#[derive(Clone)]
+pub struct Yank {
+    pub text: String,
+    pub kind: SelectionKind,
+}
+
+impl Yank {
+    /// Puts the text after the cursor (`before` puts it before), on new rows for a linewise
+    /// yank and as a column for a blockwise one. Returns where the cursor should go
+    pub fn put(&self, document: &mut Document, at: &Position, before: bool) -> Position {
+        let row_len = |document: &Document, y: usize| document.row(y).map_or(0, crate::Row::len);
+        match self.kind {
+            SelectionKind::Line => {
+                let text = self.text.strip_suffix('\n').unwrap_or(&self.text);
+                // Only a document with no text at all has its one row replaced
+                if document.len() <= 1 && document.row(0).is_none_or(crate::Row::is_empty) {
+                    document.insert_text(&Position { x: 0, y: 0 }, text);
+                    Position { x: 0, y: 0 }
+                } else if before {
+                    document.insert_text(&Position { x: 0, y: at.y }, &format!("{}\n", text));
+                    Position { x: 0, y: at.y }
+                } else {
+                    document.insert_text(&Position { x: row_len(document, at.y), y: at.y }, &format!("\n{}", text));
+                    Position { x: 0, y: at.y.saturating_add(1) }
+                }
+            }
+            SelectionKind::Char => {
+                let x = if before { at.x } else { at.x.saturating_add(1).min(row_len(document, at.y)) };
+                document.insert_text(&Position { x, y: at.y }, &self.text);
+                if self.text.contains('\n') {
+                    Position { x, y: at.y }
+                } else {
+                    Position { x: x.saturating_add(self.text.graphemes(true).count()).saturating_sub(1), y: at.y }
+                }
+            }
+            SelectionKind::Block => {
+                let x = if before || row_len(document, at.y) == 0 { at.x } else { at.x.saturating_add(1) };
+                for (y, piece) in (at.y..).zip(self.text.split('\n')) {
+                    if y >= document.len() {
+                        let last = document.len().saturating_sub(1);
+                        document.insert_text(&Position { x: row_len(document, last), y: last }, "\n");
+                    }
+                    let padding = x.saturating_sub(row_len(document, y));
+                    let mut text = " ".repeat(padding);
+                    text.push_str(piece);
+                    document.insert_text(&Position { x: x.saturating_sub(padding), y }, &text);
+                }
+                Position { x, y: at.y }
+            }
+        }
+    }
+}
+
+/// The region between the `anchor` (where Visual mode started) and the `cursor`, both inclusive
+pub struct Selection {
+    pub anchor: Position,
+    pub cursor: Position,
+    pub kind: SelectionKind,
+}
+
+impl Selection {
+    /// A linewise selection of rows `first..=last`
+    #[must_use]
+    pub fn lines(first: usize, last: usize) -> Self {
+        Self {
+            anchor: Position { x: 0, y: first },
+            cursor: Position { x: 0, y: last },
+            kind: SelectionKind::Line,
+        }
+    }
+    #[must_use] pub fn start(&self) -> Position {
+        if (self.anchor.y, self.anchor.x) <= (self.cursor.y, self.cursor.x) {
+            self.anchor
+        } else {
+            self.cursor
+        }
+    }
+    #[must_use] pub fn end(&self) -> Position {
+        if (self.anchor.y, self.anchor.x) <= (self.cursor.y, self.cursor.x) {
+            self.cursor
+        } else {
+            self.anchor
+        }
+    }
+    /// The first and last selected rows
+    #[must_use] pub fn rows(&self) -> (usize, usize) {
+        (self.start().y, self.end().y)
+    }
+    /// The selected graphemes of row `y` as `(start, end)`, `end` exclusive
+    #[must_use] pub fn columns(&self, y: usize, row_len: usize) -> Option<(usize, usize)> {
+        let (first, last) = self.rows();
+        if y < first || y > last {
+            return None;
+        }
+        let (start, end) = match self.kind {
+            SelectionKind::Line => (0, row_len),
+            SelectionKind::Block => (
+                self.anchor.x.min(self.cursor.x),
+                self.anchor.x.max(self.cursor.x).saturating_add(1),
+            ),
+            SelectionKind::Char => (
+                if y == first { self.start().x } else { 0 },
+                if y == last { self.end().x.saturating_add(1) } else { row_len },
+            ),
+        };
+        Some((start.min(row_len), end.min(row_len)))
+    }
+    /// The selected text of a charwise selection as a `start..end` span, taking in the
+    /// line break when the selection ends past the last grapheme of a row
+    fn span(&self, document: &Document) -> (Position, Position) {
+        let end = self.end();
+        let row_len = document.row(end.y).map_or(0, crate::Row::len);
+        let end = if end.x < row_len {
+            Position { x: end.x.saturating_add(1), y: end.y }
+        } else if end.y.saturating_add(1) < document.len() {
+            Position { x: 0, y: end.y.saturating_add(1) }
+        } else {
+            Position { x: row_len, y: end.y }
+        };
+        (self.start(), end)
+    }
+    #[must_use]
+    pub fn yank(&self, document: &Document) -> Yank {
+        let (first, last) = self.rows();
+        let text = match self.kind {
+            SelectionKind::Char => {
+                let (start, end) = self.span(document);
+                document.text_range(&start, &end)
+            }
+            SelectionKind::Line => {
+                let end = Position { x: document.row(last).map_or(0, crate::Row::len), y: last };
+                let mut text = document.text_range(&Position { x: 0, y: first }, &end);
+                text.push('\n');
+                text
+            }
+            SelectionKind::Block => {
+                let rows: Vec<String> = (first..=last)
+                    .filter_map(|y| {
+                        let row = document.row(y)?;
+                        let (start, end) = self.columns(y, row.len())?;
+                        Some(row.slice(start, end).to_owned())
+                    })
+                    .collect();
+                rows.join("\n")
+            }
+        };
+        Yank { text, kind: self.kind }
+    }
+    /// Deletes the selection, returning what was deleted and where the cursor should go
+    pub fn delete(&self, document: &mut Document) -> (Yank, Position) {
+        let yank = self.yank(document);
+        let (first, last) = self.rows();
+        let cursor = match self.kind {
+            SelectionKind::Char => {
+                let (start, end) = self.span(document);
+                document.delete_range(&start, &end);
+                start
+            }
+            SelectionKind::Line => {
+                let row_len = |document: &Document, y: usize| document.row(y).map_or(0, crate::Row::len);
+                if last.saturating_add(1) < document.len() {
+                    document.delete_range(&Position { x: 0, y: first }, &Position { x: 0, y: last.saturating_add(1) });
+                } else if first > 0 {
+                    let previous = first.saturating_sub(1);
+                    let start = Position { x: row_len(document, previous), y: previous };
+                    let end = Position { x: row_len(document, last), y: last };
+                    document.delete_range(&start, &end);
+                } else {
+                    let end = Position { x: row_len(document, last), y: last };
+                    document.delete_range(&Position { x: 0, y: 0 }, &end);
+                }
+                Position { x: 0, y: first.min(document.len().saturating_sub(1)) }
+            }
+            SelectionKind::Block => {
+                for y in first..=last {
+                    let row_len = document.row(y).map_or(0, crate::Row::len);
+                    if let Some((start, end)) = self.columns(y, row_len) {
+                        document.delete_range(&Position { x: start, y }, &Position { x: end, y });
+                    }
+                }
+                Position { x: self.anchor.x.min(self.cursor.x), y: first }
+            }
+        };
+        (yank, cursor)
+    }
+    /// Deletes the selection ahead of inserting a replacement, keeping an empty row in
+    /// place of selected lines
+    pub fn change(&self, document: &mut Document) -> (Yank, Position) {
+        if self.kind != SelectionKind::Line {
+            return self.delete(document);
+        }
+        let yank = self.yank(document);
+        let (first, last) = self.rows();
+        let end = Position { x: document.row(last).map_or(0, crate::Row::len), y: last };
+        document.delete_range(&Position { x: 0, y: first }, &end);
+        (yank, Position { x: 0, y: first })
+    }
+    /// Changes the case of every selected letter
+    pub fn convert_case(&self, document: &mut Document, case: Case) {
+        let (first, last) = self.rows();
+        for y in first..=last {
+            let (start, end, text) = match document.row(y) {
+                Some(row) => match self.columns(y, row.len()) {
+                    Some((start, end)) => (start, end, row.slice(start, end).to_owned()),
+                    None => continue,
+                },
+                None => continue,
+            };
+            let converted: String = text
+                .chars()
+                .flat_map(|c| match case {
+                    Case::Toggle if c.is_lowercase() => c.to_uppercase().collect::<Vec<_>>(),
+                    Case::Toggle | Case::Lower => c.to_lowercase().collect::<Vec<_>>(),
+                    Case::Upper => c.to_uppercase().collect::<Vec<_>>(),
+                })
+                .collect();
+            if converted != text {
+                document.delete_range(&Position { x: start, y }, &Position { x: end, y });
+                document.insert_text(&Position { x: start, y }, &converted);
+            }
+        }
+    }
+    /// Adds `indent` to the start of every selected, non-empty row
+    pub fn indent(&self, document: &mut Document, indent: &str) {
+        let (first, last) = self.rows();
+        for y in first..=last {
+            if document.row(y).is_some_and(|row| !row.is_empty()) {
+                document.insert_text(&Position { x: 0, y }, indent);
+            }
+        }
+    }
+    /// Removes one level of indentation (a tab or up to `tab_width` spaces) from every selected row
+    pub fn outdent(&self, document: &mut Document, tab_width: usize) {
+        let (first, last) = self.rows();
+        for y in first..=last {
+            let width = match document.row(y) {
+                Some(row) if row.grapheme(0) == Some("\t") => 1,
+                Some(row) => (0..tab_width).take_while(|&x| row.grapheme(x) == Some(" ")).count(),
+                None => 0,
+            };
+            if width > 0 {
+                document.delete_range(&Position { x: 0, y }, &Position { x: width, y });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "one two\nthree four\nfive";
+
+    fn select(kind: SelectionKind, anchor: (usize, usize), cursor: (usize, usize)) -> Selection {
+        Selection {
+            anchor: Position { x: anchor.0, y: anchor.1 },
+            cursor: Position { x: cursor.0, y: cursor.1 },
+            kind,
+        }
+    }
+
+    #[test]
+    fn yanks_each_kind() {
+        let document = Document::scratch(TEXT);
+        // The cursor may be before the anchor, and both ends are included
+        let chars = select(SelectionKind::Char, (2, 1), (4, 0));
+        assert_eq!(chars.yank(&document).text, "two\nthr");
+        assert_eq!(chars.rows(), (0, 1));
+        assert_eq!(select(SelectionKind::Char, (4, 0), (7, 0)).yank(&document).text, "two\n");
+        assert_eq!(select(SelectionKind::Line, (3, 1), (0, 2)).yank(&document).text, "three four\nfive\n");
+        let block = select(SelectionKind::Block, (6, 0), (2, 2));
+        assert_eq!(block.yank(&document).text, "e two\nree f\nve");
+        assert_eq!(block.columns(2, 4), Some((2, 4)));
+        assert_eq!(block.columns(3, 4), None);
+    }
+
+    #[test]
+    fn deletes_each_kind() {
+        let mut document = Document::scratch(TEXT);
+        let (yank, cursor) = select(SelectionKind::Char, (4, 0), (2, 1)).delete(&mut document);
+        assert_eq!((document.text(), yank.text), ("one ee four\nfive".to_owned(), "two\nthr".to_owned()));
+        assert!(cursor == Position { x: 4, y: 0 });
+
+        let mut document = Document::scratch(TEXT);
+        let (yank, cursor) = Selection::lines(1, 2).delete(&mut document);
+        assert_eq!((document.text(), yank.text), ("one two".to_owned(), "three four\nfive\n".to_owned()));
+        assert!(cursor == Position { x: 0, y: 0 });
+
+        let mut document = Document::scratch(TEXT);
+        let (yank, cursor) = select(SelectionKind::Block, (1, 0), (2, 2)).delete(&mut document);
+        assert_eq!((document.text(), yank.text), ("o two\ntee four\nfe".to_owned(), "ne\nhr\niv".to_owned()));
+        assert!(cursor == Position { x: 1, y: 0 });
+    }
+
+    #[test]
+    fn puts_yanks_back_the_way_they_were_taken() {
+        let mut document = Document::scratch(TEXT);
+        let selection = select(SelectionKind::Block, (0, 0), (2, 1));
+        let (yank, cursor) = selection.delete(&mut document);
+        yank.put(&mut document, &cursor, true);
+        assert_eq!(document.text(), TEXT);
+        let (yank, cursor) = Selection::lines(0, 0).delete(&mut document);
+        yank.put(&mut document, &cursor, false);
+        assert_eq!(document.text(), "three four\none two\nfive");
+    }
+
+    #[test]
+    fn puts_lines_below_a_blank_first_row() {
+        let yank = Yank { text: "new\n".to_owned(), kind: SelectionKind::Line };
+        let mut document = Document::scratch("\nfoo\nbar");
+        let cursor = yank.put(&mut document, &Position { x: 0, y: 2 }, false);
+        assert_eq!((cursor.x, cursor.y), (0, 3));
+        assert_eq!(document.text(), "\nfoo\nbar\nnew");
+        yank.put(&mut document, &Position { x: 0, y: 0 }, true);
+        assert_eq!(document.text(), "new\n\nfoo\nbar\nnew");
+        let mut document = Document::scratch("");
+        yank.put(&mut document, &Position::default(), false);
+        assert_eq!(document.text(), "new");
+    }
+
+    #[test]
+    fn indents_and_outdents_rows() {
+        let mut document = Document::scratch("a\n\n\tb\n      c");
+        let selection = Selection::lines(0, 3);
+        selection.indent(&mut document, "  ");
+        assert_eq!(document.text(), "  a\n\n  \tb\n        c");
+        selection.outdent(&mut document, 4);
+        assert_eq!(document.text(), "a\n\n\tb\n    c");
+        // A charwise selection still indents whole rows
+        select(SelectionKind::Char, (0, 2), (0, 3)).outdent(&mut document, 4);
+        assert_eq!(document.text(), "a\n\nb\nc");
+    }
+
+    #[test]
+    fn converts_case() {
+        let mut document = Document::scratch("Hello World\nagain");
+        select(SelectionKind::Char, (3, 0), (7, 0)).convert_case(&mut document, Case::Toggle);
+        assert_eq!(document.text(), "HelLO wOrld\nagain");
+        select(SelectionKind::Block, (0, 0), (1, 1)).convert_case(&mut document, Case::Upper);
+        assert_eq!(document.text(), "HElLO wOrld\nAGain");
+        Selection::lines(0, 1).convert_case(&mut document, Case::Lower);
+        assert_eq!(document.text(), "hello world\nagain");
+    }
+}
@@ -0,0 +1,185 @@
+use regex::{Regex, RegexBuilder};
+use std::ops::Range;
+
+/// A parsed `:s/pattern/replacement/flags`
+pub struct Substitution {
+    regex: Regex,
+    /// The replacement in the `regex` crate's expansion syntax (`${1}`, `$name`)
+    replacement: String,
+    /// `g`: replace every match in a row rather than just the first
+    pub global: bool,
+    /// `c`: ask before each replacement
+    pub confirm: bool,
+}
+
+impl Substitution {
+    /// Parses what follows `:s`: a delimiter (any punctuation, usually `/`), the pattern,
+    /// the replacement and any of the flags `g`, `i` and `c`. In the replacement, `\1`
+    /// and `$1` insert a capture group, `$name` a named one, `&` the whole match and `\r`
+    /// a line break
+    /// # Errors
+    /// Will return `Err` with a message if the pattern is invalid or a flag unknown
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim_start();
+        let delimiter = match text.chars().next() {
+            Some(c) if !c.is_alphanumeric() && !c.is_whitespace() && c != '\\' && c != '"' => c,
+            _ => return Err("Expected /pattern/replacement/".to_owned()),
+        };
+        let mut fields = split(text.get(delimiter.len_utf8()..).unwrap_or(""), delimiter).into_iter();
+        let pattern = fields.next().unwrap_or_default();
+        let replacement = fields.next().unwrap_or_default();
+        let flags = fields.next().unwrap_or_default();
+        if pattern.is_empty() {
+            return Err("Empty pattern".to_owned());
+        }
+        let (mut global, mut confirm, mut ignore_case) = (false, false, false);
+        for flag in flags.trim_end().chars() {
+            match flag {
+                'g' => global = true,
+                'c' => confirm = true,
+                'i' => ignore_case = true,
+                'I' => ignore_case = false,
+                _ => return Err(format!("Invalid flag {}", flag)),
+            }
+        }
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|error| format!("Invalid pattern: {}", error))?;
+        Ok(Self {
+            regex,
+            replacement: translate_replacement(&replacement),
+            global,
+            confirm,
+        })
+    }
+    #[must_use] pub fn pattern(&self) -> &str {
+        self.regex.as_str()
+    }
+    /// The byte ranges of `text` to replace (only the first unless `global`), each with
+    /// the text to replace it with
+    #[must_use]
+    pub fn matches(&self, text: &str) -> Vec<(Range<usize>, String)> {
+        let mut matches = vec![];
+        for captures in self.regex.captures_iter(text) {
+            let whole = match captures.get(0) {
+                Some(whole) => whole,
+                None => continue,
+            };
+            let mut replacement = String::new();
+            captures.expand(&self.replacement, &mut replacement);
+            matches.push((whole.range(), replacement));
+            if !self.global {
+                break;
+            }
+        }
+        matches
+    }
+}
+
+/// Splits `text` on unescaped `delimiter`s, unescaping them. Other escapes are kept for the
+/// pattern and replacement to interpret
+fn split(text: &str, delimiter: char) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == delimiter && fields.len() < 3 {
+            fields.push(String::new());
+            continue;
+        }
+        let field = match fields.last_mut() {
+            Some(field) => field,
+            None => break,
+        };
+        if c == '\\' {
+            match chars.next() {
+                // An escaped delimiter that means something in a regex stays escaped
+                Some(next) if next == delimiter && regex::escape(&next.to_string()).len() == 1 => field.push(next),
+                Some(next) => {
+                    field.push(c);
+                    field.push(next);
+                }
+                None => field.push(c),
+            }
+        } else {
+            field.push(c);
+        }
+    }
+    fields
+}
+
+/// Turns vim-style replacement escapes into the `regex` crate's `$` syntax, which is
+/// otherwise left as is so `$1` and `$name` work too
+fn translate_replacement(replacement: &str) -> String {
+    let mut translated = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => translated.push_str("${0}"),
+            '\\' => match chars.next() {
+                Some(digit) if digit.is_ascii_digit() => {
+                    translated.push_str("${");
+                    translated.push(digit);
+                    translated.push('}');
+                }
+                Some('n' | 'r') => translated.push('\n'),
+                Some('t') => translated.push('\t'),
+                Some('$') => translated.push_str("$$"),
+                Some(other) => translated.push(other),
+                None => translated.push('\\'),
+            },
+            _ => translated.push(c),
+        }
+    }
+    translated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replace(command: &str, text: &str) -> String {
+        let substitution = Substitution::parse(command).unwrap();
+        let mut result = text.to_owned();
+        for (range, replacement) in substitution.matches(text).into_iter().rev() {
+            result.replace_range(range, &replacement);
+        }
+        result
+    }
+
+    #[test]
+    fn replaces_first_or_every_match() {
+        assert_eq!(replace("/o/0/", "foo boo"), "f0o boo");
+        assert_eq!(replace("/o/0/g", "foo boo"), "f00 b00");
+        assert_eq!(replace("/O/0/gi", "foo"), "f00");
+        assert_eq!(replace("/foo", "a foo b"), "a  b");
+        assert_eq!(replace("/^/# /", "line"), "# line");
+    }
+
+    #[test]
+    fn expands_captures() {
+        assert_eq!(replace(r"/(\w+) (\w+)/\2 \1/", "hello world"), "world hello");
+        assert_eq!(replace(r"/(?P<key>\w+)=(?P<value>\w+)/$value=$key/", "a=b"), "b=a");
+        assert_eq!(replace(r"/\d+/[&]/g", "1 22"), "[1] [22]");
+        assert_eq!(replace(r"/\d+/\&/", "1"), "&");
+        assert_eq!(replace(r"/, /\r/g", "a, b"), "a\nb");
+    }
+
+    #[test]
+    fn handles_delimiters_and_escapes() {
+        assert_eq!(replace(r"#/usr/bin#/opt#", "/usr/bin/env"), "/opt/env");
+        assert_eq!(replace(r"/a\/b/c/", "a/b"), "c");
+        assert_eq!(replace(r"|a\|b|c|g", "a|b"), "c");
+        assert_eq!(replace(r"/x/\\/", "x"), "\\");
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert!(Substitution::parse("").is_err());
+        assert!(Substitution::parse("//x/").is_err());
+        assert!(Substitution::parse("/(/x/").is_err());
+        assert!(Substitution::parse("/a/b/z").is_err());
+        let substitution = Substitution::parse("/a/b/gc").unwrap();
+        assert!(substitution.global && substitution.confirm);
+    }
+}
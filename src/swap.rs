@@ -0,0 +1,139 @@
+use crate::save::{self, Backup};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "lecturn swap file";
+
+/// A swap file read back from disk: the unsaved text of a document and the process that
+/// wrote it
+pub struct Swap {
+    pub pid: u32,
+    pub text: String,
+}
+
+/// What a document knows about its swap file
+#[derive(Default)]
+pub struct State {
+    /// There are changes the swap file does not have yet
+    pub stale: bool,
+    /// This process wrote the swap file, so it is the one to remove it
+    pub written: bool,
+    /// Writing it failed, which is only reported once
+    pub failed: bool,
+}
+
+/// The swap file for `file_name`, e.g. `src/.main.rs.swp`
+#[must_use]
+pub fn path_for(file_name: &str) -> PathBuf {
+    let path = Path::new(file_name);
+    let name = path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    path.with_file_name(format!(".{}.swp", name))
+}
+
+/// Writes `text` as the swap file at `path`, unless it belongs to another process that
+/// is still running
+/// # Errors
+/// Will return `Err` if the file cannot be written, or with `ErrorKind::AlreadyExists`
+/// if another running process owns it
+pub fn write(path: &Path, file_name: &str, text: &str) -> io::Result<()> {
+    if let Ok(swap) = read(path) {
+        if swap.pid != std::process::id() && is_running(swap.pid) {
+            let message = format!("{} is being edited by process {}", file_name, swap.pid);
+            return Err(Error::new(ErrorKind::AlreadyExists, message));
+        }
+    }
+    let contents = format!("{}\npid: {}\nfile: {}\n\n{}", HEADER, std::process::id(), file_name, text);
+    save::write_atomic(path, contents.as_bytes(), &Backup::Off).map_err(|error| Error::other(error.to_string()))
+}
+
+/// # Errors
+/// Will return `Err` if the file cannot be read or is not a swap file
+pub fn read(path: &Path) -> io::Result<Swap> {
+    let contents = fs::read_to_string(path)?;
+    let invalid = || Error::new(ErrorKind::InvalidData, "not a swap file");
+    let (header, text) = contents.split_once("\n\n").ok_or_else(invalid)?;
+    let mut lines = header.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(invalid());
+    }
+    let pid = lines
+        .find_map(|line| line.strip_prefix("pid: "))
+        .and_then(|pid| pid.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok(Swap { pid, text: text.to_owned() })
+}
+
+/// Whether a process with id `pid` exists, for telling a crashed session's swap file
+/// from one still in use
+#[cfg(unix)]
+#[must_use]
+pub fn is_running(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+    #[allow(unsafe_code)]
+    // SAFETY: signal 0 sends nothing, it only checks that the process could be signalled
+    let result = unsafe { libc::kill(pid, 0) };
+    // EPERM means the process exists but belongs to another user
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+#[must_use]
+pub fn is_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn round_trips_text() {
+        let path = env::temp_dir().join(format!("lecturn-swap-{}.swp", std::process::id()));
+        write(&path, "notes.txt", "first\n\nthird").unwrap();
+        let swap = read(&path).unwrap();
+        assert_eq!(swap.pid, std::process::id());
+        assert_eq!(swap.text, "first\n\nthird");
+        let _ = fs::remove_file(path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn leaves_swap_files_of_running_processes() {
+        let path = env::temp_dir().join(format!("lecturn-swap-owned-{}.swp", std::process::id()));
+        let parent = std::os::unix::process::parent_id();
+        fs::write(&path, format!("{}\npid: {}\nfile: notes.txt\n\ntheirs", HEADER, parent)).unwrap();
+        let error = write(&path, "notes.txt", "ours").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(read(&path).unwrap().text, "theirs");
+        let _ = fs::remove_file(path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tells_running_processes_from_finished_ones() {
+        assert!(is_running(std::process::id()));
+        // Running as another user, if not root
+        assert!(is_running(1));
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert!(!is_running(pid));
+        assert!(!is_running(0));
+        assert!(!is_running(u32::MAX));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = env::temp_dir().join(format!("lecturn-swap-invalid-{}.swp", std::process::id()));
+        fs::write(&path, "just some text\n\nmore").unwrap();
+        assert!(read(&path).is_err());
+        let _ = fs::remove_file(path);
+        assert_eq!(path_for("src/main.rs"), Path::new("src/.main.rs.swp"));
+    }
+}
@@ -0,0 +1,305 @@
+use crate::highlighting;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use unicode_segmentation::UnicodeSegmentation;
+
+const BUILT_IN_LANGUAGES: [&str; 6] = [
+    include_str!("languages/rust.toml"),
+    include_str!("languages/c.toml"),
+    include_str!("languages/python.toml"),
+    include_str!("languages/markdown.toml"),
+    include_str!("languages/toml.toml"),
+    include_str!("languages/json.toml"),
+];
+
+/// The highlighting state carried from the end of one row to the start of the next:
+/// the index of the multi-line region (block comment, string...) still open, if any
+pub type State = Option<usize>;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Keyword,
+    Type,
+    String,
+    Comment,
+    Number,
+}
+
+impl Kind {
+    #[must_use]
+    pub fn highlight(self) -> highlighting::Type {
+        match self {
+            Kind::Keyword => highlighting::Type::Keyword,
+            Kind::Type => highlighting::Type::DataType,
+            Kind::String => highlighting::Type::String,
+            Kind::Comment => highlighting::Type::Comment,
+            Kind::Number => highlighting::Type::Number,
+        }
+    }
+}
+
+/// Text between a `start` and `end` delimiter, e.g. a string or block comment
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    start: String,
+    end: String,
+    kind: Kind,
+    #[serde(default)]
+    escape: bool,
+    #[serde(default)]
+    multiline: bool,
+}
+
+/// A row that is highlighted as a whole when it starts with `prefix`, e.g. a Markdown heading
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LineRule {
+    prefix: String,
+    kind: Kind,
+}
+
+/// A language definition, read from the same TOML format for built-in and user languages
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Language {
+    pub name: String,
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    types: Vec<String>,
+    #[serde(default)]
+    line_comment: Option<String>,
+    #[serde(default)]
+    numbers: bool,
+    #[serde(default)]
+    regions: Vec<Region>,
+    #[serde(default)]
+    lines: Vec<LineRule>,
+}
+
+impl Language {
+    /// Loads the user's language definitions from `<config dir>/languages/*.toml`
+    /// followed by the built-in ones, so user definitions take precedence.
+    /// Returns the languages alongside a message for every file that could not be loaded
+    /// # Panics
+    /// Will panic if a built-in definition is invalid
+    #[must_use]
+    pub fn load_all() -> (Vec<Self>, Vec<String>) {
+        let mut languages = vec![];
+        let mut errors = vec![];
+        if let Some(directory) = crate::config_dir().map(|dir| dir.join("languages")) {
+            if let Ok(entries) = fs::read_dir(directory) {
+                let mut paths: Vec<_> = entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect();
+                paths.sort();
+                for path in paths.iter().filter(|path| path.extension().is_some_and(|ext| ext == "toml")) {
+                    match Self::from_file(path) {
+                        Ok(language) => languages.push(language),
+                        Err(error) => errors.push(format!("{}: {}", path.display(), error)),
+                    }
+                }
+            }
+        }
+        languages.extend(Self::built_in());
+        (languages, errors)
+    }
+    /// The languages that come with the editor
+    /// # Panics
+    /// Will panic if a built-in definition is invalid
+    #[must_use]
+    #[allow(clippy::expect_used)]
+    pub fn built_in() -> Vec<Self> {
+        BUILT_IN_LANGUAGES
+            .iter()
+            .map(|definition| toml::from_str(definition).expect("Invalid built-in language definition"))
+            .collect()
+    }
+    /// # Errors
+    /// Will return `Err` with a description if the file cannot be read or parsed
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|error| error.to_string())?;
+        toml::from_str(&contents).map_err(|error| error.to_string())
+    }
+    /// Picks the language for `file_name` based on its extension
+    #[must_use]
+    pub fn detect<'a>(languages: &'a [Self], file_name: &str) -> Option<&'a Self> {
+        let extension = Path::new(file_name).extension()?.to_str()?;
+        languages
+            .iter()
+            .find(|language| language.extensions.iter().any(|ext| ext == extension))
+    }
+
+    /// Highlights a row starting in `state`, returning the highlighting of each
+    /// grapheme and the state the next row starts in
+    #[must_use]
+    pub fn highlight(&self, text: &str, mut state: State) -> (Vec<highlighting::Type>, State) {
+        let graphemes: Vec<(usize, &str)> = text.grapheme_indices(true).collect();
+        let mut result = vec![highlighting::Type::None; graphemes.len()];
+
+        if state.is_none() {
+            let trimmed = text.trim_start();
+            if let Some(rule) = self.lines.iter().find(|rule| trimmed.starts_with(&rule.prefix)) {
+                result.fill(rule.kind.highlight());
+                return (result, None);
+            }
+        }
+
+        let mut i = 0;
+        let mut after_separator = true;
+        while let Some(&(byte_index, grapheme)) = graphemes.get(i) {
+            let rest = text.get(byte_index..).unwrap_or("");
+            if let Some(region) = state.and_then(|index| self.regions.get(index)) {
+                let kind = region.kind.highlight();
+                let length = if region.escape && grapheme == "\\" {
+                    2
+                } else if rest.starts_with(&region.end) {
+                    state = None;
+                    after_separator = true;
+                    region.end.graphemes(true).count()
+                } else {
+                    1
+                };
+                fill(&mut result, i, length, kind);
+                i = i.saturating_add(length);
+                continue;
+            }
+            if let Some(comment) = &self.line_comment {
+                if rest.starts_with(comment) {
+                    fill(&mut result, i, graphemes.len(), highlighting::Type::Comment);
+                    break;
+                }
+            }
+            if let Some((index, region)) = self
+                .regions
+                .iter()
+                .enumerate()
+                .find(|&(_, region)| !region.start.is_empty() && rest.starts_with(&region.start))
+            {
+                let length = region.start.graphemes(true).count();
+                fill(&mut result, i, length, region.kind.highlight());
+                state = Some(index);
+                i = i.saturating_add(length);
+                continue;
+            }
+            if after_separator && is_word(grapheme) {
+                after_separator = false;
+                if self.numbers && grapheme.starts_with(|c: char| c.is_ascii_digit()) {
+                    let length = graphemes.iter().skip(i).take_while(|&&(_, g)| is_word(g) || g == ".").count();
+                    fill(&mut result, i, length, highlighting::Type::Number);
+                    i = i.saturating_add(length);
+                    continue;
+                }
+                let word_length = graphemes.iter().skip(i).take_while(|&&(_, g)| is_word(g)).count();
+                let end = graphemes.get(i.saturating_add(word_length)).map_or(text.len(), |&(index, _)| index);
+                let word = text.get(byte_index..end).unwrap_or("");
+                if self.keywords.iter().any(|keyword| keyword == word) {
+                    fill(&mut result, i, word_length, highlighting::Type::Keyword);
+                } else if self.types.iter().any(|data_type| data_type == word) {
+                    fill(&mut result, i, word_length, highlighting::Type::DataType);
+                }
+                i = i.saturating_add(word_length);
+                continue;
+            }
+            after_separator = !is_word(grapheme);
+            i = i.saturating_add(1);
+        }
+
+        if let Some(index) = state {
+            if !self.regions.get(index).is_some_and(|region| region.multiline) {
+                state = None;
+            }
+        }
+        (result, state)
+    }
+}
+
+#[must_use]
+pub fn is_word(grapheme: &str) -> bool {
+    grapheme.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn fill(result: &mut [highlighting::Type], start: usize, length: usize, highlight_type: highlighting::Type) {
+    for highlight in result.iter_mut().skip(start).take(length) {
+        *highlight = highlight_type;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use highlighting::Type;
+
+    fn language(name: &str) -> Language {
+        Language::built_in().into_iter().find(|language| language.name == name).unwrap()
+    }
+
+    /// Highlights each row in turn, one letter per grapheme: keyword, type, string,
+    /// comment, number, or a space for none
+    fn highlight(language: &Language, rows: &[&str]) -> Vec<String> {
+        let mut state = None;
+        rows.iter()
+            .map(|row| {
+                let (highlighting, next) = language.highlight(row, state);
+                state = next;
+                highlighting
+                    .iter()
+                    .map(|highlight| match highlight {
+                        Type::Keyword => 'k',
+                        Type::DataType => 't',
+                        Type::String => 's',
+                        Type::Comment => 'c',
+                        Type::Number => 'n',
+                        _ => ' ',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn highlights_words_and_numbers() {
+        assert_eq!(
+            highlight(&language("Rust"), &["let x: u8 = 0x1f; // done", "#[test] letter"]),
+            ["kkk    tt   nnnn  ccccccc", "tttttttttttttt"]
+        );
+    }
+
+    #[test]
+    fn carries_block_comments_across_rows() {
+        let rust = language("Rust");
+        assert_eq!(
+            highlight(&rust, &["fn a() /* one", "two", "three */ fn", "fn"]),
+            ["kk     cccccc", "ccc", "cccccccc kk", "kk"]
+        );
+        // A string that isn't closed carries on to the next row too
+        assert_eq!(highlight(&rust, &["\"a\\\"", "b\" fn"]), ["ssss", "ss kk"]);
+    }
+
+    #[test]
+    fn ends_single_line_regions_with_the_row() {
+        let python = language("Python");
+        let (_, state) = python.highlight("x = 'open", None);
+        assert!(state.is_none());
+    }
+
+    #[test]
+    fn keeps_quotes_in_char_literals_out_of_strings() {
+        assert_eq!(
+            highlight(&language("Rust"), &["let q = '\"'; let e = '\\\"';", "fn"]),
+            ["kkk     sss  kkk     ssss ", "kk"]
+        );
+        // Lifetimes are not char literals
+        assert_eq!(highlight(&language("Rust"), &["fn f<'a>(s: &'a str)"]), ["kk              ttt "]);
+    }
+
+    #[test]
+    fn detects_by_extension() {
+        let languages = Language::built_in();
+        assert_eq!(Language::detect(&languages, "src/main.rs").map(|language| language.name.as_str()), Some("Rust"));
+        assert!(Language::detect(&languages, "notes").is_none());
+    }
+}
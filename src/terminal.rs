@@ -1,18 +1,176 @@
+use crate::screen::Screen;
 use crate::Position;
-use std::io::{self, stdout, Write};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, stdout, Read, Stdout, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
 use termion::color;
-use termion::event::Key;
-use termion::input::TermRead;
+use termion::event::{self, Event, Key};
 use termion::raw::{IntoRawMode, RawTerminal};
 
+/// The write end of the pipe `on_resize` writes to, or -1 before there is one
+static RESIZE_PIPE: AtomicI32 = AtomicI32::new(-1);
+
 pub struct Size {
     pub width: u16,
     pub height: u16,
 }
 
+impl Size {
+    /// The size left for windows in a terminal of `width` by `height`, below which are
+    /// the status and message bars
+    fn of_text(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height: height.saturating_sub(2),
+        }
+    }
+}
+
+/// What the editor reads from its terminal
+pub enum Input {
+    Key(Key),
+    /// The terminal changed size, so everything needs drawing again
+    Resize,
+    /// Nothing happened for as long as the editor was prepared to wait
+    Idle,
+}
+
+/// Where the editor is drawn and its keys come from: the real terminal, or a virtual
+/// screen when testing
+pub trait Backend {
+    /// The width and height in cells
+    /// # Errors
+    /// Will return `Err` if the size cannot be found
+    fn size(&self) -> Result<(u16, u16), std::io::Error>;
+    /// Writes text and escape sequences as a terminal would receive them
+    /// # Errors
+    /// Will return `Err` if the output cannot be written
+    fn write(&mut self, bytes: &[u8]) -> Result<(), std::io::Error>;
+    /// # Errors
+    /// Will return `Err` if the output cannot be flushed
+    fn flush(&mut self) -> Result<(), std::io::Error>;
+    /// Waits up to `timeout` for the next key press or change of size
+    /// # Errors
+    /// Will return `Err` if no more keys can be read
+    fn read_input(&mut self, timeout: Duration) -> Result<Input, std::io::Error>;
+    /// Leaves raw mode, e.g. before writing to a stdout that is also the terminal
+    /// # Errors
+    /// Will return `Err` if the terminal settings cannot be restored
+    fn restore(&mut self) -> Result<(), std::io::Error>;
+}
+
+/// The terminal the editor was started in, in raw mode
+pub struct Tty {
+    stdout: RawTerminal<Stdout>,
+    /// Written to on SIGWINCH, so waiting for a key also notices the terminal resizing
+    resized: File,
+    /// Bytes read from stdin but not yet turned into keys
+    pending: VecDeque<u8>,
+}
+
+impl Tty {
+    /// # Errors
+    /// Will return `Err` if the `termion` dependency fails
+    /// to initiate raw mode, or the resize signal cannot be watched for
+    pub fn new() -> Result<Self, std::io::Error> {
+        Ok(Self {
+            stdout: stdout().into_raw_mode()?,
+            resized: watch_resize()?,
+            pending: VecDeque::new(),
+        })
+    }
+    /// Takes the next key from the bytes read so far. As in `termion`, an escape byte
+    /// with nothing after it is the Esc key
+    fn parse_key(&mut self) -> Option<Key> {
+        while let Some(byte) = self.pending.pop_front() {
+            if byte == b'\x1b' && self.pending.is_empty() {
+                return Some(Key::Esc);
+            }
+            let pending = &mut self.pending;
+            let mut rest = std::iter::from_fn(|| pending.pop_front().map(Ok));
+            if let Ok(Event::Key(key)) = event::parse_event(byte, &mut rest) {
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+impl Backend for Tty {
+    fn size(&self) -> Result<(u16, u16), std::io::Error> {
+        termion::terminal_size()
+    }
+    fn write(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        self.stdout.write_all(bytes)
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.stdout.flush()
+    }
+    fn read_input(&mut self, timeout: Duration) -> Result<Input, std::io::Error> {
+        let timeout = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+        loop {
+            if let Some(key) = self.parse_key() {
+                return Ok(Input::Key(key));
+            }
+            let poll = |fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+            let mut fds = [poll(libc::STDIN_FILENO), poll(self.resized.as_raw_fd())];
+            #[allow(unsafe_code, clippy::as_conversions)]
+            // SAFETY: `fds` holds as many entries as it says
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            if ready == 0 {
+                return Ok(Input::Idle);
+            }
+            if ready < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            let [input, resized] = fds;
+            if resized.revents != 0 {
+                // Several signals while busy drawing only need one redraw
+                let mut signals = [0; 64];
+                while matches!(self.resized.read(&mut signals), Ok(read) if read > 0) {}
+                return Ok(Input::Resize);
+            }
+            if input.revents != 0 {
+                let mut buffer = [0_u8; 1024];
+                #[allow(unsafe_code)]
+                // SAFETY: `buffer` has room for the `buffer.len()` bytes asked for. stdin is
+                // read directly as `io::stdin` would keep bytes buffered out of `poll`'s sight
+                let read = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr().cast(), buffer.len()) };
+                match usize::try_from(read) {
+                    Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The terminal was closed")),
+                    Ok(read) => self.pending.extend(buffer.get(..read).unwrap_or_default()),
+                    Err(_) => {
+                        let error = io::Error::last_os_error();
+                        if error.kind() != io::ErrorKind::Interrupted {
+                            return Err(error);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    fn restore(&mut self) -> Result<(), std::io::Error> {
+        self.stdout.suspend_raw_mode()
+    }
+}
+
 pub struct Terminal {
     size: Size,
-    _stdout: RawTerminal<std::io::Stdout>,
+    backend: Box<dyn Backend>,
+    /// The frame being drawn, which is compared with `shown` to find what to write to the
+    /// backend on the next flush
+    frame: RefCell<Screen>,
+    /// What the backend shows, or `None` before the first flush
+    shown: Option<Screen>,
 }
 
 impl Terminal {
@@ -20,68 +178,181 @@ impl Terminal {
     /// Will return `Err` if the `termion` dependency fails
     /// to initiate raw mode
     pub fn default() -> Result<Self, std::io::Error> {
-        let size = termion::terminal_size()?;
+        Self::new(Box::new(Tty::new()?))
+    }
+    /// A terminal drawing on and reading keys from `backend`
+    /// # Errors
+    /// Will return `Err` if the size of the backend cannot be found
+    pub fn new(backend: Box<dyn Backend>) -> Result<Self, std::io::Error> {
+        let (width, height) = backend.size()?;
         Ok(Self {
-            size: Size {
-                width: size.0,
-                height: size.1.saturating_sub(2),
-            },
-            _stdout: stdout().into_raw_mode()?,
+            size: Size::of_text(width, height),
+            backend,
+            frame: RefCell::new(Screen::new(usize::from(width), usize::from(height))),
+            shown: None,
         })
     }
+    /// Reads everything piped to stdin, then puts the terminal in its place so keys are
+    /// still read from the keyboard
+    /// # Errors
+    /// Will return `Err` if stdin cannot be read or there is no terminal to read keys from
+    pub fn take_stdin() -> Result<Vec<u8>, std::io::Error> {
+        let mut contents = vec![];
+        io::stdin().lock().read_to_end(&mut contents)?;
+        let tty = File::open("/dev/tty")?;
+        replace_fd(&tty, libc::STDIN_FILENO)?;
+        Ok(contents)
+    }
+    /// Moves stdout aside and puts the terminal in its place, so the editor is drawn on
+    /// the terminal even when stdout is a pipe. Returns the original stdout
+    /// # Errors
+    /// Will return `Err` if there is no terminal to draw on
+    pub fn take_stdout() -> Result<File, std::io::Error> {
+        io::stdout().flush()?;
+        #[allow(unsafe_code)]
+        // SAFETY: `dup` returns a new descriptor (or -1), which nothing else owns
+        let original = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if original < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[allow(unsafe_code)]
+        // SAFETY: `original` is open and owned by nothing else
+        let original = unsafe { File::from_raw_fd(original) };
+        let tty = OpenOptions::new().write(true).open("/dev/tty")?;
+        replace_fd(&tty, libc::STDOUT_FILENO)?;
+        Ok(original)
+    }
+    /// Leaves raw mode, e.g. before writing to a stdout that is also the terminal
+    /// # Errors
+    /// Will return `Err` if the terminal settings cannot be restored
+    pub fn restore(&mut self) -> Result<(), std::io::Error> {
+        self.backend.restore()
+    }
     #[must_use] pub fn size(&self) -> &Size {
         &self.size
     }
-    pub fn clear_screen() {
-        print!("{}", termion::clear::All);
+    /// Draws `text` on the frame at the cursor, to be shown on the next flush
+    pub fn print(&self, text: impl std::fmt::Display) {
+        self.frame.borrow_mut().write(&text.to_string());
+    }
+    pub fn clear_screen(&self) {
+        self.print(termion::clear::All);
     }
 
     #[allow(clippy::cast_possible_truncation, clippy::as_conversions)]
-    pub fn cursor_position(position: &Position) {
+    pub fn cursor_position(&self, position: &Position) {
         let &Position { x, y } = position;
         let x: u16 = x.saturating_add(1) as u16;
         let y: u16 = y.saturating_add(1) as u16;
-        print!("{}", termion::cursor::Goto(x, y));
+        self.print(termion::cursor::Goto(x, y));
     }
+    /// Shows the frame, writing to the backend only what changed since the last flush
     /// # Errors
-    /// Will return `Err` if `stdout` fails to flush
-    pub fn flush() -> Result<(), std::io::Error> {
-        io::stdout().flush()
+    /// Will return `Err` if the backend fails to write or flush
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        let frame = self.frame.get_mut();
+        let output = frame.diff(self.shown.as_ref());
+        self.shown = Some(frame.clone());
+        self.backend.write(output.as_bytes())?;
+        self.backend.flush()
     }
+    /// Waits up to `timeout` for the next key press. If the terminal is resized first, its
+    /// new size is taken and the next flush draws everything again
     /// # Errors
-    /// Will return `Err` if `stdin` fails to collect key presses
-    pub fn read_key() -> Result<Key, std::io::Error> {
-        loop {
-            if let Some(key) = io::stdin().lock().keys().next() {
-                return key;
-            }
+    /// Will return `Err` if the backend fails to collect key presses
+    pub fn read_input(&mut self, timeout: Duration) -> Result<Input, std::io::Error> {
+        let input = self.backend.read_input(timeout)?;
+        if let Input::Resize = input {
+            let (width, height) = self.backend.size()?;
+            self.size = Size::of_text(width, height);
+            self.frame.get_mut().resize(usize::from(width), usize::from(height));
+            self.shown = None;
         }
+        Ok(input)
+    }
+    /// Whether the frame being drawn shows the cursor
+    #[must_use]
+    pub fn cursor_shown(&self) -> bool {
+        self.frame.borrow().cursor().is_some()
+    }
+    pub fn cursor_hide(&self) {
+        self.print(termion::cursor::Hide);
+    }
+    pub fn cursor_show(&self) {
+        self.print(termion::cursor::Show);
+    }
+    pub fn cursor_bar(&self) {
+        self.print(termion::cursor::BlinkingBar);
+    }
+    pub fn cursor_block(&self) {
+        self.print(termion::cursor::BlinkingBlock);
     }
-    pub fn cursor_hide() {
-        print!("{}", termion::cursor::Hide);
+    pub fn clear_current_line(&self) {
+        self.print(termion::clear::CurrentLine);
     }
-    pub fn cursor_show() {
-        print!("{}", termion::cursor::Show);
+    pub fn set_bg_color(&self, color: color::Rgb) {
+        self.print(color::Bg(color));
     }
-    pub fn cursor_bar() {
-        print!("{}", termion::cursor::BlinkingBar);
+    pub fn reset_bg_color(&self) {
+        self.print(color::Bg(color::Reset));
     }
-    pub fn cursor_block() {
-        print!("{}", termion::cursor::BlinkingBlock);
+    pub fn set_fg_color(&self, color: color::Rgb) {
+        self.print(color::Fg(color));
     }
-    pub fn clear_current_line() {
-        print!("{}", termion::clear::CurrentLine);
+    pub fn reset_fg_color(&self) {
+        self.print(color::Fg(color::Reset));
     }
-    pub fn set_bg_color(color: color::Rgb) {
-        print!("{}", color::Bg(color));
+}
+
+/// Writes to the resize pipe. Only async-signal-safe calls may be made here
+extern "C" fn on_resize(_signal: libc::c_int) {
+    let fd = RESIZE_PIPE.load(Ordering::Relaxed);
+    #[allow(unsafe_code)]
+    // SAFETY: `write` is async-signal-safe. If the pipe is full, a wake-up is already waiting
+    unsafe {
+        libc::write(fd, [0_u8].as_ptr().cast(), 1);
+    }
+}
+
+/// Starts handling SIGWINCH by writing to a pipe, returning the end to read from
+fn watch_resize() -> Result<File, std::io::Error> {
+    let mut fds = [0; 2];
+    #[allow(unsafe_code)]
+    // SAFETY: `fds` has room for both ends of the pipe
+    let result = unsafe { libc::pipe(fds.as_mut_ptr()) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
     }
-    pub fn reset_bg_color() {
-        print!("{}", color::Bg(color::Reset));
+    for &fd in &fds {
+        #[allow(unsafe_code)]
+        // SAFETY: `fd` is open. Neither end should block or outlive an `exec`
+        let result = unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC).min(libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK))
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
     }
-    pub fn set_fg_color(color: color::Rgb) {
-        print!("{}", color::Fg(color));
+    let [read, write] = fds;
+    RESIZE_PIPE.store(write, Ordering::Relaxed);
+    #[allow(unsafe_code, clippy::as_conversions, clippy::fn_to_numeric_cast_any)]
+    // SAFETY: the handler only makes async-signal-safe calls
+    let previous = unsafe { libc::signal(libc::SIGWINCH, on_resize as extern "C" fn(libc::c_int) as libc::sighandler_t) };
+    if previous == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
     }
-    pub fn reset_fg_color() {
-        print!("{}", color::Fg(color::Reset));
+    #[allow(unsafe_code)]
+    // SAFETY: `read` is open and owned by nothing else
+    Ok(unsafe { File::from_raw_fd(read) })
+}
+
+/// Makes descriptor `fd` refer to `file` as well
+fn replace_fd(file: &File, fd: libc::c_int) -> Result<(), std::io::Error> {
+    #[allow(unsafe_code)]
+    // SAFETY: both descriptors are open; `dup2` closes `fd` before reusing it
+    let result = unsafe { libc::dup2(file.as_raw_fd(), fd) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(())
 }
@@ -0,0 +1,419 @@
+use crate::motion::{class, next, previous, row_len, Class};
+use crate::{Document, Position, Row};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A region picked by what surrounds the cursor rather than by moving it, as in `diw` or `ca(`.
+/// `around` is `a` (taking in the delimiters or surrounding whitespace), otherwise `i`
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum TextObject {
+    /// `iw`, `aw` and, when `big`, `iW`, `aW`
+    Word { big: bool, around: bool },
+    /// `i"`, `a'`, ``i` ``...
+    Quote { quote: char, around: bool },
+    /// `i(`, `a[`, `i{`, `a<` (and `ib`, `iB`)
+    Pair { open: char, close: char, around: bool },
+    /// `ip`, `ap`
+    Paragraph { around: bool },
+    /// `it`, `at`, an XML/HTML element
+    Tag { around: bool },
+}
+
+/// The text a text object covers: `start` up to (not including) `end`, or whole rows
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+    pub linewise: bool,
+}
+
+impl TextObject {
+    /// The object named by the key after `i` (or `a` when `around`)
+    #[must_use]
+    pub fn from_key(c: char, around: bool) -> Option<Self> {
+        let object = match c {
+            'w' | 'W' => TextObject::Word { big: c == 'W', around },
+            '"' | '\'' | '`' => TextObject::Quote { quote: c, around },
+            '(' | ')' | 'b' => TextObject::Pair { open: '(', close: ')', around },
+            '[' | ']' => TextObject::Pair { open: '[', close: ']', around },
+            '{' | '}' | 'B' => TextObject::Pair { open: '{', close: '}', around },
+            '<' | '>' => TextObject::Pair { open: '<', close: '>', around },
+            'p' => TextObject::Paragraph { around },
+            't' => TextObject::Tag { around },
+            _ => return None,
+        };
+        Some(object)
+    }
+    /// The range the object covers at `at`; a `count` takes in that many words or
+    /// paragraphs, or selects the pair or tag that many levels out
+    #[must_use]
+    pub fn range(self, document: &Document, at: Position, count: Option<usize>) -> Option<Range> {
+        let times = count.unwrap_or(1).max(1);
+        match self {
+            TextObject::Word { big, around } => word(document, at, big, around, times),
+            TextObject::Quote { quote, around } => self::quote(document.row(at.y)?, at, quote, around),
+            TextObject::Pair { open, close, around } => pair(document, at, open, close, around, times),
+            TextObject::Paragraph { around } => Some(paragraph(document, at, around, times)),
+            TextObject::Tag { around } => tag(document, at, around, times),
+        }
+    }
+}
+
+fn charwise(start: Position, end: Position) -> Range {
+    Range { start, end, linewise: false }
+}
+
+/// The graphemes from `x` in the same class as the one at `x`, returning the end of the run
+fn run_end(document: &Document, y: usize, x: usize, big: bool) -> usize {
+    let len = row_len(document, y);
+    let run = class(document, Position { x, y }, big);
+    (x..len).find(|&x| class(document, Position { x, y }, big) != run).unwrap_or(len)
+}
+
+fn run_start(document: &Document, y: usize, x: usize, big: bool) -> usize {
+    let run = class(document, Position { x, y }, big);
+    (0..x).rev().find(|&x| class(document, Position { x, y }, big) != run).map_or(0, |x| x.saturating_add(1))
+}
+
+fn word(document: &Document, at: Position, big: bool, around: bool, times: usize) -> Option<Range> {
+    let len = row_len(document, at.y);
+    if len == 0 {
+        return None;
+    }
+    let y = at.y;
+    let x = at.x.min(len.saturating_sub(1));
+    let on_blank = class(document, Position { x, y }, big) == Class::Blank;
+    let mut start = run_start(document, y, x, big);
+    let mut end = run_end(document, y, x, big);
+    // Each further count takes in the next run of blanks or word characters; `aw` counts a
+    // word together with its surrounding blanks as one
+    for i in 0..times {
+        if i > 0 {
+            end = run_end(document, y, end.min(len.saturating_sub(1)), big);
+        }
+        if around {
+            let next_is_blank = end < len && class(document, Position { x: end, y }, big) == Class::Blank;
+            if on_blank || next_is_blank {
+                end = run_end(document, y, end.min(len.saturating_sub(1)), big);
+            } else if i == 0 && start > 0 && class(document, Position { x: start.saturating_sub(1), y }, big) == Class::Blank {
+                // No blanks after the word, so take in the ones before it instead
+                start = run_start(document, y, start.saturating_sub(1), big);
+            }
+        }
+    }
+    Some(charwise(Position { x: start, y }, Position { x: end, y }))
+}
+
+fn quote(row: &Row, at: Position, quote: char, around: bool) -> Option<Range> {
+    let target = quote.to_string();
+    let graphemes: Vec<&str> = row.slice(0, row.len()).graphemes(true).collect();
+    let mut quotes = vec![];
+    let mut escaped = false;
+    for (x, grapheme) in graphemes.iter().enumerate() {
+        if escaped {
+            escaped = false;
+        } else if *grapheme == "\\" {
+            escaped = true;
+        } else if *grapheme == target {
+            quotes.push(x);
+        }
+    }
+    // Quotes pair up from the start of the row; use the pair around the cursor or the next one
+    let (open, close) = quotes
+        .chunks_exact(2)
+        .filter_map(|pair| match pair {
+            [open, close] => Some((*open, *close)),
+            _ => None,
+        })
+        .find(|&(_, close)| at.x <= close)?;
+    let y = at.y;
+    if !around {
+        return Some(charwise(Position { x: open.saturating_add(1), y }, Position { x: close, y }));
+    }
+    let is_blank = |x: usize| graphemes.get(x).is_some_and(|grapheme| grapheme.trim().is_empty());
+    let mut start = open;
+    let mut end = close.saturating_add(1);
+    if is_blank(end) {
+        while is_blank(end) {
+            end = end.saturating_add(1);
+        }
+    } else {
+        while start > 0 && is_blank(start.saturating_sub(1)) {
+            start = start.saturating_sub(1);
+        }
+    }
+    Some(charwise(Position { x: start, y }, Position { x: end, y }))
+}
+
+fn grapheme_at(document: &Document, at: Position) -> Option<&str> {
+    document.row(at.y)?.grapheme(at.x)
+}
+
+/// The unmatched `open` at or before `at`, skipping pairs nested in between
+fn find_open(document: &Document, at: Position, open: &str, close: &str) -> Option<Position> {
+    let mut position = at;
+    let mut depth = 0_usize;
+    loop {
+        match grapheme_at(document, position) {
+            Some(grapheme) if grapheme == open => {
+                if depth == 0 {
+                    return Some(position);
+                }
+                depth = depth.saturating_sub(1);
+            }
+            // A closing delimiter under the cursor belongs to the pair wanted
+            Some(grapheme) if grapheme == close && position != at => depth = depth.saturating_add(1),
+            _ => (),
+        }
+        position = previous(document, position)?;
+    }
+}
+
+/// The `close` matching the `open` at `at`
+fn find_close(document: &Document, at: Position, open: &str, close: &str) -> Option<Position> {
+    let mut position = at;
+    let mut depth = 0_usize;
+    loop {
+        position = next(document, position)?;
+        match grapheme_at(document, position) {
+            Some(grapheme) if grapheme == open => depth = depth.saturating_add(1),
+            Some(grapheme) if grapheme == close => {
+                if depth == 0 {
+                    return Some(position);
+                }
+                depth = depth.saturating_sub(1);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn pair(document: &Document, at: Position, open: char, close: char, around: bool, times: usize) -> Option<Range> {
+    let (open, close) = (open.to_string(), close.to_string());
+    let mut start = find_open(document, at, &open, &close)?;
+    let mut end = find_close(document, start, &open, &close)?;
+    for _ in 1..times {
+        start = find_open(document, previous(document, start)?, &open, &close)?;
+        end = find_close(document, start, &open, &close)?;
+    }
+    if around {
+        return Some(charwise(start, Position { x: end.x.saturating_add(1), y: end.y }));
+    }
+    let mut inner_start = next(document, start)?;
+    // Delimiters on rows of their own (a block) leave just the rows between them
+    if inner_start.x == row_len(document, start.y) && end.y > start.y {
+        inner_start = Position { x: 0, y: start.y.saturating_add(1) };
+        let before_close = document.row(end.y).map_or("", |row| row.slice(0, end.x));
+        if before_close.trim().is_empty() {
+            if inner_start.y >= end.y {
+                return Some(charwise(end, end));
+            }
+            return Some(Range {
+                start: inner_start,
+                end: Position { x: row_len(document, end.y.saturating_sub(1)), y: end.y.saturating_sub(1) },
+                linewise: true,
+            });
+        }
+    }
+    Some(charwise(inner_start, end))
+}
+
+fn is_blank_row(document: &Document, y: usize) -> bool {
+    document.row(y).is_none_or(|row| row.slice(0, row.len()).trim().is_empty())
+}
+
+fn paragraph(document: &Document, at: Position, around: bool, times: usize) -> Range {
+    let last_row = document.len().saturating_sub(1);
+    let blank = is_blank_row(document, at.y);
+    let mut first = (0..at.y).rev().find(|&y| is_blank_row(document, y) != blank).map_or(0, |y| y.saturating_add(1));
+    let run_end = |from: usize| (from..=last_row).find(|&y| is_blank_row(document, y) != is_blank_row(document, from)).unwrap_or(last_row.saturating_add(1));
+    let mut end = run_end(at.y);
+    // `ap` counts a paragraph with the blank rows after it as one; `ip` counts each run of rows
+    let runs = if around { times.saturating_mul(2) } else { times };
+    for _ in 1..runs {
+        if end > last_row {
+            break;
+        }
+        end = run_end(end);
+    }
+    if around && !blank && run_end(at.y) > last_row {
+        // The last paragraph has no blank rows after it, so take in the ones before it
+        while first > 0 && is_blank_row(document, first.saturating_sub(1)) {
+            first = first.saturating_sub(1);
+        }
+    }
+    let last = end.saturating_sub(1).min(last_row);
+    Range {
+        start: Position { x: 0, y: first },
+        end: Position { x: row_len(document, last), y: last },
+        linewise: true,
+    }
+}
+
+/// An opening or closing tag, from its `<` up to (not including) what follows its `>`
+struct Tag {
+    name: String,
+    closing: bool,
+    start: Position,
+    end: Position,
+}
+
+/// Every tag in the document, skipping comments, declarations and self-closing tags
+fn tags(document: &Document) -> Vec<Tag> {
+    let mut tags = vec![];
+    for y in 0..document.len() {
+        let row = match document.row(y) {
+            Some(row) => row,
+            None => continue,
+        };
+        let graphemes: Vec<&str> = row.slice(0, row.len()).graphemes(true).collect();
+        let mut x = 0;
+        while x < graphemes.len() {
+            if graphemes.get(x) != Some(&"<") {
+                x = x.saturating_add(1);
+                continue;
+            }
+            let close = match (x..graphemes.len()).find(|&i| graphemes.get(i) == Some(&">")) {
+                Some(close) => close,
+                None => break,
+            };
+            let inner = graphemes.get(x.saturating_add(1)..close).unwrap_or(&[]).concat();
+            let closing = inner.starts_with('/');
+            let name: String = inner
+                .trim_start_matches('/')
+                .chars()
+                .take_while(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+                .collect();
+            if !name.is_empty() && !inner.ends_with('/') {
+                tags.push(Tag {
+                    name,
+                    closing,
+                    start: Position { x, y },
+                    end: Position { x: close.saturating_add(1), y },
+                });
+            }
+            x = close.saturating_add(1);
+        }
+    }
+    tags
+}
+
+fn before(a: Position, b: Position) -> bool {
+    (a.y, a.x) < (b.y, b.x)
+}
+
+fn tag(document: &Document, at: Position, around: bool, times: usize) -> Option<Range> {
+    // Pair tags up with a stack, dropping unmatched ones so unbalanced markup still works
+    let mut open: Vec<Tag> = vec![];
+    let mut elements = vec![];
+    for tag in tags(document) {
+        if !tag.closing {
+            open.push(tag);
+        } else if let Some(index) = open.iter().rposition(|opening| opening.name == tag.name) {
+            open.truncate(index.saturating_add(1));
+            if let Some(opening) = open.pop() {
+                elements.push((opening, tag));
+            }
+        }
+    }
+    // Elements close innermost first, so the ones around the cursor come up smallest first
+    let (opening, closing) = elements
+        .into_iter()
+        .filter(|(opening, closing)| !before(at, opening.start) && before(at, closing.end))
+        .nth(times.saturating_sub(1))?;
+    if around {
+        Some(charwise(opening.start, closing.end))
+    } else {
+        Some(charwise(opening.end, closing.start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text `keys` (e.g. `iw`) covers at `at`, with `|` standing in for a linewise range
+    fn covered(text: &str, at: (usize, usize), keys: &str, count: Option<usize>) -> Option<String> {
+        let document = Document::scratch(text);
+        let mut chars = keys.chars();
+        let around = chars.next() == Some('a');
+        let object = TextObject::from_key(chars.next()?, around)?;
+        let range = object.range(&document, Position { x: at.0, y: at.1 }, count)?;
+        let text = document.text_range(&range.start, &range.end);
+        Some(if range.linewise { format!("|{}|", text) } else { text })
+    }
+
+    fn check(text: &str, at: (usize, usize), keys: &str, expected: Option<&str>) {
+        assert_eq!(covered(text, at, keys, None).as_deref(), expected, "{} at {:?}", keys, at);
+    }
+
+    #[test]
+    fn words() {
+        check("foo bar baz", (5, 0), "iw", Some("bar"));
+        check("foo bar baz", (5, 0), "aw", Some("bar "));
+        check("foo bar", (5, 0), "aw", Some(" bar"));
+        check("foo   bar", (4, 0), "iw", Some("   "));
+        check("foo   bar", (4, 0), "aw", Some("   bar"));
+        check("foo.bar baz", (1, 0), "iw", Some("foo"));
+        check("foo.bar baz", (1, 0), "iW", Some("foo.bar"));
+        check("foo.bar baz", (1, 0), "aW", Some("foo.bar "));
+        check("", (0, 0), "iw", None);
+        assert_eq!(covered("one two three", (0, 0), "iw", Some(3)).as_deref(), Some("one two"));
+        assert_eq!(covered("one two three four", (0, 0), "aw", Some(2)).as_deref(), Some("one two "));
+    }
+
+    #[test]
+    fn quotes() {
+        check(r#"say "hello" now"#, (6, 0), "i\"", Some("hello"));
+        check(r#"say "hello" now"#, (6, 0), "a\"", Some("\"hello\" "));
+        check(r#"say "hello""#, (6, 0), "a\"", Some(" \"hello\""));
+        check(r#"x = "a" + "b""#, (0, 0), "i\"", Some("a"));
+        check(r#"x = "a" + "b""#, (11, 0), "i\"", Some("b"));
+        check(r#""esc \" aped""#, (2, 0), "i\"", Some(r#"esc \" aped"#));
+        check(r#"no quotes"#, (0, 0), "i\"", None);
+        check(r#"one "unclosed"#, (0, 0), "i\"", None);
+    }
+
+    #[test]
+    fn nested_pairs_across_rows() {
+        check("f(a, (b, c), d)", (6, 0), "i(", Some("b, c"));
+        check("f(a, (b, c), d)", (6, 0), "a(", Some("(b, c)"));
+        check("f(a, (b, c), d)", (2, 0), "i(", Some("a, (b, c), d"));
+        check("f(a, (b, c), d)", (5, 0), "ib", Some("b, c"));
+        check("f(a, (b, c), d)", (10, 0), "i)", Some("b, c"));
+        assert_eq!(covered("f(a, (b, c), d)", (6, 0), "i(", Some(2)).as_deref(), Some("a, (b, c), d"));
+        check("call(\n    one,\n    two(x)\n)", (4, 2), "a(", Some("(\n    one,\n    two(x)\n)"));
+        check("fn f() {\n    a;\n    {b}\n}", (4, 1), "i{", Some("|    a;\n    {b}|"));
+        check("fn f() {\n    a;\n    {b}\n}", (5, 2), "i{", Some("b"));
+        check("x[1][2]", (5, 0), "i[", Some("2"));
+    }
+
+    #[test]
+    fn unbalanced_pairs() {
+        check("(a, (b)", (5, 0), "i(", Some("b"));
+        check("(a, (b)", (1, 0), "i(", None);
+        check("a) (b", (0, 0), "i(", None);
+        check("no brackets", (3, 0), "i(", None);
+    }
+
+    #[test]
+    fn paragraphs() {
+        let text = "one\ntwo\n\nthree\nfour\n\n\nfive";
+        check(text, (0, 0), "ip", Some("|one\ntwo|"));
+        check(text, (0, 0), "ap", Some("|one\ntwo\n|"));
+        check(text, (0, 4), "ap", Some("|three\nfour\n\n|"));
+        check(text, (0, 7), "ap", Some("|\n\nfive|"));
+        check(text, (0, 2), "ip", Some("||"));
+        assert_eq!(covered(text, (0, 0), "ip", Some(3)).as_deref(), Some("|one\ntwo\n\nthree\nfour|"));
+    }
+
+    #[test]
+    fn tags() {
+        let html = "<div class=\"a\">\n  <p>hi <b>there</b></p>\n  <br/>\n</div>";
+        check(html, (9, 1), "it", Some("there"));
+        check(html, (9, 1), "at", Some("<b>there</b>"));
+        check(html, (4, 1), "it", Some("hi <b>there</b>"));
+        check(html, (2, 2), "it", Some("\n  <p>hi <b>there</b></p>\n  <br/>\n"));
+        assert_eq!(covered(html, (9, 1), "it", Some(2)).as_deref(), Some("hi <b>there</b>"));
+        check("<a><b>x</a>", (6, 0), "it", Some("<b>x"));
+        check("no tags", (1, 0), "it", None);
+    }
+}
@@ -0,0 +1,346 @@
+use crate::Position;
+use std::convert::TryFrom;
+
+/// A view onto a buffer. The current window's cursor and scroll offset live in the
+/// `Editor` itself; every other window keeps them here until it is focused again
+pub struct Window {
+    pub id: usize,
+    /// The id of the buffer shown, which other windows may be showing too
+    pub buffer: usize,
+    pub cursor_position: Position,
+    pub offset: Position,
+}
+
+/// A tab page, with its own windows and layout. The current tab's live in the `Editor`
+/// itself; every other tab keeps them here until it is switched back to
+pub struct Tab {
+    pub windows: Vec<Window>,
+    /// The index in `windows` of the window focused in this tab
+    pub current_window: usize,
+    pub layout: Layout,
+}
+
+impl Tab {
+    #[must_use]
+    pub fn default() -> Self {
+        Self {
+            windows: vec![],
+            current_window: 0,
+            layout: Layout::Window(0),
+        }
+    }
+}
+
+/// How a split lays out its two halves
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    /// One above the other, from `:split`
+    Horizontal,
+    /// Side by side with a separator column between them, from `:vsplit`
+    Vertical,
+}
+
+/// A screen area in cells
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// The tree of splits the windows are laid out in
+pub enum Layout {
+    Window(usize),
+    Split {
+        direction: Direction,
+        first: Box<Layout>,
+        second: Box<Layout>,
+        /// The rows or columns given to `first`; `None` shares the space evenly between windows
+        size: Option<u16>,
+    },
+}
+
+/// The rows a window needs for one line of text and its status line
+const MIN_HEIGHT: u16 = 2;
+const MIN_WIDTH: u16 = 1;
+
+impl Layout {
+    /// The area of every window within `area`, in drawing order
+    #[must_use]
+    pub fn rects(&self, area: Rect) -> Vec<(usize, Rect)> {
+        let mut rects = vec![];
+        self.collect_rects(area, &mut rects);
+        rects
+    }
+    fn collect_rects(&self, area: Rect, rects: &mut Vec<(usize, Rect)>) {
+        match self {
+            Layout::Window(id) => rects.push((*id, area)),
+            Layout::Split { direction, first, second, size } => {
+                let (first_area, second_area) = split_area(area, *direction, first_size(*direction, first, second, *size, area));
+                first.collect_rects(first_area, rects);
+                second.collect_rects(second_area, rects);
+            }
+        }
+    }
+    #[must_use]
+    pub fn contains(&self, window: usize) -> bool {
+        match self {
+            Layout::Window(id) => *id == window,
+            Layout::Split { first, second, .. } => first.contains(window) || second.contains(window),
+        }
+    }
+    /// How many windows are lined up in `direction`, for sharing space evenly between them
+    fn count(&self, direction: Direction) -> usize {
+        match self {
+            Layout::Window(_) => 1,
+            Layout::Split { direction: split_direction, first, second, .. } if *split_direction == direction => {
+                first.count(direction).saturating_add(second.count(direction))
+            }
+            Layout::Split { first, second, .. } => first.count(direction).max(second.count(direction)),
+        }
+    }
+    /// Splits `window` in two, putting `new` above or to the left of it
+    pub fn split(&mut self, window: usize, new: usize, direction: Direction) {
+        match self {
+            Layout::Window(id) if *id == window => {
+                *self = Layout::Split {
+                    direction,
+                    first: Box::new(Layout::Window(new)),
+                    second: Box::new(Layout::Window(window)),
+                    size: None,
+                };
+            }
+            Layout::Window(_) => (),
+            Layout::Split { first, second, .. } => {
+                first.split(window, new, direction);
+                second.split(window, new, direction);
+            }
+        }
+    }
+    /// Takes `window` out of the tree, giving its space to its sibling
+    pub fn remove(&mut self, window: usize) {
+        if let Layout::Split { first, second, .. } = self {
+            let remaining = match (&**first, &**second) {
+                (Layout::Window(id), _) if *id == window => std::mem::replace(&mut **second, Layout::Window(0)),
+                (_, Layout::Window(id)) if *id == window => std::mem::replace(&mut **first, Layout::Window(0)),
+                _ => {
+                    first.remove(window);
+                    second.remove(window);
+                    return;
+                }
+            };
+            *self = remaining;
+        }
+    }
+    /// Shares the space evenly between all windows again
+    pub fn equalize(&mut self) {
+        if let Layout::Split { first, second, size, .. } = self {
+            *size = None;
+            first.equalize();
+            second.equalize();
+        }
+    }
+    /// Grows `window` by `delta` rows (or columns, for `Direction::Vertical`) at the
+    /// expense of its neighbour in the innermost split of that direction, shrinking it
+    /// if `delta` is negative. Returns `false` if there is no such split
+    pub fn resize(&mut self, window: usize, direction: Direction, delta: i32, area: Rect) -> bool {
+        if let Layout::Split { direction: split_direction, first, second, size } = self {
+            let current = first_size(*split_direction, first, second, *size, area);
+            let (first_area, second_area) = split_area(area, *split_direction, current);
+            let in_first = first.contains(window);
+            if !in_first && !second.contains(window) {
+                return false;
+            }
+            let resized = if in_first {
+                first.resize(window, direction, delta, first_area)
+            } else {
+                second.resize(window, direction, delta, second_area)
+            };
+            if resized || *split_direction != direction {
+                return resized;
+            }
+            let delta = if in_first { delta } else { delta.saturating_neg() };
+            let wanted = i32::from(current).saturating_add(delta).max(0);
+            *size = Some(u16::try_from(wanted).unwrap_or(u16::MAX));
+            return true;
+        }
+        false
+    }
+    /// The window next to `window` on the side given by `side` (`h`, `j`, `k` or `l`),
+    /// preferring the one level with `at` if several border it
+    #[must_use]
+    pub fn neighbour(&self, window: usize, side: char, at: Position, area: Rect) -> Option<usize> {
+        let rects = self.rects(area);
+        let &(_, from) = rects.iter().find(|&&(id, _)| id == window)?;
+        let overlaps = |start: u16, len: u16, other_start: u16, other_len: u16| {
+            start < other_start.saturating_add(other_len) && other_start < start.saturating_add(len)
+        };
+        let candidates: Vec<(usize, Rect)> = rects
+            .into_iter()
+            .filter(|&(_, rect)| match side {
+                'h' => rect.x.saturating_add(rect.width).saturating_add(1) == from.x && overlaps(rect.y, rect.height, from.y, from.height),
+                'l' => from.x.saturating_add(from.width).saturating_add(1) == rect.x && overlaps(rect.y, rect.height, from.y, from.height),
+                'k' => rect.y.saturating_add(rect.height) == from.y && overlaps(rect.x, rect.width, from.x, from.width),
+                'j' => from.y.saturating_add(from.height) == rect.y && overlaps(rect.x, rect.width, from.x, from.width),
+                _ => false,
+            })
+            .collect();
+        let level = |rect: &Rect| {
+            let (x, y) = (usize::from(rect.x), usize::from(rect.y));
+            if side == 'h' || side == 'l' {
+                (y..y.saturating_add(usize::from(rect.height))).contains(&at.y)
+            } else {
+                (x..x.saturating_add(usize::from(rect.width))).contains(&at.x)
+            }
+        };
+        candidates
+            .iter()
+            .find(|(_, rect)| level(rect))
+            .or_else(|| candidates.first())
+            .map(|&(id, _)| id)
+    }
+}
+
+/// The rows or columns `first` gets out of `area`, leaving each side room for a window
+#[allow(clippy::integer_arithmetic, clippy::integer_division)]
+fn first_size(direction: Direction, first: &Layout, second: &Layout, size: Option<u16>, area: Rect) -> u16 {
+    let (available, min) = match direction {
+        Direction::Horizontal => (area.height, MIN_HEIGHT),
+        Direction::Vertical => (area.width.saturating_sub(1), MIN_WIDTH),
+    };
+    let wanted = size.unwrap_or_else(|| {
+        let share = usize::from(available) * first.count(direction) / first.count(direction).saturating_add(second.count(direction)).max(1);
+        u16::try_from(share).unwrap_or(available)
+    });
+    if available < min.saturating_mul(2) {
+        return wanted.min(available);
+    }
+    wanted.clamp(min, available.saturating_sub(min))
+}
+
+fn split_area(area: Rect, direction: Direction, first_size: u16) -> (Rect, Rect) {
+    match direction {
+        Direction::Horizontal => (
+            Rect { height: first_size, ..area },
+            Rect {
+                y: area.y.saturating_add(first_size),
+                height: area.height.saturating_sub(first_size),
+                ..area
+            },
+        ),
+        Direction::Vertical => (
+            Rect { width: first_size, ..area },
+            Rect {
+                x: area.x.saturating_add(first_size).saturating_add(1),
+                width: area.width.saturating_sub(first_size).saturating_sub(1),
+                ..area
+            },
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{open, play, TempFile};
+
+    const AREA: Rect = Rect { x: 0, y: 0, width: 80, height: 24 };
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    #[test]
+    fn splits_share_space_evenly() {
+        let mut layout = Layout::Window(1);
+        layout.split(1, 2, Direction::Horizontal);
+        assert_eq!(layout.rects(AREA), [(2, rect(0, 0, 80, 12)), (1, rect(0, 12, 80, 12))]);
+        layout.split(1, 3, Direction::Vertical);
+        assert_eq!(
+            layout.rects(AREA),
+            [(2, rect(0, 0, 80, 12)), (3, rect(0, 12, 39, 12)), (1, rect(40, 12, 40, 12))]
+        );
+    }
+
+    #[test]
+    fn resizes_within_bounds_and_equalizes() {
+        let mut layout = Layout::Window(1);
+        layout.split(1, 2, Direction::Horizontal);
+        assert!(layout.resize(1, Direction::Horizontal, 3, AREA));
+        assert_eq!(layout.rects(AREA), [(2, rect(0, 0, 80, 9)), (1, rect(0, 9, 80, 15))]);
+        assert!(layout.resize(2, Direction::Horizontal, -20, AREA));
+        assert_eq!(layout.rects(AREA), [(2, rect(0, 0, 80, 2)), (1, rect(0, 2, 80, 22))]);
+        assert!(!layout.resize(2, Direction::Vertical, 1, AREA));
+        layout.equalize();
+        assert_eq!(layout.rects(AREA), [(2, rect(0, 0, 80, 12)), (1, rect(0, 12, 80, 12))]);
+    }
+
+    #[test]
+    fn removing_a_window_gives_its_space_to_its_sibling() {
+        let mut layout = Layout::Window(1);
+        layout.split(1, 2, Direction::Vertical);
+        layout.split(2, 3, Direction::Horizontal);
+        layout.remove(3);
+        assert_eq!(layout.rects(AREA), [(2, rect(0, 0, 39, 24)), (1, rect(40, 0, 40, 24))]);
+        layout.remove(2);
+        assert_eq!(layout.rects(AREA), [(1, AREA)]);
+    }
+
+    #[test]
+    fn finds_neighbours_by_position() {
+        let mut layout = Layout::Window(1);
+        layout.split(1, 2, Direction::Vertical);
+        layout.split(1, 3, Direction::Horizontal);
+        // 2 on the left, 3 above 1 on the right
+        assert_eq!(layout.neighbour(2, 'l', Position { x: 0, y: 20 }, AREA), Some(1));
+        assert_eq!(layout.neighbour(2, 'l', Position { x: 0, y: 2 }, AREA), Some(3));
+        assert_eq!(layout.neighbour(3, 'j', Position::default(), AREA), Some(1));
+        assert_eq!(layout.neighbour(1, 'k', Position::default(), AREA), Some(3));
+        assert_eq!(layout.neighbour(1, 'h', Position::default(), AREA), Some(2));
+        assert_eq!(layout.neighbour(2, 'h', Position::default(), AREA), None);
+    }
+
+    #[test]
+    fn opens_switches_and_closes_tabs() {
+        let file = TempFile::new("tabs", "first.txt", "one\n");
+        let other = file.sibling("second.txt", "two\n");
+        let (mut editor, screen) = open(&file.path);
+        // The tab line only shows up with a second tab
+        assert_eq!(screen.rows().first().map(String::as_str), Some("1 one"));
+        play(&mut editor, &screen, &format!(":tabnew {}\n", other.display()));
+        assert_eq!(screen.rows().get(..2), Some(&[" 1 first.txt  2 second.txt".to_owned(), "1 two".to_owned()][..]));
+        assert_eq!(screen.cell(1, 0).and_then(|cell| cell.bg), None);
+        assert_eq!(screen.cell(13, 0).and_then(|cell| cell.bg), Some(editor.config.theme.status_bg));
+
+        play(&mut editor, &screen, ":tabnew\n");
+        assert_eq!(screen.rows().first().map(String::as_str), Some(" 1 first.txt  2 second.txt  3 [No Name]"));
+        play(&mut editor, &screen, ":tabnext\n");
+        assert_eq!(screen.rows().get(1).map(String::as_str), Some("1 one"));
+        play(&mut editor, &screen, ":tabp\n:tabp\n");
+        assert_eq!(screen.rows().get(1).map(String::as_str), Some("1 two"));
+
+        play(&mut editor, &screen, ":tabclose\n");
+        assert_eq!(screen.rows().first().map(String::as_str), Some(" 1 first.txt  2 [No Name]"));
+        assert_eq!(screen.rows().get(1).map(String::as_str), Some("1"));
+        // Closing a tab leaves its buffers open
+        play(&mut editor, &screen, ":ls\n");
+        assert!(editor.status_message.text.contains("second.txt"));
+    }
+
+    #[test]
+    fn keeps_each_tabs_windows() {
+        let file = TempFile::new("tab-windows", "first.txt", "one\n");
+        let (mut editor, screen) = open(&file.path);
+        play(&mut editor, &screen, ":sp\n:tabnew\n");
+        assert_eq!(screen.rows().iter().filter(|row| row.contains("utf-8")).count(), 1);
+        play(&mut editor, &screen, ":tabn\n");
+        assert_eq!(screen.rows().iter().filter(|row| row.contains("utf-8")).count(), 2);
+        // Closing the last window of a tab closes the tab, and the last tab can't be closed
+        play(&mut editor, &screen, ":tabn\n:q\n");
+        assert_eq!(screen.rows().first().map(String::as_str), Some("1 one"));
+        play(&mut editor, &screen, ":tabclose\n");
+        assert_eq!(editor.status_message.text, "ERR: Cannot close last tab page");
+        assert!(!editor.should_quit);
+    }
+}